@@ -0,0 +1,452 @@
+//! Measuring how fast this machine hashes, compresses and encrypts (with the adapters the transfers use)
+//! and how fast whole transfers are on it, to pick the compression level and to decide on encryption.
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use walkdir::WalkDir;
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use crate::{CompressionAlgorithm, SmdError};
+use crate::bench_events::BenchEvent;
+use crate::buffered_io::{HashReader, HashWriter, PerhapsCompressedReader, PerhapsCompressedWriter, PerhapsEncrReader, PerhapsEncrWriter, ZstdParams};
+use crate::buffered_io::comp_io::BROTLI_MAX_LEVEL;
+use crate::buffered_io::counting_io::compression_ratio;
+use crate::buffered_io::encrypt_io::prepare_key;
+use crate::client::{Client, RetryPolicy};
+use crate::client_events::ClientEvent;
+use crate::server::Server;
+use crate::transport;
+
+
+/// zstd levels measured, unless told otherwise
+pub const DEFAULT_LEVELS: &[u8] = &[1, 3, 6, 9, 12, 15, 19];
+/// Speeds of the links recommended for, in Bytes per second (unless told otherwise): 10 Mbit/s to 10 Gbit/s
+pub const DEFAULT_LINKS: &[u64] = &[1_250_000, 12_500_000, 125_000_000, 1_250_000_000];
+/// Of the sample, unless told otherwise
+pub const DEFAULT_SIZE: u64 = 32_000_000;
+
+/// Written to the adapters at once, as the Hoster reads files
+const PIECE_SIZE: usize = 1 << 13;
+/// Passes over the sample are cut short after this long (slow levels only see its start)
+const MAX_PASS: Duration = Duration::from_secs(2);
+/// Small samples are passed over again, until they have been measured for this long
+const MIN_DURATION: Duration = Duration::from_millis(500);
+/// Settings this close to the best are recommended instead, if they need less CPU
+const TOLERANCE: f64 = 0.02;
+/// Encrypted with (any key is as fast)
+const KEY: &str = "smd_transfer bench";
+
+
+/// What to measure on
+#[derive(Debug, Clone)]
+pub enum Sample {
+    /// The files in a directory (or a single file), one after the other
+    Files(PathBuf),
+    /// Generated log lines, with runs of random Bytes in between
+    Synthetic
+}
+
+/// What was measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// MD5, of every file
+    Hash,
+    Compression{ codec: CompressionAlgorithm, level: u8 },
+    /// ChaCha20-Poly1305, with an encryption key
+    Encryption,
+    /// A whole transfer from a Hoster to a client, over TCP on this machine
+    Loopback{ compression: Option<(CompressionAlgorithm, u8)>, encryption: bool }
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Hash => write!(f, "md5"),
+            Self::Compression{ codec: CompressionAlgorithm::Lz4, .. } => write!(f, "lz4"),
+            Self::Compression{ codec, level } => write!(f, "{codec} level {level}"),
+            Self::Encryption => write!(f, "chacha20-poly1305"),
+            Self::Loopback{ compression, encryption } => {
+                write!(f, "loopback")?;
+                if let Some((codec, level)) = compression {
+                    write!(f, " {}", Settings(Some((*codec, *level))))?;
+                }
+                if *encryption {
+                    write!(f, " -k")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The Hoster's options compressing like this (or "no -c")
+pub struct Settings(pub Option<(CompressionAlgorithm, u8)>);
+
+impl std::fmt::Display for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            None => f.pad("no -c"),
+            Some((CompressionAlgorithm::Zstd, level)) => f.pad(&format!("-c -l {level}")),
+            Some((CompressionAlgorithm::Lz4, _)) => f.pad("-c --codec lz4"),
+            Some((codec, level)) => f.pad(&format!("-c --codec {codec} -l {level}"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    pub stage: Stage,
+    /// Raw Bytes per second through the Hoster's side (or of the whole transfer)
+    pub write_rate: f64,
+    /// Raw Bytes per second through the client's side (None for transfers)
+    pub read_rate: Option<f64>,
+    /// Raw Bytes per Byte sent (compression and transfers only)
+    pub ratio: Option<f64>
+}
+
+/// The compression getting the most through a link, with and without encryption
+#[derive(Debug, Clone, Copy)]
+pub struct Recommendation {
+    /// In Bytes per second
+    pub link: u64,
+    pub plain: Choice,
+    pub encrypted: Choice
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Choice {
+    /// None for no compression
+    pub compression: Option<(CompressionAlgorithm, u8)>,
+    /// Expected raw Bytes per second
+    pub rate: f64
+}
+
+
+/// Measures hashing, compression and encryption on a sample, then whole transfers over loopback,
+/// and recommends the settings for links of different speeds.
+///
+/// ```no_run
+/// use simple_mass_data_transfer::bench::{Benchmark, Sample};
+///
+/// let (tx, rx) = std::sync::mpsc::channel();
+/// let printer = std::thread::spawn(|| simple_mass_data_transfer::bench_events::handle_events_cli(rx));
+/// Benchmark::new(Sample::Synthetic).run(tx)?;
+/// printer.join().unwrap()?;
+/// # anyhow::Ok(())
+/// ```
+pub struct Benchmark {
+    sample: Sample,
+    size: u64,
+    codec: CompressionAlgorithm,
+    levels: Vec<u8>,
+    links: Vec<u64>,
+    loopback: bool
+}
+
+impl Benchmark {
+    pub fn new(sample: Sample) -> Self {
+        Self{
+            sample,
+            size: DEFAULT_SIZE,
+            codec: CompressionAlgorithm::Zstd,
+            levels: DEFAULT_LEVELS.to_vec(),
+            links: DEFAULT_LINKS.to_vec(),
+            loopback: true
+        }
+    }
+
+    /// Measure on (at most) `size` Bytes
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Compress with `codec` at each of `levels` (lz4 has no levels, it is measured once)
+    pub fn with_levels(mut self, codec: CompressionAlgorithm, levels: Vec<u8>) -> Self {
+        self.codec = codec;
+        self.levels = levels;
+        self
+    }
+
+    /// Recommend for links of these speeds, in Bytes per second
+    pub fn with_links(mut self, links: Vec<u64>) -> Self {
+        self.links = links;
+        self
+    }
+
+    /// Whether to measure whole transfers too
+    pub fn with_loopback(mut self, loopback: bool) -> Self {
+        self.loopback = loopback;
+        self
+    }
+
+    /// Runs all measurements, one after the other
+    pub fn run(&self, handler: Sender<BenchEvent>) -> anyhow::Result<()> {
+        if self.codec == CompressionAlgorithm::Unknown {
+            anyhow::bail!("Can not compress with an unknown algorithm")
+        }
+        let mut levels: Vec<u8> = match self.codec {
+            CompressionAlgorithm::Lz4 => self.levels.iter().take(1).copied().collect(),
+            CompressionAlgorithm::Brotli => self.levels.iter().map(|&level| level.min(BROTLI_MAX_LEVEL)).collect(),
+            _ => self.levels.clone()
+        };
+        levels.sort_unstable();
+        levels.dedup();
+        if levels.is_empty() {
+            anyhow::bail!("No compression level to measure")
+        }
+
+        let (sample, description) = match &self.sample {
+            Sample::Files(path) => (read_files(path, self.size)?, path.display().to_string()),
+            Sample::Synthetic => (synthetic(self.size as usize), "synthetic data".into())
+        };
+        handler.send(BenchEvent::Sample{ description, size: sample.len() as u64 })?;
+
+        let mut measurements = Vec::new();
+        let mut measured = |measurement: Measurement| -> anyhow::Result<()> {
+            handler.send(BenchEvent::Measured(measurement))?;
+            measurements.push(measurement);
+            Ok(())
+        };
+        measured(hash(&sample)?)?;
+        for &level in &levels {
+            measured(compression(&sample, self.codec, level)?)?;
+        }
+        measured(encryption(&sample)?)?;
+
+        // (at the lowest level, loopback being faster than any link)
+        if self.loopback {
+            let dir = std::env::temp_dir().join(format!("smd_bench_{}", std::process::id()));
+            let transfers = (|| -> anyhow::Result<()> {
+                std::fs::create_dir_all(&dir).map_err(SmdError::io(&dir))?;
+                let path = dir.join("sample");
+                std::fs::write(&path, &sample).map_err(SmdError::io(&path))?;
+                for (compression, encryption) in [(None, false), (Some((self.codec, levels[0])), false), (None, true), (Some((self.codec, levels[0])), true)] {
+                    measured(loopback(&dir, compression, encryption)?)?;
+                }
+                Ok(())
+            })();
+            let _ = std::fs::remove_dir_all(&dir);
+            transfers?;
+        }
+
+        handler.send(BenchEvent::Recommended(recommend(&measurements, &self.links)))?;
+        Ok(())
+    }
+}
+
+
+/// Up to `size` Bytes of the files at `path`, one after the other
+fn read_files(path: &Path, size: u64) -> anyhow::Result<Vec<u8>> {
+    let mut sample = Vec::new();
+    for entry in WalkDir::new(path).sort_by_file_name() {
+        let entry = entry?;
+        let remaining = size - sample.len() as u64;
+        if remaining == 0 {
+            break
+        }
+        if entry.file_type().is_file() {
+            let file = std::fs::File::open(entry.path()).map_err(SmdError::io(entry.path()))?;
+            file.take(remaining).read_to_end(&mut sample).map_err(SmdError::io(entry.path()))?;
+        }
+    }
+    if sample.is_empty() {
+        anyhow::bail!("There is nothing to measure on in {path:?}")
+    }
+    Ok(sample)
+}
+
+/// `size` Bytes of log lines, with a run of random Bytes every so often
+/// (the same every time, so that runs can be compared)
+fn synthetic(size: usize) -> Vec<u8> {
+    const WORDS: &[&str] = &[
+        "INFO", "WARN", "DEBUG", "GET", "POST", "request", "served", "worker", "cache", "hit", "miss",
+        "session", "opened", "closed", "user", "/api/v1/files", "/index.html", "bytes", "in", "ms"
+    ];
+    // xorshift
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let mut sample = Vec::with_capacity(size + PIECE_SIZE);
+    let mut time = 1_700_000_000;
+    while sample.len() < size {
+        if next() % 512 == 0 {
+            let words = 128 + next() % 512;
+            for _ in 0..words {
+                sample.extend_from_slice(&next().to_le_bytes());
+            }
+            continue
+        }
+        time += next() % 3;
+        let _ = writeln!(sample, "{time} {} worker-{} {} {} {} {} {}",
+                       WORDS[(next() % 3) as usize], next() % 32,
+                       WORDS[(next() % WORDS.len() as u64) as usize], WORDS[(next() % WORDS.len() as u64) as usize],
+                       next() % 100_000, WORDS[(next() % WORDS.len() as u64) as usize], next() % 1000);
+    }
+    sample.truncate(size);
+    sample
+}
+
+
+/// The pieces of `sample` a pass has time for (all of them, unless it takes longer than [`MAX_PASS`])
+fn pieces(sample: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let start = Instant::now();
+    sample.chunks(PIECE_SIZE).take_while(move |_| start.elapsed() < MAX_PASS)
+}
+
+/// Repeats `pass` for at least [`MIN_DURATION`], returning the Bytes per second of the raw Bytes it returns
+fn rate(mut pass: impl FnMut() -> io::Result<u64>) -> io::Result<f64> {
+    let start = Instant::now();
+    let mut raw = 0;
+    loop {
+        raw += pass()?;
+        if start.elapsed() >= MIN_DURATION {
+            return Ok(raw as f64 / start.elapsed().as_secs_f64())
+        }
+    }
+}
+
+fn hash(sample: &[u8]) -> io::Result<Measurement> {
+    let write_rate = rate(|| {
+        let mut writer = HashWriter::new(io::sink());
+        let mut raw = 0;
+        for piece in pieces(sample) {
+            writer.write_all(piece)?;
+            raw += piece.len() as u64;
+        }
+        writer.finalize();
+        Ok(raw)
+    })?;
+    let read_rate = rate(|| {
+        let mut reader = HashReader::new(sample);
+        let raw = io::copy(&mut reader, &mut io::sink())?;
+        reader.finalize();
+        Ok(raw)
+    })?;
+    Ok(Measurement{ stage: Stage::Hash, write_rate, read_rate: Some(read_rate), ratio: None })
+}
+
+fn compression(sample: &[u8], codec: CompressionAlgorithm, level: u8) -> io::Result<Measurement> {
+    let mut compressed = Vec::new();
+    let mut raw = 0;
+    let write_rate = rate(|| {
+        let mut writer = PerhapsCompressedWriter::with_params(Vec::with_capacity(sample.len()), Some(codec), level, None, &ZstdParams::default())?;
+        raw = 0;
+        for piece in pieces(sample) {
+            writer.write_all(piece)?;
+            raw += piece.len() as u64;
+        }
+        compressed = writer.finish()?;
+        Ok(raw)
+    })?;
+    let read_rate = rate(|| {
+        let mut reader = PerhapsCompressedReader::with_params(compressed.as_slice(), Some(codec), None, None)?;
+        io::copy(&mut reader, &mut io::sink())
+    })?;
+    let ratio = compression_ratio(raw, compressed.len() as u64);
+    Ok(Measurement{ stage: Stage::Compression{ codec, level }, write_rate, read_rate: Some(read_rate), ratio })
+}
+
+fn encryption(sample: &[u8]) -> io::Result<Measurement> {
+    let mut cipher = Some(ChaCha20Poly1305::new(&prepare_key(KEY)));
+    let mut encrypted = Vec::new();
+    let write_rate = rate(|| {
+        let mut writer = PerhapsEncrWriter::with_encryptor(Vec::with_capacity(sample.len() + sample.len() / 64), &mut cipher);
+        let mut raw = 0;
+        for piece in pieces(sample) {
+            writer.write_all(piece)?;
+            raw += piece.len() as u64;
+        }
+        encrypted = writer.into_inner();
+        Ok(raw)
+    })?;
+    let read_rate = rate(|| {
+        let mut reader = PerhapsEncrReader::with_decryptor(encrypted.as_slice(), &mut cipher);
+        io::copy(&mut reader, &mut io::sink())
+    })?;
+    Ok(Measurement{ stage: Stage::Encryption, write_rate, read_rate: Some(read_rate), ratio: None })
+}
+
+/// Downloads the sample in `dir` into it, from a Hoster on this machine.
+/// Timed by the client, from its first file to its last.
+fn loopback(dir: &Path, compression: Option<(CompressionAlgorithm, u8)>, encryption: bool) -> anyhow::Result<Measurement> {
+    let key = encryption.then(|| KEY.to_owned());
+    let listener = transport::bind("127.0.0.1:0")?;
+    let address = listener.local_addr();
+    let mut server = Server::new(address.clone())
+        .with_paths([dir.join("sample").to_string_lossy()])
+        .with_encryption_key(key.clone());
+    if let Some((codec, level)) = compression {
+        server = server.with_compression(true, level).with_codec(codec);
+    }
+    let destination = dir.join("download");
+    let client = Client::new(address, &destination)
+        .with_compression(compression.is_some())
+        .with_encryption_key(key)
+        .with_retry_policy(RetryPolicy{ max_attempts: 0, ..Default::default() });
+
+    let (server_events, _server_events) = std::sync::mpsc::channel();
+    let (client_events, received) = std::sync::mpsc::channel();
+    let downloaded = std::thread::scope(|scope| {
+        let serving = scope.spawn(|| server.serve_on(listener, server_events));
+        let downloaded = client.download(client_events);
+        server.control().cancel();
+        let served = serving.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        downloaded.and(served)
+    });
+    let _ = std::fs::remove_dir_all(&destination);
+    downloaded?;
+
+    let Some((time_taken, raw, wire)) = received.try_iter().find_map(|event| match event {
+        ClientEvent::Completed{ time_taken, raw, wire } => Some((time_taken, raw, wire)),
+        _ => None
+    }) else {
+        anyhow::bail!("The transfer did not complete")
+    };
+    Ok(Measurement{
+        stage: Stage::Loopback{ compression, encryption },
+        write_rate: raw as f64 / time_taken.as_secs_f64().max(f64::EPSILON),
+        read_rate: None,
+        ratio: compression.and(compression_ratio(raw, wire))
+    })
+}
+
+
+/// For every link, the compression getting the most through it.
+///
+/// Estimated for the Hoster's side, hashing, compressing and encrypting one after the other
+/// (see [`Server::with_pipeline`] for overlapping them), while the link sends in parallel.
+fn recommend(measurements: &[Measurement], links: &[u64]) -> Vec<Recommendation> {
+    let rate_of = |stage| measurements.iter().find(|m| m.stage == stage).map(|m| m.write_rate);
+    let (Some(hash), Some(encryption)) = (rate_of(Stage::Hash), rate_of(Stage::Encryption)) else {
+        return Vec::new()
+    };
+    // (none first, then the levels from the lowest, as the cheaper ones are preferred)
+    let candidates: Vec<_> = std::iter::once((None, f64::INFINITY, 1.))
+        .chain(measurements.iter().filter_map(|m| match m.stage {
+            Stage::Compression{ codec, level } => Some((Some((codec, level)), m.write_rate, m.ratio.unwrap_or(1.))),
+            _ => None
+        }))
+        .collect();
+
+    let best = |link: u64, encrypted: bool| {
+        let expected: Vec<Choice> = candidates.iter().map(|&(compression, rate, ratio)| {
+            let mut seconds = 1. / hash + 1. / rate;
+            if encrypted {
+                // (only what is left after compressing is encrypted)
+                seconds += 1. / (encryption * ratio);
+            }
+            Choice{ compression, rate: (1. / seconds).min(link as f64 * ratio) }
+        }).collect();
+        let max = expected.iter().map(|choice| choice.rate).fold(0., f64::max);
+        *expected.iter().find(|choice| choice.rate >= max * (1. - TOLERANCE)).unwrap()
+    };
+    links.iter().map(|&link| Recommendation{ link, plain: best(link, false), encrypted: best(link, true) }).collect()
+}
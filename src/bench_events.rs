@@ -0,0 +1,55 @@
+use bytesize::ByteSize;
+
+use crate::bench::{Measurement, Recommendation, Settings};
+
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+
+
+#[derive(Debug)]
+pub enum BenchEvent {
+    /// The sample has been read (or generated)
+    Sample{ description: String, size: u64 },
+    Measured(Measurement),
+    /// The best settings for each link, at the end
+    Recommended(Vec<Recommendation>)
+}
+
+
+pub fn handle_events_cli(recv: Receiver<BenchEvent>) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    while let Ok(msg) = recv.recv() {
+        match msg {
+            BenchEvent::Sample{ description, size } => {
+                writeln!(&mut stdout, "Measuring on {} of {description}\n", ByteSize(size))?;
+                writeln!(&mut stdout, "{:<36} {:>6} {:>12} {:>12}", "Stage", "Ratio", "Hoster", "Client")?;
+            },
+            BenchEvent::Measured(Measurement{ stage, write_rate, read_rate, ratio }) => {
+                writeln!(&mut stdout, "{:<36} {:>6} {:>12} {:>12}",
+                         stage.to_string(),
+                         ratio.map_or_else(|| "-".into(), |ratio| format!("{ratio:.2}")),
+                         format_rate(write_rate),
+                         read_rate.map_or_else(|| "-".into(), format_rate))?;
+            },
+            BenchEvent::Recommended(recommendations) => {
+                writeln!(&mut stdout, "\nRecommended for the Hoster (estimated from its side, see --pipeline for more)\n")?;
+                writeln!(&mut stdout, "{:<12} {:<24} {:>12}   {:<24} {:>12} {:>11}", "Link", "Without -k", "Expected", "With -k", "Expected", "Cost of -k")?;
+                for Recommendation{ link, plain, encrypted } in recommendations {
+                    let cost = (1. - encrypted.rate / plain.rate) * 100.;
+                    writeln!(&mut stdout, "{:<12} {:<24} {:>12}   {:<24} {:>12} {:>10.0}%",
+                             format_rate(link as f64), Settings(plain.compression), format_rate(plain.rate),
+                             Settings(encrypted.compression), format_rate(encrypted.rate), cost)?;
+                }
+            }
+        }
+        stdout.flush()?;
+    }
+
+    Ok(())
+}
+
+/// e.g. "12.3 MB/s"
+fn format_rate(rate: f64) -> String {
+    format!("{}/s", ByteSize(rate as u64))
+}
@@ -2,8 +2,19 @@ pub mod hash_io;
 pub mod comp_io;
 pub mod encrypt_io;
 pub mod counting_io;
+pub mod frame_io;
+pub mod rate_io;
+pub mod channel_io;
+pub mod vectored_io;
+#[cfg(target_os = "linux")]
+pub mod zero_copy;
+#[cfg(feature = "async")]
+pub mod tokio_io;
 
 pub use hash_io::{HashReader, HashWriter, PerhapsHashingWriter};
-pub use comp_io::{PerhapsCompressedReader, PerhapsCompressedWriter};
+pub use comp_io::{PerhapsCompressedReader, PerhapsCompressedWriter, ZstdParams};
 pub use encrypt_io::{PerhapsEncrReader, PerhapsEncrWriter};
 pub use counting_io::{CountingReader, CountingWriter};
+pub use frame_io::{FramedReader, FramedWriter};
+pub use rate_io::{RateLimiter, RateLimitedReader, RateLimitedWriter};
+pub use vectored_io::VectoredBufWriter;
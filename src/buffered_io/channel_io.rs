@@ -0,0 +1,180 @@
+//! Bounded channels of buffers between the stages of a pipeline, each on a thread of its own,
+//! so that the usual adapters can be used as the stages' bodies.
+use std::io::{self, BufRead, Read, Write};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread::ScopedJoinHandle;
+
+
+/// Chunks are collected up to this size before being sent on
+pub const CHUNK_SIZE: usize = 1 << 16;
+/// Chunks on their way from one stage to the next (at most)
+pub const CHANNEL_DEPTH: usize = 4;
+
+
+/// A channel from one stage to the next
+pub fn channel() -> (ChannelWriter, Receiver<Vec<u8>>) {
+    let (sender, receiver) = std::sync::mpsc::sync_channel(CHANNEL_DEPTH);
+    (ChannelWriter{ sender, buffer: Vec::new() }, receiver)
+}
+
+
+/// The stage on the other end of a channel stopped (because it failed, its error is the one to report)
+#[derive(Debug)]
+pub struct StageStopped;
+
+impl std::fmt::Display for StageStopped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The next stage of the pipeline stopped")
+    }
+}
+
+impl std::error::Error for StageStopped {}
+
+fn is_stage_stopped(error: &anyhow::Error) -> bool {
+    error.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| e.get_ref().is_some_and(|e| e.is::<StageStopped>()))
+}
+
+
+/// Collects written Bytes into chunks, sending them to the next stage.
+/// Has to be flushed at the end (dropping it flushes too, ignoring errors).
+pub struct ChannelWriter {
+    sender: SyncSender<Vec<u8>>,
+    buffer: Vec<u8>
+}
+
+impl ChannelWriter {
+    /// Sends `chunk` as it is (after what has been written before)
+    pub fn send(&mut self, chunk: Vec<u8>) -> io::Result<()> {
+        self.flush()?;
+        self.sender.send(chunk).map_err(|_| io::Error::other(StageStopped))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.capacity() == 0 {
+            self.buffer.reserve_exact(CHUNK_SIZE);
+        }
+        let amount = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..amount]);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(amount)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(())
+        }
+        let chunk = std::mem::take(&mut self.buffer);
+        self.sender.send(chunk).map_err(|_| io::Error::other(StageStopped))
+    }
+}
+
+impl Drop for ChannelWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+
+/// Reads the chunks sent by the previous stage, until it is done
+pub struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize
+}
+
+impl ChannelReader {
+    pub fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        Self{ receiver, chunk: Vec::new(), position: 0 }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.fill_buf()?;
+        let amount = data.len().min(buf.len());
+        buf[..amount].copy_from_slice(&data[..amount]);
+        self.consume(amount);
+        Ok(amount)
+    }
+}
+
+impl BufRead for ChannelReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.chunk.len() {
+            // (ended, once the previous stage is done)
+            if let Ok(chunk) = self.receiver.recv() {
+                self.chunk = chunk;
+                self.position = 0;
+            }
+        }
+        Ok(&self.chunk[self.position..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position += amt;
+    }
+}
+
+
+/// Writes every chunk sent by the previous stage into `writer`
+pub fn write_chunks(input: Receiver<Vec<u8>>, writer: &mut impl Write) -> io::Result<()> {
+    for chunk in input {
+        writer.write_all(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Sends everything `reader` reads to the next stage, returning how much that was
+pub fn forward(mut reader: impl Read, output: &mut ChannelWriter) -> io::Result<u64> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut forwarded = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        output.write_all(&buffer[..read])?;
+        forwarded += read as u64;
+    }
+    output.flush()?;
+    Ok(forwarded)
+}
+
+/// Waits for a stage, passing its panic on
+pub fn join<T>(stage: ScopedJoinHandle<'_, T>) -> T {
+    stage.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+
+/// The error that broke a pipeline: the first one (in the order of the stages),
+/// which is not just the consequence of another stage stopping
+#[derive(Debug, Default)]
+pub struct StageErrors(Option<anyhow::Error>);
+
+impl StageErrors {
+    /// The value of a stage that succeeded, otherwise keeps its error
+    pub fn take<T, E: Into<anyhow::Error>>(&mut self, result: Result<T, E>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                let e = e.into();
+                if self.0.as_ref().is_none_or(|kept| is_stage_stopped(kept) && !is_stage_stopped(&e)) {
+                    self.0 = Some(e);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn into_result(self) -> anyhow::Result<()> {
+        self.0.map_or(Ok(()), Err)
+    }
+}
@@ -1,33 +1,135 @@
-use std::io::{BufRead, Read, Write};
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use brotli::{CompressorWriter, Decompressor};
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
 use zstd::{Decoder, Encoder};
 
+use crate::CompressionAlgorithm;
+
+
+/// How much of a file is looked at, to decide whether to compress it
+pub const SAMPLE_SIZE: usize = 256 << 10;
+
+/// Extensions of formats which are compressed already
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp4", "m4v", "mkv", "mov", "webm", "avi",
+    "mp3", "m4a", "aac", "ogg", "opus", "flac",
+    "zip", "gz", "tgz", "bz2", "xz", "zst", "lz4", "br", "7z", "rar",
+    "jar", "apk", "docx", "xlsx", "pptx", "odt", "epub",
+];
+
+/// Magic bytes (at the given offset) of formats which are compressed already
+const COMPRESSED_MAGIC: &[(usize, &[u8])] = &[
+    (0, b"\x1f\x8b"),             // gzip
+    (0, b"PK\x03\x04"),           // zip (and everything based on it)
+    (0, b"\x28\xb5\x2f\xfd"),     // zstd
+    (0, b"\xfd7zXZ\x00"),         // xz
+    (0, b"BZh"),                  // bzip2
+    (0, b"7z\xbc\xaf\x27\x1c"),   // 7z
+    (0, b"Rar!"),                 // rar
+    (0, b"\x89PNG"),              // png
+    (0, b"\xff\xd8\xff"),         // jpeg
+    (0, b"OggS"),                 // ogg
+    (0, b"fLaC"),                 // flac
+    (4, b"ftyp"),                 // mp4, mov, heic, ...
+];
+
+/// brotli's highest level
+pub const BROTLI_MAX_LEVEL: u8 = 11;
+/// Log2 of brotli's window (its default)
+pub const BROTLI_WINDOW_LOG: u32 = 22;
+/// Of brotli's internal buffers
+const BROTLI_BUFFER_SIZE: usize = 1 << 12;
+
+/// Largest window log zstd decoders accept, unless told otherwise
+pub const DEFAULT_WINDOW_LOG_MAX: u32 = 27;
+
+
+/// zstd parameters beyond the level, mostly for huge files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ZstdParams {
+    /// Threads compressing in the background, 0 to compress on the writing thread
+    /// (more need the `zstdmt` feature)
+    pub workers: u32,
+    /// If some, matches are also searched far back, in a window of 2^`long_window` Bytes.
+    /// Decoders need to be told about windows above [`DEFAULT_WINDOW_LOG_MAX`].
+    pub long_window: Option<u32>,
+}
+
+
+/// Compressing has to save at least this fraction of the sample
+const MIN_SAVINGS: f64 = 0.05;
+
+/// Whether the file at `path`, starting with `sample`, is worth compressing.
+///
+/// Checks the extension and magic bytes for formats that are compressed already,
+/// otherwise compresses the sample quickly to see if it shrinks.
+pub fn worth_compressing(path: &Path, sample: &[u8]) -> bool {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    if extension.is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.as_str())) {
+        return false
+    }
+    if COMPRESSED_MAGIC.iter().any(|(offset, magic)| sample.get(*offset..).is_some_and(|s| s.starts_with(magic))) {
+        return false
+    }
+
+    // does the sample shrink? (tiny files never do, which does not matter either)
+    match zstd::bulk::compress(sample, 1) {
+        Ok(compressed) => (compressed.len() as f64) < sample.len() as f64 * (1. - MIN_SAVINGS),
+        Err(_) => true
+    }
+}
+
 
 pub enum PerhapsCompressedReader<'a, R: BufRead> {
-    Compressed(Decoder<'a, R>),
+    Zstd(Decoder<'a, R>),
+    Lz4(FrameDecoder<R>),
+    Brotli(Box<Decompressor<R>>),
     UnCompressed(R)
 }
 
 impl<'a, R: BufRead> PerhapsCompressedReader<'a, R> {
     pub fn into_inner(self) -> R {
         match self {
-            Self::Compressed(decoder) => decoder.finish(),
+            Self::Zstd(decoder) => decoder.finish(),
+            Self::Lz4(decoder) => decoder.into_inner(),
+            Self::Brotli(decoder) => decoder.into_inner(),
             Self::UnCompressed(r) => r
         }
     }
-    
-    pub fn with_compression(reader: R, comp: bool) -> Self {
-        if comp {
-            Self::Compressed(Decoder::with_buffer(reader).unwrap())
-        } else {
-            Self::UnCompressed(reader)
-        }
+
+    /// Decompresses `codec` (if any).
+    /// zstd is decompressed with the Hoster's `dictionary` (if any),
+    /// accepting windows of up to 2^`window_log` Bytes (if some).
+    pub fn with_params(reader: R, codec: Option<CompressionAlgorithm>, dictionary: Option<&[u8]>, window_log: Option<u32>) -> io::Result<Self> {
+        Ok(match codec {
+            None => Self::UnCompressed(reader),
+            Some(CompressionAlgorithm::Zstd) => {
+                let mut decoder = match dictionary {
+                    Some(dictionary) => Decoder::with_dictionary(reader, dictionary)?,
+                    None => Decoder::with_buffer(reader)?
+                };
+                if let Some(window_log) = window_log {
+                    decoder.window_log_max(window_log)?;
+                }
+                Self::Zstd(decoder.single_frame())
+            },
+            Some(CompressionAlgorithm::Lz4) => Self::Lz4(FrameDecoder::new(reader)),
+            Some(CompressionAlgorithm::Brotli) => Self::Brotli(Box::new(Decompressor::new(reader, BROTLI_BUFFER_SIZE))),
+            Some(CompressionAlgorithm::Unknown) => return Err(unknown_codec())
+        })
     }
 }
 
 impl<'a, R: BufRead> Read for PerhapsCompressedReader<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         match self {
-            Self::Compressed(decoder) => decoder.read(buf),
+            Self::Zstd(decoder) => decoder.read(buf),
+            Self::Lz4(decoder) => decoder.read(buf),
+            Self::Brotli(decoder) => decoder.read(buf),
             Self::UnCompressed(reader) => reader.read(buf)
         }
     }
@@ -35,39 +137,88 @@ impl<'a, R: BufRead> Read for PerhapsCompressedReader<'a, R> {
 
 
 pub enum PerhapsCompressedWriter<'a, W: Write> {
-    Compressed(Encoder<'a, W>),
+    Zstd(Encoder<'a, W>),
+    Lz4(FrameEncoder<W>),
+    Brotli(Box<CompressorWriter<W>>),
     UnCompressed(W)
 }
 
 impl<'a, W: Write> PerhapsCompressedWriter<'a, W> {
-    pub fn finish(self) -> anyhow::Result<W> {
+    pub fn finish(self) -> std::io::Result<W> {
         Ok(match self {
-            Self::Compressed(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+            Self::Lz4(encoder) => encoder.finish()?,
+            // brotli ignores errors while finishing, at least report the previous ones
+            Self::Brotli(mut encoder) => {
+                encoder.flush()?;
+                encoder.into_inner()
+            },
             Self::UnCompressed(w) => w
         })
     }
     
-    pub fn with_compression(writer: W, comp: bool, level: u8) -> Self {
-        if comp {
-            Self::Compressed(Encoder::new(writer, level as i32).unwrap())
-        } else {
-            Self::UnCompressed(writer)
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::Zstd(encoder) => encoder.get_mut(),
+            Self::Lz4(encoder) => encoder.get_mut(),
+            Self::Brotli(encoder) => encoder.get_mut(),
+            Self::UnCompressed(w) => w
         }
     }
+
+    /// Compresses with `codec` (if any) at `level` (as far as it has levels).
+    /// zstd is compressed with a `dictionary` (if any) and `params`.
+    pub fn with_params(writer: W, codec: Option<CompressionAlgorithm>, level: u8, dictionary: Option<&[u8]>, params: &ZstdParams) -> io::Result<Self> {
+        Ok(match codec {
+            None => Self::UnCompressed(writer),
+            Some(CompressionAlgorithm::Zstd) => {
+                let mut encoder = match dictionary {
+                    Some(dictionary) => Encoder::with_dictionary(writer, level as i32, dictionary)?,
+                    None => Encoder::new(writer, level as i32)?
+                };
+                if params.workers > 0 {
+                    #[cfg(feature = "zstdmt")]
+                    encoder.multithread(params.workers)?;
+                    #[cfg(not(feature = "zstdmt"))]
+                    return Err(io::Error::new(io::ErrorKind::Unsupported, "Compression workers need the zstdmt feature"))
+                }
+                if let Some(window_log) = params.long_window {
+                    encoder.long_distance_matching(true)?;
+                    encoder.window_log(window_log)?;
+                }
+                Self::Zstd(encoder)
+            },
+            Some(CompressionAlgorithm::Lz4) => Self::Lz4(FrameEncoder::new(writer)),
+            Some(CompressionAlgorithm::Brotli) => Self::Brotli(Box::new(
+                CompressorWriter::new(writer, BROTLI_BUFFER_SIZE, level.min(BROTLI_MAX_LEVEL) as u32, BROTLI_WINDOW_LOG)
+            )),
+            Some(CompressionAlgorithm::Unknown) => return Err(unknown_codec())
+        })
+    }
 }
 
 impl<'a, W: Write> Write for PerhapsCompressedWriter<'a, W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         match self {
-            Self::Compressed(encoder) => { encoder.write(buf) },
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Lz4(encoder) => encoder.write(buf),
+            Self::Brotli(encoder) => encoder.write(buf),
             Self::UnCompressed(writer) => writer.write(buf)
         }
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
         match self {
-            Self::Compressed(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Lz4(encoder) => encoder.flush(),
+            Self::Brotli(encoder) => encoder.flush(),
             Self::UnCompressed(writer) => writer.flush()
         }
     }
-}
\ No newline at end of file
+}
+
+
+/// For algorithms only known to newer peers, which are never agreed on
+pub(crate) fn unknown_codec() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "Unknown compression algorithm")
+}
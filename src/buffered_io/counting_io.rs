@@ -1,20 +1,29 @@
-use std::io::{Read, Write};
+use std::io::{BufRead, Read, Write};
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct CountingWriter<W: Write> {
     writer: W,
-    pub written: usize
+    pub written: usize,
+    /// Time spent waiting for the inner writer
+    pub busy: Duration
 }
 
 impl<W: Write> CountingWriter<W> {
     pub fn new(writer: W) -> Self {
-        Self{ writer, written: 0 }
+        Self{ writer, written: 0, busy: Duration::ZERO }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
     }
 }
 
 impl<W: Write> Write for CountingWriter<W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let start = Instant::now();
         let written = self.writer.write(buf);
+        self.busy += start.elapsed();
         if let Ok(written) = written {
             self.written += written
         }
@@ -22,7 +31,10 @@ impl<W: Write> Write for CountingWriter<W> {
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.writer.flush()
+        let start = Instant::now();
+        let flushed = self.writer.flush();
+        self.busy += start.elapsed();
+        flushed
     }
 }
 
@@ -37,6 +49,14 @@ impl<R: Read> CountingReader<R> {
     pub fn new(reader: R) -> Self {
         Self{ reader, read: 0 }
     }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
 }
 
 impl<R: Read> Read for CountingReader<R> {
@@ -48,3 +68,21 @@ impl<R: Read> Read for CountingReader<R> {
         read
     }
 }
+
+/// Counts consumed Bytes, like reads
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.read += amt
+    }
+}
+
+
+/// How many times larger the `raw` Bytes are than the `wire` Bytes they took (None if either is none)
+pub fn compression_ratio(raw: u64, wire: u64) -> Option<f64> {
+    (raw > 0 && wire > 0).then(|| raw as f64 / wire as f64)
+}
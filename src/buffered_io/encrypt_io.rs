@@ -4,7 +4,9 @@ use sha2::digest::Digest;
 
 use std::cmp::min;
 use std::io::{BufRead, Read, Write};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::SmdError;
 
 
 pub fn prepare_key<S: AsRef<str>>(key: S) -> Key {
@@ -17,7 +19,34 @@ pub fn prepare_key<S: AsRef<str>>(key: S) -> Key {
 }
 
 
-pub fn maybe_encrypt_path(path: &Path, encryptor: &mut Option<ChaCha20Poly1305>) -> anyhow::Result<Vec<u8>> {
+/// Known text, encrypted by the client to prove it uses the same key as the server
+const KEY_CHECK: &[u8] = b"simple_mass_data_transfer key check";
+
+
+pub fn create_key_check(encryptor: &ChaCha20Poly1305) -> Vec<u8> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut check = KEY_CHECK.to_vec();
+    encryptor.encrypt_in_place(&nonce, b"", &mut check).unwrap();
+    
+    // append nonce
+    check.extend(nonce.as_slice());
+    check
+}
+
+
+/// Whether `check` has been created with the same key as `decryptor`
+pub fn verify_key_check(mut check: Vec<u8>, decryptor: &ChaCha20Poly1305) -> bool {
+    let Some((_, nonce)) = check.split_last_chunk::<12>() else {
+        return false
+    };
+    let nonce = Nonce::from(*nonce);
+    
+    check.truncate(check.len()-12);
+    decryptor.decrypt_in_place(&nonce, b"", &mut check).is_ok() && check == KEY_CHECK
+}
+
+
+pub fn maybe_encrypt_path(path: &Path, encryptor: &mut Option<ChaCha20Poly1305>) -> Vec<u8> {
     let mut path = path.to_string_lossy().into_owned().into_bytes();
     
     if let Some(encryptor) = encryptor {
@@ -29,25 +58,25 @@ pub fn maybe_encrypt_path(path: &Path, encryptor: &mut Option<ChaCha20Poly1305>)
         
         // append nonce
         path.extend(nonce.as_slice());
-        Ok(path)
-    }
-    else {
-        Ok(path)
     }
+    path
 }
 
 
-pub fn maybe_decrypt_path(mut path_bytes: Vec<u8>, decryptor: &mut Option<ChaCha20Poly1305>) -> anyhow::Result<PathBuf> {
+/// Also makes sure the path is relative and stays inside the directory it is joined onto.
+pub fn maybe_decrypt_path(mut path_bytes: Vec<u8>, decryptor: &mut Option<ChaCha20Poly1305>) -> Result<PathBuf, SmdError> {
     let bytes = if let Some(decryptor) = decryptor {
-        // grab hex from first 12 bytes
-        let nonce = path_bytes.as_slice().split_last_chunk::<12>().unwrap().1;
+        // grab nonce from last 12 bytes
+        let Some((_, nonce)) = path_bytes.as_slice().split_last_chunk::<12>() else {
+            return Err(SmdError::ProtocolViolation("Encrypted path is too short".into()))
+        };
         let nonce = Nonce::from(*nonce);
 
         // decrypt rest
         path_bytes.truncate(path_bytes.len()-12);
         if decryptor.decrypt_in_place(&nonce, b"", &mut path_bytes).is_err() {
-            // this error's, when a wrong encryption key is used
-            anyhow::bail!("Wrong encryption key used!")
+            // (the key has been checked during the handshake)
+            return Err(SmdError::ProtocolViolation("An encrypted path is corrupted or has been tampered with".into()))
         }
         path_bytes
     }
@@ -55,13 +84,44 @@ pub fn maybe_decrypt_path(mut path_bytes: Vec<u8>, decryptor: &mut Option<ChaCha
         path_bytes
     };
 
-    Ok(PathBuf::from(String::from_utf8(bytes)?))
+    let path = PathBuf::from(String::from_utf8(bytes)
+        .map_err(|_| SmdError::ProtocolViolation("Path is not valid UTF-8".into()))?);
+    
+    // no absolute paths or escaping via ".."
+    if !path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(SmdError::UnsafePath(path))
+    }
+    Ok(path)
+}
+
+
+/// Of the nonce and length preceding every encrypted block
+const BLOCK_HEADER_SIZE: usize = 12 + 4;
+/// Of the tag following every encrypted block
+pub(crate) const TAG_SIZE: usize = 16;
+/// Most data in one encrypted block, larger writes are split
+pub const MAX_BLOCK_SIZE: usize = 1 << 20;
+
+
+/// The length of an encrypted block (data and tag), as sent in its header.
+/// Errors, if no writer produces blocks this long.
+pub(crate) fn block_length(header: [u8; 4]) -> std::io::Result<usize> {
+    let length = u32::from_be_bytes(header) as usize;
+    if !(TAG_SIZE..=MAX_BLOCK_SIZE + TAG_SIZE).contains(&length) {
+        return Err(std::io::Error::other(SmdError::ProtocolViolation(format!("Encrypted block of {length} Bytes"))))
+    }
+    Ok(length)
 }
 
+/// For blocks failing to decrypt, as the key has been checked during the handshake
+pub(crate) fn corrupted_block() -> std::io::Error {
+    std::io::Error::other(SmdError::ProtocolViolation("An encrypted block is corrupted or has been tampered with".into()))
+}
 
 pub enum PerhapsEncrWriter<'a, W: Write> {
     Plain(W),
     Encrypted{
+        /// The block being sent (header, ciphertext and tag), written at once
         buffer: Vec<u8>,
         encryptor: &'a mut ChaCha20Poly1305,
         writer: W
@@ -88,6 +148,13 @@ impl<'a, W: Write> PerhapsEncrWriter<'a, W> {
             Self::Encrypted {writer, ..} => writer
         }
     }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::Plain(w) => w,
+            Self::Encrypted {writer, ..} => writer
+        }
+    }
 }
 
 impl<'a, W: Write> Write for PerhapsEncrWriter<'a, W> {
@@ -95,18 +162,21 @@ impl<'a, W: Write> Write for PerhapsEncrWriter<'a, W> {
         match self {
             Self::Plain(w) => w.write(buf),
             Self::Encrypted {writer, encryptor, buffer} => {
+                let buf = &buf[..buf.len().min(MAX_BLOCK_SIZE)];
                 // generate nonce
                 let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-                // write nonce
-                writer.write_all(nonce.as_slice())?;
-                // write total length of data (including padding and tag)
-                let length = buf.len() + 16;
-                writer.write_all(&(length as u32).to_be_bytes())?;
-
-                // encrypt and send data
-                buffer.resize_with(buf.len(), || 0);
-                buffer.copy_from_slice(buf);
-                encryptor.encrypt_in_place(&nonce, b"", buffer).unwrap();
+                buffer.clear();
+                buffer.extend_from_slice(nonce.as_slice());
+                // total length of data (including padding and tag)
+                let length = buf.len() + TAG_SIZE;
+                buffer.extend_from_slice(&(length as u32).to_be_bytes());
+
+                // encrypt data behind the header
+                buffer.extend_from_slice(buf);
+                let tag = encryptor.encrypt_in_place_detached(&nonce, b"", &mut buffer[BLOCK_HEADER_SIZE..]).unwrap();
+                buffer.extend_from_slice(tag.as_slice());
+
+                // and send the whole block in one go
                 writer.write_all(buffer)?;
 
                 Ok(buf.len())
@@ -178,6 +248,10 @@ impl<'a, R: Read + BufRead> BufRead for PerhapsEncrReader<'a, R> {
                 if *already_read < buffer.len() {
                     Ok(&buffer[*already_read..buffer.len()])
                 }
+                // the stream ended between blocks
+                else if reader.fill_buf()?.is_empty() {
+                    Ok(&[])
+                }
                 // get and decrypt more bytes from reader
                 else {
                     // get nonce
@@ -188,14 +262,13 @@ impl<'a, R: Read + BufRead> BufRead for PerhapsEncrReader<'a, R> {
                     // get length of encrypted block
                     let mut length = [0u8; 4];
                     reader.read_exact(&mut length)?;
-                    let length = u32::from_be_bytes(length) as usize;
+                    let length = block_length(length)?;
 
                     // decrypt into buffer
                     buffer.resize_with(length, || 0);
                     reader.read_exact(buffer)?;
                     if decryptor.decrypt_in_place(&nonce, b"", buffer).is_err() {
-                        // this error's, when a wrong encryption key is used
-                        return Err(std::io::Error::other("Wrong encryption key used!"))
+                        return Err(corrupted_block())
                     }
                     *already_read = 0;
 
@@ -0,0 +1,187 @@
+use std::cmp::min;
+use std::io::{BufRead, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::error::SmdError;
+#[cfg(target_os = "linux")]
+use super::zero_copy::{Pipe, SendFile, SpliceRead};
+
+
+// Tags of the frames a file is sent in.
+// A data frame is followed by its length (u32, big endian) and payload,
+// the others consist of only their tag.
+pub(crate) const DATA: u8 = 0;
+/// Keeps the connection alive, while no data is available (yet)
+pub(crate) const HEARTBEAT: u8 = 1;
+/// Marks the end of the file
+pub(crate) const END: u8 = 2;
+
+
+pub struct FramedWriter<W: Write> {
+    writer: W,
+    last_frame: Instant,
+    last_flush: Instant
+}
+
+impl<W: Write> FramedWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self{ writer, last_frame: Instant::now(), last_flush: Instant::now() }
+    }
+
+    /// Send a heartbeat, if no frame has been sent during the last `interval`.
+    /// Frames still sitting in a buffer further down are flushed as often.
+    pub fn heartbeat_if_idle(&mut self, interval: Duration) -> std::io::Result<()> {
+        let idle = self.last_frame.elapsed() >= interval;
+        if idle {
+            self.writer.write_all(&[HEARTBEAT])?;
+            self.last_frame = Instant::now();
+        }
+        if idle || self.last_flush.elapsed() >= interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends `count` Bytes of `file` from `offset` on in one data frame, without copying them through userspace
+    #[cfg(target_os = "linux")]
+    pub fn send_file(&mut self, file: &std::fs::File, mut offset: u64, count: u32) -> std::io::Result<()> where W: SendFile {
+        if count == 0 {
+            return Ok(())
+        }
+        self.writer.write_all(&[DATA])?;
+        self.writer.write_all(&count.to_be_bytes())?;
+
+        // the frame has been announced, it must be filled
+        let mut remaining = count as usize;
+        while remaining > 0 {
+            match self.writer.send_file(file, &mut offset, remaining)? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                sent => remaining -= sent
+            }
+        }
+        self.last_frame = Instant::now();
+        Ok(())
+    }
+
+    /// Marks the end of the stream
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.writer.write_all(&[END])?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for FramedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // an empty data frame would be pointless
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        let buf = &buf[..min(buf.len(), u32::MAX as usize)];
+
+        self.writer.write_all(&[DATA])?;
+        self.writer.write_all(&(buf.len() as u32).to_be_bytes())?;
+        self.writer.write_all(buf)?;
+        self.last_frame = Instant::now();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.last_flush = Instant::now();
+        self.writer.flush()
+    }
+}
+
+
+pub struct FramedReader<R: BufRead> {
+    reader: R,
+    /// of the current data frame
+    remaining: usize,
+    ended: bool
+}
+
+impl<R: BufRead> FramedReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self{ reader, remaining: 0, ended: false }
+    }
+
+    /// Skips everything up to and including the end of the stream
+    pub fn finish(mut self) -> std::io::Result<R> {
+        loop {
+            let available = self.fill_buf()?.len();
+            if available == 0 {
+                return Ok(self.reader)
+            }
+            self.consume(available);
+        }
+    }
+
+    /// Moves up to `count` Bytes of data into `file` without copying them through userspace,
+    /// returning how many were moved (0 at the end of the stream)
+    #[cfg(target_os = "linux")]
+    pub fn splice_to(&mut self, pipe: &Pipe, file: &std::fs::File, count: usize) -> std::io::Result<usize> where R: SpliceRead {
+        // (only the header is read, refilling the buffer would copy the data through userspace again)
+        if !self.next_frame()? {
+            return Ok(0)
+        }
+        let moved = self.reader.splice_to(pipe, file, min(count, self.remaining))?;
+        if moved == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into())
+        }
+        self.remaining -= moved;
+        Ok(moved)
+    }
+
+    /// Reads frame headers until within a data frame, false at the end of the stream
+    fn next_frame(&mut self) -> std::io::Result<bool> {
+        while self.remaining == 0 && !self.ended {
+            match self.read_tag()? {
+                DATA => {
+                    let mut length = [0u8; 4];
+                    self.reader.read_exact(&mut length)?;
+                    self.remaining = u32::from_be_bytes(length) as usize;
+                },
+                HEARTBEAT => (),
+                END => self.ended = true,
+                tag => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, SmdError::ProtocolViolation(format!("Invalid frame tag {tag}"))))
+            }
+        }
+        Ok(!self.ended)
+    }
+
+    fn read_tag(&mut self) -> std::io::Result<u8> {
+        let mut tag = [0u8];
+        self.reader.read_exact(&mut tag)?;
+        Ok(tag[0])
+    }
+}
+
+impl<R: BufRead> Read for FramedReader<R> {
+    fn read(&mut self, dist_buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = self.fill_buf()?;
+        let to_copy = min(dist_buf.len(), data.len());
+
+        dist_buf[..to_copy].copy_from_slice(&data[..to_copy]);
+        self.consume(to_copy);
+        Ok(to_copy)
+    }
+}
+
+impl<R: BufRead> BufRead for FramedReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if !self.next_frame()? {
+            return Ok(&[])
+        }
+
+        let data = self.reader.fill_buf()?;
+        if data.is_empty() {
+            return Err(std::io::ErrorKind::UnexpectedEof.into())
+        }
+        Ok(&data[..min(data.len(), self.remaining)])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.remaining -= amt;
+    }
+}
@@ -53,6 +53,10 @@ impl<W: Write> HashWriter<W> {
             *self.hasher.finalize().split_first_chunk::<16>().unwrap().0)
         )
     }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
 }
 
 impl<W: Write> Write for HashWriter<W> {
@@ -91,6 +95,13 @@ impl<W: Write> PerhapsHashingWriter<W> {
             Self::Hashing(hasher) => hasher.finalize()
         }
     }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::Precomputed { writer, .. } => writer,
+            Self::Hashing(hasher) => hasher.get_mut()
+        }
+    }
 }
 
 impl<W: Write> Write for PerhapsHashingWriter<W> {
@@ -0,0 +1,163 @@
+use std::cmp::min;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+
+/// Passed on at once (at most), to wait in small steps
+pub(crate) const MAX_CHUNK: usize = 1 << 14;
+/// How much unused rate may be saved up
+const BURST: Duration = Duration::from_millis(250);
+
+
+/// Token bucket limiting the Bytes per second passing through it.
+///
+/// Clones share the bucket, the rate can be changed at any time (from any thread).
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    /// Bytes per second, 0 for unlimited
+    rate: Arc<AtomicU64>,
+    bucket: Arc<Mutex<Bucket>>
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative, if more has passed than the rate allows (to be waited for)
+    tokens: f64,
+    last_refill: Instant
+}
+
+impl RateLimiter {
+    /// `rate` in Bytes per second, `None` for unlimited
+    pub fn new(rate: Option<u64>) -> Self {
+        Self{
+            rate: Arc::new(AtomicU64::new(rate.unwrap_or(0))),
+            bucket: Arc::new(Mutex::new(Bucket{ tokens: 0., last_refill: Instant::now() }))
+        }
+    }
+
+    /// Another (empty) bucket, following the same rate
+    pub fn new_bucket(&self) -> Self {
+        Self{ rate: self.rate.clone(), ..Self::new(None) }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        Some(self.rate.load(Ordering::Relaxed)).filter(|rate| *rate > 0)
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.rate.store(rate.unwrap_or(0), Ordering::Relaxed)
+    }
+
+    /// Takes `amount` Bytes out of the bucket, returning how long to wait for them
+    pub fn take(&self, amount: usize) -> Duration {
+        let Some(rate) = self.rate() else {
+            return Duration::ZERO
+        };
+        let rate = rate as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+
+        // refill (a full bucket holds at least one chunk)
+        let now = Instant::now();
+        let capacity = (rate * BURST.as_secs_f64()).max(MAX_CHUNK as f64);
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.last_refill).as_secs_f64() * rate).min(capacity);
+        bucket.last_refill = now;
+
+        bucket.tokens -= amount as f64;
+        if bucket.tokens >= 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// The longest wait any of `limiters` demands for `amount` Bytes
+pub(crate) fn take_all(limiters: &[RateLimiter], amount: usize) -> Duration {
+    limiters.iter()
+        .map(|limiter| limiter.take(amount))
+        .max()
+        .unwrap_or_default()
+}
+
+
+/// Blocks writes according to all of its [`RateLimiter`]s.
+pub struct RateLimitedWriter<'a, W: Write> {
+    writer: W,
+    limiters: &'a [RateLimiter]
+}
+
+impl<'a, W: Write> RateLimitedWriter<'a, W> {
+    pub fn new(writer: W, limiters: &'a [RateLimiter]) -> Self {
+        Self{ writer, limiters }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub(crate) fn limiters(&self) -> &'a [RateLimiter] {
+        self.limiters
+    }
+}
+
+impl<'a, W: Write> Write for RateLimitedWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(&buf[..min(buf.len(), MAX_CHUNK)])?;
+        std::thread::sleep(take_all(self.limiters, written));
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+
+/// Blocks reads according to all of its [`RateLimiter`]s.
+pub struct RateLimitedReader<'a, R: Read> {
+    reader: R,
+    limiters: &'a [RateLimiter]
+}
+
+impl<'a, R: Read> RateLimitedReader<'a, R> {
+    pub fn new(reader: R, limiters: &'a [RateLimiter]) -> Self {
+        Self{ reader, limiters }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub(crate) fn limiters(&self) -> &'a [RateLimiter] {
+        self.limiters
+    }
+}
+
+impl<'a, R: Read> Read for RateLimitedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let amount = min(buf.len(), MAX_CHUNK);
+        let read = self.reader.read(&mut buf[..amount])?;
+        std::thread::sleep(take_all(self.limiters, read));
+        Ok(read)
+    }
+}
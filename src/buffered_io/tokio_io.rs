@@ -0,0 +1,916 @@
+//! [`tokio`] versions of the adapters in [`buffered_io`](super), producing the same wire format.
+//!
+//! Unlike their blocking counterparts, writers buffer (at most) one frame or block internally,
+//! so they have to be flushed or shut down.
+//! Shutting down a file's writer chain writes the end of its stream,
+//! but leaves the underlying stream open.
+
+use async_compression::tokio::bufread::{BrotliDecoder, Lz4Decoder, ZstdDecoder};
+use async_compression::zstd::DParameter;
+use chacha20poly1305::{AeadCore, AeadInPlace, ChaCha20Poly1305, Nonce};
+use chacha20poly1305::aead::OsRng;
+use md5::{Digest, Md5};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use std::cmp::min;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::CompressionAlgorithm;
+use crate::error::SmdError;
+use super::frame_io::{DATA, END, HEARTBEAT};
+use super::rate_io::{RateLimiter, MAX_CHUNK, take_all};
+use super::comp_io::{ZstdParams, unknown_codec};
+use super::encrypt_io::{block_length, corrupted_block, MAX_BLOCK_SIZE, TAG_SIZE};
+
+
+/// Fails with `TimedOut`, if `future` does not complete within `timeout` (if some)
+pub async fn with_timeout<T, F: Future<Output = io::Result<T>>>(timeout: Option<Duration>, future: F) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => future.await
+    }
+}
+
+/// Writes `buffer[*written..]` into `writer`, then clears `buffer`
+fn poll_drain<W: AsyncWrite + Unpin>(writer: &mut W, cx: &mut Context<'_>, buffer: &mut Vec<u8>, written: &mut usize) -> Poll<io::Result<()>> {
+    while *written < buffer.len() {
+        let n = ready!(Pin::new(&mut *writer).poll_write(cx, &buffer[*written..]))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()))
+        }
+        *written += n;
+    }
+    buffer.clear();
+    *written = 0;
+    Poll::Ready(Ok(()))
+}
+
+/// [`AsyncRead`] through the [`AsyncBufRead`] interface of `reader`
+fn poll_read_buffered<R: AsyncBufRead + Unpin>(reader: &mut R, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let amount = {
+        let data = ready!(Pin::new(&mut *reader).poll_fill_buf(cx))?;
+        let amount = min(data.len(), buf.remaining());
+        buf.put_slice(&data[..amount]);
+        amount
+    };
+    Pin::new(reader).consume(amount);
+    Poll::Ready(Ok(()))
+}
+
+
+/// Reads msgpack messages, while also being the buffered reader of the stream they are sent in.
+pub struct MessageReader<R: AsyncRead + Unpin> {
+    reader: R,
+    buffer: Vec<u8>,
+    /// Bytes of `buffer` already consumed
+    position: usize
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self{ reader, buffer: Vec::with_capacity(1 << 13), position: 0 }
+    }
+
+    /// Bytes still buffered are lost
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    pub async fn read_message<T: DeserializeOwned>(&mut self) -> io::Result<T> {
+        loop {
+            // try to decode from what is buffered
+            let mut cursor = io::Cursor::new(&self.buffer[self.position..]);
+            match T::deserialize(&mut rmp_serde::Deserializer::new(&mut cursor)) {
+                Ok(message) => {
+                    self.position += cursor.position() as usize;
+                    return Ok(message)
+                },
+                Err(rmp_serde::decode::Error::InvalidMarkerRead(e) | rmp_serde::decode::Error::InvalidDataRead(e))
+                    if e.kind() == io::ErrorKind::UnexpectedEof => (),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, SmdError::ProtocolViolation(e.to_string())))
+            }
+
+            // the message is incomplete, read more
+            self.buffer.drain(..self.position);
+            self.position = 0;
+            self.buffer.reserve(1 << 13);
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MessageReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        poll_read_buffered(self.get_mut(), cx, buf)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncBufRead for MessageReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.position == this.buffer.len() {
+            this.buffer.clear();
+            this.position = 0;
+            this.buffer.resize(1 << 13, 0);
+            let mut buf = ReadBuf::new(&mut this.buffer);
+            let result = Pin::new(&mut this.reader).poll_read(cx, &mut buf);
+            let filled = buf.filled().len();
+            this.buffer.truncate(filled);
+            ready!(result)?;
+        }
+        Poll::Ready(Ok(&this.buffer[this.position..]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().position += amt;
+    }
+}
+
+/// Sends a msgpack message (and flushes)
+pub async fn write_message<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    write_message_unflushed(writer, message).await?;
+    writer.flush().await
+}
+
+/// Writes a msgpack message without flushing, e.g. into a compressor
+pub async fn write_message_unflushed<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let bytes = rmp_serde::to_vec(message).map_err(io::Error::other)?;
+    writer.write_all(&bytes).await
+}
+
+
+#[derive(Debug)]
+pub struct CountingWriter<W: AsyncWrite + Unpin> {
+    writer: W,
+    pub written: usize,
+    /// Time spent waiting for the inner writer
+    pub busy: Duration,
+    /// Since the inner writer has been pending
+    pending_since: Option<Instant>
+}
+
+impl<W: AsyncWrite + Unpin> CountingWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self{ writer, written: 0, busy: Duration::ZERO, pending_since: None }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Adds the time from the first pending poll up to a ready one to `busy`
+    fn track<T>(&mut self, poll: Poll<T>) -> Poll<T> {
+        let now = Instant::now();
+        match (&poll, self.pending_since) {
+            (Poll::Pending, None) => self.pending_since = Some(now),
+            (Poll::Ready(_), Some(since)) => {
+                self.busy += now - since;
+                self.pending_since = None;
+            },
+            _ => ()
+        }
+        poll
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.writer).poll_write(cx, buf);
+        let written = ready!(this.track(poll))?;
+        this.written += written;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.writer).poll_flush(cx);
+        this.track(poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_shutdown(cx)
+    }
+}
+
+
+/// Counts the consumed Bytes of a buffered reader
+#[derive(Debug)]
+pub struct CountingReader<R: AsyncBufRead + Unpin> {
+    reader: R,
+    pub read: usize
+}
+
+impl<R: AsyncBufRead + Unpin> CountingReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self{ reader, read: 0 }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        poll_read_buffered(self.get_mut(), cx, buf)
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for CountingReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().reader).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        Pin::new(&mut this.reader).consume(amt);
+        this.read += amt;
+    }
+}
+
+
+/// Delays writes according to all of its [`RateLimiter`]s.
+pub struct RateLimitedWriter<'a, W: AsyncWrite + Unpin> {
+    writer: W,
+    limiters: &'a [RateLimiter],
+    /// For the Bytes already passed on
+    delay: Option<Pin<Box<tokio::time::Sleep>>>
+}
+
+impl<'a, W: AsyncWrite + Unpin> RateLimitedWriter<'a, W> {
+    pub fn new(writer: W, limiters: &'a [RateLimiter]) -> Self {
+        Self{ writer, limiters, delay: None }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Waits for `delay` (if any), then clears it
+fn poll_delay(delay: &mut Option<Pin<Box<tokio::time::Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(sleep) = delay {
+        ready!(sleep.as_mut().poll(cx));
+        *delay = None;
+    }
+    Poll::Ready(())
+}
+
+/// The delay `limiters` demand for `amount` Bytes
+fn rate_delay(limiters: &[RateLimiter], amount: usize) -> Option<Pin<Box<tokio::time::Sleep>>> {
+    let wait = take_all(limiters, amount);
+    (!wait.is_zero()).then(|| Box::pin(tokio::time::sleep(wait)))
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncWrite for RateLimitedWriter<'a, W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(poll_delay(&mut this.delay, cx));
+        let written = ready!(Pin::new(&mut this.writer).poll_write(cx, &buf[..min(buf.len(), MAX_CHUNK)]))?;
+        this.delay = rate_delay(this.limiters, written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(poll_delay(&mut this.delay, cx));
+        Pin::new(&mut this.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(poll_delay(&mut this.delay, cx));
+        Pin::new(&mut this.writer).poll_shutdown(cx)
+    }
+}
+
+
+/// Delays reads according to all of its [`RateLimiter`]s.
+pub struct RateLimitedReader<'a, R: AsyncRead + Unpin> {
+    reader: R,
+    limiters: &'a [RateLimiter],
+    /// For the Bytes already passed on
+    delay: Option<Pin<Box<tokio::time::Sleep>>>
+}
+
+impl<'a, R: AsyncRead + Unpin> RateLimitedReader<'a, R> {
+    pub fn new(reader: R, limiters: &'a [RateLimiter]) -> Self {
+        Self{ reader, limiters, delay: None }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<'a, R: AsyncRead + Unpin> AsyncRead for RateLimitedReader<'a, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(poll_delay(&mut this.delay, cx));
+        let amount = min(buf.remaining(), MAX_CHUNK);
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(amount));
+        ready!(Pin::new(&mut this.reader).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        this.delay = rate_delay(this.limiters, read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+pub struct FramedWriter<W: AsyncWrite + Unpin> {
+    writer: W,
+    /// The frame(s) not yet written
+    pending: Vec<u8>,
+    pending_written: usize,
+    last_frame: Instant,
+    ended: bool
+}
+
+impl<W: AsyncWrite + Unpin> FramedWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self{ writer, pending: Vec::with_capacity(1 << 13), pending_written: 0, last_frame: Instant::now(), ended: false }
+    }
+
+    /// Send a heartbeat, if no frame has been sent during the last `interval`.
+    pub async fn heartbeat_if_idle(&mut self, interval: Duration) -> io::Result<()> {
+        if self.last_frame.elapsed() >= interval {
+            self.pending.push(HEARTBEAT);
+            self.last_frame = Instant::now();
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Marks the end of the stream (same as shutting down)
+    pub async fn finish(mut self) -> io::Result<W> {
+        self.shutdown().await?;
+        Ok(self.writer)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for FramedWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(poll_drain(&mut this.writer, cx, &mut this.pending, &mut this.pending_written))?;
+        // an empty data frame would be pointless
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0))
+        }
+        let buf = &buf[..min(buf.len(), u32::MAX as usize)];
+
+        this.pending.push(DATA);
+        this.pending.extend((buf.len() as u32).to_be_bytes());
+        this.pending.extend(buf);
+        this.last_frame = Instant::now();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(poll_drain(&mut this.writer, cx, &mut this.pending, &mut this.pending_written))?;
+        Pin::new(&mut this.writer).poll_flush(cx)
+    }
+
+    /// Writes the end frame, the underlying stream is only flushed
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.ended {
+            ready!(poll_drain(&mut this.writer, cx, &mut this.pending, &mut this.pending_written))?;
+            this.pending.push(END);
+            this.ended = true;
+        }
+        ready!(poll_drain(&mut this.writer, cx, &mut this.pending, &mut this.pending_written))?;
+        Pin::new(&mut this.writer).poll_flush(cx)
+    }
+}
+
+
+pub struct FramedReader<R: AsyncBufRead + Unpin> {
+    reader: R,
+    /// of the current data frame
+    remaining: usize,
+    ended: bool,
+    /// Tag and length of the next frame, as far as read
+    header: [u8; 5],
+    header_read: usize
+}
+
+impl<R: AsyncBufRead + Unpin> FramedReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self{ reader, remaining: 0, ended: false, header: [0; 5], header_read: 0 }
+    }
+
+    /// Skips everything up to and including the end of the stream
+    pub async fn finish(mut self) -> io::Result<R> {
+        loop {
+            let available = self.fill_buf().await?.len();
+            if available == 0 {
+                return Ok(self.reader)
+            }
+            self.consume(available);
+        }
+    }
+
+    /// Reads into `header` until it holds `length` Bytes
+    fn poll_header(&mut self, cx: &mut Context<'_>, length: usize) -> Poll<io::Result<()>> {
+        while self.header_read < length {
+            let data = ready!(Pin::new(&mut self.reader).poll_fill_buf(cx))?;
+            if data.is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+            }
+            let amount = min(length - self.header_read, data.len());
+            self.header[self.header_read..self.header_read + amount].copy_from_slice(&data[..amount]);
+            self.header_read += amount;
+            Pin::new(&mut self.reader).consume(amount);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for FramedReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        poll_read_buffered(self.get_mut(), cx, buf)
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for FramedReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+
+        // get next data frame
+        while this.remaining == 0 && !this.ended {
+            ready!(this.poll_header(cx, 1))?;
+            match this.header[0] {
+                DATA => {
+                    ready!(this.poll_header(cx, 5))?;
+                    this.remaining = u32::from_be_bytes(this.header[1..5].try_into().unwrap()) as usize;
+                },
+                HEARTBEAT => (),
+                END => this.ended = true,
+                tag => return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, SmdError::ProtocolViolation(format!("Invalid frame tag {tag}")))))
+            }
+            this.header_read = 0;
+        }
+        if this.ended {
+            return Poll::Ready(Ok(&[]))
+        }
+
+        let remaining = this.remaining;
+        let data = ready!(Pin::new(&mut this.reader).poll_fill_buf(cx))?;
+        if data.is_empty() {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+        }
+        Poll::Ready(Ok(&data[..min(data.len(), remaining)]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        Pin::new(&mut this.reader).consume(amt);
+        this.remaining -= amt;
+    }
+}
+
+
+pub enum PerhapsEncrWriter<'a, W: AsyncWrite + Unpin> {
+    Plain(W),
+    Encrypted{
+        /// The encrypted block not yet written
+        pending: Vec<u8>,
+        pending_written: usize,
+        encryptor: &'a mut ChaCha20Poly1305,
+        writer: W
+    }
+}
+
+impl<'a, W: AsyncWrite + Unpin> PerhapsEncrWriter<'a, W> {
+    pub fn with_encryptor(writer: W, encryptor: &'a mut Option<ChaCha20Poly1305>) -> Self {
+        if let Some(encryptor) = encryptor {
+            Self::Encrypted{ pending: Vec::with_capacity(1 << 13), pending_written: 0, encryptor, writer }
+        }
+        else {
+            Self::Plain(writer)
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        match self {
+            Self::Plain(w) => w,
+            Self::Encrypted{ writer, .. } => writer
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::Plain(w) => w,
+            Self::Encrypted{ writer, .. } => writer
+        }
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self {
+            Self::Plain(_) => Poll::Ready(Ok(())),
+            Self::Encrypted{ pending, pending_written, writer, .. } => poll_drain(writer, cx, pending, pending_written)
+        }
+    }
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncWrite for PerhapsEncrWriter<'a, W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        match this {
+            Self::Plain(w) => Pin::new(w).poll_write(cx, buf),
+            Self::Encrypted{ pending, encryptor, .. } => {
+                let buf = &buf[..buf.len().min(MAX_BLOCK_SIZE)];
+                // nonce, total length of data (including tag), encrypted data
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                pending.extend(nonce.as_slice());
+                pending.extend(((buf.len() + TAG_SIZE) as u32).to_be_bytes());
+                let mut block = buf.to_vec();
+                encryptor.encrypt_in_place(&nonce, b"", &mut block).unwrap();
+                pending.extend(block);
+
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(this.get_mut()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(this.get_mut()).poll_shutdown(cx)
+    }
+}
+
+
+pub enum PerhapsEncrReader<'a, R: AsyncBufRead + Unpin> {
+    Plain(R),
+    Encrypted{
+        /// Decrypted data
+        buffer: Vec<u8>,
+        already_read: usize,
+        /// The encrypted block, as far as received
+        incoming: Vec<u8>,
+        decryptor: &'a mut ChaCha20Poly1305,
+        reader: R
+    }
+}
+
+impl<'a, R: AsyncBufRead + Unpin> PerhapsEncrReader<'a, R> {
+    pub fn with_decryptor(reader: R, decryptor: &'a mut Option<ChaCha20Poly1305>) -> Self {
+        if let Some(decryptor) = decryptor {
+            Self::Encrypted{ buffer: Vec::with_capacity(1 << 13), already_read: 0, incoming: Vec::with_capacity(1 << 13), decryptor, reader }
+        }
+        else {
+            Self::Plain(reader)
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        match self {
+            Self::Plain(r) => r,
+            Self::Encrypted{ reader, .. } => reader
+        }
+    }
+}
+
+/// Reads from `reader` into `incoming`, until it holds `length` Bytes
+fn poll_incoming<R: AsyncBufRead + Unpin>(reader: &mut R, cx: &mut Context<'_>, incoming: &mut Vec<u8>, length: usize) -> Poll<io::Result<()>> {
+    while incoming.len() < length {
+        let data = ready!(Pin::new(&mut *reader).poll_fill_buf(cx))?;
+        if data.is_empty() {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+        }
+        let amount = min(length - incoming.len(), data.len());
+        incoming.extend(&data[..amount]);
+        Pin::new(&mut *reader).consume(amount);
+    }
+    Poll::Ready(Ok(()))
+}
+
+impl<'a, R: AsyncBufRead + Unpin> AsyncRead for PerhapsEncrReader<'a, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        poll_read_buffered(self.get_mut(), cx, buf)
+    }
+}
+
+impl<'a, R: AsyncBufRead + Unpin> AsyncBufRead for PerhapsEncrReader<'a, R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        match self.get_mut() {
+            Self::Plain(r) => Pin::new(r).poll_fill_buf(cx),
+            Self::Encrypted{ buffer, already_read, incoming, decryptor, reader } => {
+                // if there are some residuals...
+                if *already_read < buffer.len() {
+                    return Poll::Ready(Ok(&buffer[*already_read..]))
+                }
+
+                // the stream may only end between blocks
+                if incoming.is_empty() && ready!(Pin::new(&mut *reader).poll_fill_buf(cx))?.is_empty() {
+                    return Poll::Ready(Ok(&[]))
+                }
+
+                // get nonce and length of encrypted block, then the block itself
+                ready!(poll_incoming(reader, cx, incoming, 16))?;
+                let length = block_length(incoming[12..16].try_into().unwrap())?;
+                ready!(poll_incoming(reader, cx, incoming, 16 + length))?;
+
+                // decrypt into buffer
+                let nonce = Nonce::clone_from_slice(&incoming[..12]);
+                buffer.clear();
+                buffer.extend(&incoming[16..]);
+                incoming.clear();
+                if decryptor.decrypt_in_place(&nonce, b"", buffer).is_err() {
+                    return Poll::Ready(Err(corrupted_block()))
+                }
+                *already_read = 0;
+
+                Poll::Ready(Ok(&buffer[..]))
+            }
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        match self.get_mut() {
+            Self::Plain(r) => Pin::new(r).consume(amt),
+            Self::Encrypted{ already_read, .. } => *already_read += amt
+        }
+    }
+}
+
+
+/// The blocking compressor, compressing into a buffer (boxed, as it is large)
+type BlockingEncoder = Box<super::PerhapsCompressedWriter<'static, Vec<u8>>>;
+/// Compressing a chunk (or finishing the stream) on a blocking thread,
+/// giving back the compressor (unless finished) and what it produced
+type Compressing = tokio::task::JoinHandle<(Option<BlockingEncoder>, io::Result<Vec<u8>>)>;
+
+/// Most Bytes compressed at once
+const COMPRESS_CHUNK: usize = 1 << 17;
+
+/// Compresses with the blocking [`PerhapsCompressedWriter`](super::PerhapsCompressedWriter) on [`tokio`]'s blocking threads,
+/// so that high levels do not hold up the runtime (and the heartbeats of other clients).
+pub enum PerhapsCompressedWriter<W: AsyncWrite + Unpin> {
+    Compressed{
+        /// None while compressing, once finished, or after failing
+        encoder: Option<BlockingEncoder>,
+        compressing: Option<Compressing>,
+        /// Compressed, but not written yet
+        pending: Vec<u8>,
+        pending_written: usize,
+        /// Whether the compressor has nothing buffered since the last flush
+        flushed: bool,
+        finished: bool,
+        writer: W
+    },
+    UnCompressed(W)
+}
+
+impl<W: AsyncWrite + Unpin> PerhapsCompressedWriter<W> {
+    /// Compresses with `codec` (if any) at `level` (as far as it has levels).
+    /// zstd is compressed with a `dictionary` (if any) and `params`.
+    pub fn with_params(writer: W, codec: Option<CompressionAlgorithm>, level: u8, dictionary: Option<&[u8]>, params: &ZstdParams) -> io::Result<Self> {
+        if codec.is_none() {
+            return Ok(Self::UnCompressed(writer))
+        }
+        let encoder = Box::new(super::PerhapsCompressedWriter::with_params(Vec::new(), codec, level, dictionary, params)?);
+        Ok(Self::Compressed{ encoder: Some(encoder), compressing: None, pending: Vec::new(), pending_written: 0, flushed: true, finished: false, writer })
+    }
+
+    /// Only valid after shutting down (which finishes the compressed frame)
+    pub fn into_inner(self) -> W {
+        match self {
+            Self::Compressed{ writer, .. } => writer,
+            Self::UnCompressed(w) => w
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::Compressed{ writer, .. } => writer,
+            Self::UnCompressed(w) => w
+        }
+    }
+
+    /// Waits for the chunk being compressed (if any), then writes out what has been compressed
+    fn poll_compressed(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Self::Compressed{ encoder, compressing, pending, pending_written, writer, .. } = self else {
+            return Poll::Ready(Ok(()))
+        };
+        if let Some(job) = compressing {
+            let joined = ready!(Pin::new(job).poll(cx));
+            *compressing = None;
+            let (returned, compressed) = joined.map_err(io::Error::other)?;
+            *encoder = returned;
+            *pending = compressed?;
+            *pending_written = 0;
+        }
+        poll_drain(writer, cx, pending, pending_written)
+    }
+
+    /// Runs `job` on the compressor, on a blocking thread
+    fn spawn<F: FnOnce(&mut BlockingEncoder) -> io::Result<()> + Send + 'static>(&mut self, job: F) -> io::Result<()> {
+        let Self::Compressed{ encoder, compressing, .. } = self else { unreachable!("Only compressing writers spawn") };
+        let mut encoder = encoder.take().ok_or_else(|| io::Error::other("The compressor has failed before"))?;
+        *compressing = Some(tokio::task::spawn_blocking(move || {
+            let compressed = job(&mut encoder).map(|()| std::mem::take(encoder.get_mut()));
+            (Some(encoder), compressed)
+        }));
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for PerhapsCompressedWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_compressed(cx))?;
+        match this {
+            Self::UnCompressed(writer) => Pin::new(writer).poll_write(cx, buf),
+            Self::Compressed{ finished: true, .. } => Poll::Ready(Err(io::Error::other("Writing after the compressed stream has been finished"))),
+            Self::Compressed{ flushed, .. } => {
+                *flushed = false;
+                // (taken over right away, compressed in the background)
+                let chunk = buf[..buf.len().min(COMPRESS_CHUNK)].to_vec();
+                let length = chunk.len();
+                this.spawn(move |encoder| io::Write::write_all(encoder, &chunk))?;
+                Poll::Ready(Ok(length))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_compressed(cx))?;
+            match this {
+                Self::UnCompressed(writer) => return Pin::new(writer).poll_flush(cx),
+                Self::Compressed{ flushed: true, writer, .. } | Self::Compressed{ finished: true, writer, .. } => return Pin::new(writer).poll_flush(cx),
+                Self::Compressed{ flushed, .. } => {
+                    *flushed = true;
+                    this.spawn(io::Write::flush)?;
+                }
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_compressed(cx))?;
+            match this {
+                Self::UnCompressed(writer) => return Pin::new(writer).poll_shutdown(cx),
+                Self::Compressed{ finished: true, writer, .. } => return Pin::new(writer).poll_shutdown(cx),
+                Self::Compressed{ encoder, compressing, finished, .. } => {
+                    let encoder = encoder.take().ok_or_else(|| io::Error::other("The compressor has failed before"))?;
+                    *compressing = Some(tokio::task::spawn_blocking(move || (None, (*encoder).finish())));
+                    *finished = true;
+                }
+            }
+        }
+    }
+}
+
+
+pub enum PerhapsCompressedReader<R: AsyncBufRead + Unpin> {
+    Zstd(ZstdDecoder<R>),
+    Lz4(Lz4Decoder<R>),
+    Brotli(Box<BrotliDecoder<R>>),
+    UnCompressed(R)
+}
+
+impl<R: AsyncBufRead + Unpin> PerhapsCompressedReader<R> {
+    /// Decompresses `codec` (if any).
+    /// zstd is decompressed with the Hoster's `dictionary` (if any)
+    /// or accepting windows of up to 2^`window_log` Bytes (if some), Hosters never use both.
+    pub fn with_params(reader: R, codec: Option<CompressionAlgorithm>, dictionary: Option<&[u8]>, window_log: Option<u32>) -> io::Result<Self> {
+        Ok(match (codec, dictionary, window_log) {
+            (None, ..) => Self::UnCompressed(reader),
+            (Some(CompressionAlgorithm::Zstd), Some(dictionary), _) => Self::Zstd(ZstdDecoder::with_dict(reader, dictionary)?),
+            (Some(CompressionAlgorithm::Zstd), None, Some(window_log)) => Self::Zstd(ZstdDecoder::with_params(reader, &[DParameter::window_log_max(window_log)])),
+            (Some(CompressionAlgorithm::Zstd), None, None) => Self::Zstd(ZstdDecoder::new(reader)),
+            (Some(CompressionAlgorithm::Lz4), ..) => Self::Lz4(Lz4Decoder::new(reader)),
+            (Some(CompressionAlgorithm::Brotli), ..) => Self::Brotli(Box::new(BrotliDecoder::new(reader))),
+            (Some(CompressionAlgorithm::Unknown), ..) => return Err(unknown_codec())
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        match self {
+            Self::Zstd(decoder) => decoder.into_inner(),
+            Self::Lz4(decoder) => decoder.into_inner(),
+            Self::Brotli(decoder) => decoder.into_inner(),
+            Self::UnCompressed(r) => r
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for PerhapsCompressedReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Zstd(decoder) => Pin::new(decoder).poll_read(cx, buf),
+            Self::Lz4(decoder) => Pin::new(decoder).poll_read(cx, buf),
+            Self::Brotli(decoder) => Pin::new(decoder).poll_read(cx, buf),
+            Self::UnCompressed(reader) => Pin::new(reader).poll_read(cx, buf)
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct HashReader<R: AsyncRead + Unpin> {
+    reader: R,
+    hasher: Md5
+}
+
+impl<R: AsyncRead + Unpin> HashReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self{ reader, hasher: Md5::new() }
+    }
+
+    pub fn finalize(self) -> (R, u128) {
+        (self.reader, u128::from_be_bytes(
+            *self.hasher.finalize().split_first_chunk::<16>().unwrap().0)
+        )
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.reader).poll_read(cx, buf))?;
+        this.hasher.update(&buf.filled()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+#[derive(Debug)]
+pub enum PerhapsHashingWriter<W: AsyncWrite + Unpin> {
+    Hashing{ writer: W, hasher: Md5 },
+    Precomputed{ writer: W, hash: u128 }
+}
+
+impl<W: AsyncWrite + Unpin> PerhapsHashingWriter<W> {
+    pub fn with_hash(writer: W, hash: Option<u128>) -> Self {
+        match hash {
+            Some(hash) => Self::Precomputed{ writer, hash },
+            None => Self::Hashing{ writer, hasher: Md5::new() }
+        }
+    }
+
+    pub fn finalize(self) -> (W, u128) {
+        match self {
+            Self::Precomputed{ writer, hash } => (writer, hash),
+            Self::Hashing{ writer, hasher } => (writer, u128::from_be_bytes(
+                *hasher.finalize().split_first_chunk::<16>().unwrap().0)
+            )
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::Precomputed{ writer, .. } | Self::Hashing{ writer, .. } => writer
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for PerhapsHashingWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Precomputed{ writer, .. } => Pin::new(writer).poll_write(cx, buf),
+            Self::Hashing{ writer, hasher } => {
+                let written = ready!(Pin::new(writer).poll_write(cx, buf))?;
+                hasher.update(&buf[..written]);
+                Poll::Ready(Ok(written))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().get_mut()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().get_mut()).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{self, IoSlice, Write};
+
+
+/// Of [`VectoredBufWriter`], enough for a few frames' headers and small payloads
+pub const BUFFER_SIZE: usize = 1 << 16;
+
+
+/// Collects small writes (message headers, frame tags, hashes) into one syscall,
+/// and sends large ones together with what has been collected in one vectored write.
+///
+/// Nothing is sent before it is full or flushed, so it has to be flushed at the end of
+/// every message the other side answers (and before waiting for anything).
+pub struct VectoredBufWriter<W: Write> {
+    writer: W,
+    buffer: Vec<u8>
+}
+
+impl<W: Write> VectoredBufWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self::with_capacity(BUFFER_SIZE, writer)
+    }
+
+    pub fn with_capacity(capacity: usize, writer: W) -> Self {
+        Self{ writer, buffer: Vec::with_capacity(capacity) }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Writing to it directly skips what has been buffered, flush first.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Sends what has been buffered (without flushing the inner writer)
+    pub fn flush_buffer(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.buffer.len() {
+                break Ok(())
+            }
+            match self.writer.write(&self.buffer[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => break Err(e)
+            }
+        };
+        self.buffer.drain(..written);
+        result
+    }
+}
+
+impl<W: Write> Write for VectoredBufWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            if self.buffer.len() + buf.len() <= self.buffer.capacity() {
+                self.buffer.extend_from_slice(buf);
+                return Ok(buf.len())
+            }
+            if self.buffer.is_empty() {
+                return self.writer.write(buf)
+            }
+
+            // send both in one go
+            let written = self.writer.write_vectored(&[IoSlice::new(&self.buffer), IoSlice::new(buf)])?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into())
+            }
+            if written <= self.buffer.len() {
+                // (buf might fit now)
+                self.buffer.drain(..written);
+                continue
+            }
+            let from_buf = written - self.buffer.len();
+            self.buffer.clear();
+            return Ok(from_buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buffer()?;
+        self.writer.flush()
+    }
+}
+
+/// Sends what is left, like [`io::BufWriter`]
+impl<W: Write> Drop for VectoredBufWriter<W> {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            let _ = self.flush_buffer();
+        }
+    }
+}
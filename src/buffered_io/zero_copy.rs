@@ -0,0 +1,241 @@
+//! Moving file contents between files and sockets within the kernel (Linux only),
+//! for streams that are neither compressed nor encrypted.
+//!
+//! The Hoster `sendfile`s from the page cache into the socket,
+//! the client `splice`s from the socket through a pipe into the file.
+//! Both fall back to copying, where the kernel refuses (e.g. for some filesystems).
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, splice, FcntlArg, SpliceFFlags};
+use nix::sys::sendfile::sendfile;
+
+use std::cmp::min;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::time::Instant;
+
+use super::{CountingReader, CountingWriter, HashWriter, RateLimitedReader, RateLimitedWriter, VectoredBufWriter, rate_io::{take_all, MAX_CHUNK}};
+use crate::transport::Transport;
+
+
+/// Data frames files are sent in, and moved at once (at most)
+pub const FRAME_SIZE: usize = 1 << 20;
+/// Asked of the kernel (the default is 64 KiB)
+const PIPE_SIZE: usize = 1 << 20;
+/// Of the buffer used when falling back to copying
+const COPY_BUFFER_SIZE: usize = 1 << 16;
+
+
+/// Whether data can be moved to and from `transport` within the kernel
+pub fn supported(transport: &dyn Transport) -> bool {
+    transport.socket_fd().is_some()
+}
+
+/// Hashes a file range by range, reading each back from the page cache right after it has been moved
+pub struct FileHasher {
+    hasher: HashWriter<io::Sink>,
+    buffer: Vec<u8>
+}
+
+impl FileHasher {
+    pub fn new() -> Self {
+        Self{ hasher: HashWriter::new(io::sink()), buffer: vec![0; COPY_BUFFER_SIZE] }
+    }
+
+    /// Hashes `count` Bytes of `file` from `offset` on, which have to follow the ones hashed before
+    pub fn update(&mut self, file: &File, offset: u64, count: usize) -> io::Result<()> {
+        let mut hashed = 0;
+        while hashed < count {
+            let amount = min(count - hashed, self.buffer.len());
+            file.read_exact_at(&mut self.buffer[..amount], offset + hashed as u64)?;
+            self.hasher.write_all(&self.buffer[..amount])?;
+            hashed += amount;
+        }
+        Ok(())
+    }
+
+    pub fn finalize(self) -> u128 {
+        self.hasher.finalize().1
+    }
+}
+
+impl Default for FileHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Writers able to send (parts of) a file without copying them through userspace
+pub trait SendFile: Write {
+    /// Sends up to `count` Bytes of `file` from `offset` on (advancing it),
+    /// returning how many were sent (0 at the end of the file).
+    fn send_file(&mut self, file: &File, offset: &mut u64, count: usize) -> io::Result<usize>;
+}
+
+/// Readers able to move what they read into a file without copying it through userspace
+pub trait SpliceRead: Read {
+    /// Moves up to `count` Bytes into `file` (at its position) through `pipe`,
+    /// returning how many were moved (0 at the end of the stream).
+    fn splice_to(&mut self, pipe: &Pipe, file: &File, count: usize) -> io::Result<usize>;
+}
+
+
+/// What [`SpliceRead`] moves data through, as splicing needs a pipe on one end
+pub struct Pipe {
+    read: File,
+    write: File
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Self> {
+        let (read, write) = nix::unistd::pipe()?;
+        // fewer, larger moves (smaller pipes work too)
+        let _ = fcntl(write.as_raw_fd(), FcntlArg::F_SETPIPE_SZ(PIPE_SIZE as i32));
+        Ok(Self{ read: read.into(), write: write.into() })
+    }
+}
+
+
+impl SendFile for Box<dyn Transport> {
+    fn send_file(&mut self, file: &File, offset: &mut u64, count: usize) -> io::Result<usize> {
+        let mut position = *offset as i64;
+        let sent = self.socket_fd().map(|socket| retry(|| sendfile(socket, file, Some(&mut position), count)));
+        match sent {
+            Some(Ok(sent)) => {
+                *offset = position as u64;
+                Ok(sent)
+            },
+            None | Some(Err(Errno::EINVAL | Errno::ENOSYS)) => copy_from_file(self, file, offset, count),
+            Some(Err(e)) => Err(e.into())
+        }
+    }
+}
+
+impl SpliceRead for Box<dyn Transport> {
+    fn splice_to(&mut self, pipe: &Pipe, file: &File, count: usize) -> io::Result<usize> {
+        let moved = self.socket_fd().map(|socket| retry(|| splice(socket, None, &pipe.write, None, min(count, PIPE_SIZE), SpliceFFlags::SPLICE_F_MOVE)));
+        let moved = match moved {
+            Some(Ok(moved)) => moved,
+            None | Some(Err(Errno::EINVAL | Errno::ENOSYS)) => return copy_into_file(self, file, count),
+            Some(Err(e)) => return Err(e.into())
+        };
+
+        // empty the pipe into the file
+        let mut remaining = moved;
+        while remaining > 0 {
+            match retry(|| splice(&pipe.read, None, file, None, remaining, SpliceFFlags::SPLICE_F_MOVE)) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(spliced) => remaining -= spliced,
+                // (the filesystem does not support splicing)
+                Err(Errno::EINVAL | Errno::ENOSYS) => {
+                    io::copy(&mut (&pipe.read).take(remaining as u64), &mut &*file)?;
+                    remaining = 0;
+                },
+                Err(e) => return Err(e.into())
+            }
+        }
+        Ok(moved)
+    }
+}
+
+/// Retries calls interrupted by signals
+fn retry(mut call: impl FnMut() -> nix::Result<usize>) -> nix::Result<usize> {
+    loop {
+        match call() {
+            Err(Errno::EINTR) => (),
+            result => return result
+        }
+    }
+}
+
+fn copy_from_file(writer: &mut impl Write, file: &File, offset: &mut u64, count: usize) -> io::Result<usize> {
+    let mut buffer = vec![0u8; min(count, COPY_BUFFER_SIZE)];
+    let read = file.read_at(&mut buffer, *offset)?;
+    writer.write_all(&buffer[..read])?;
+    *offset += read as u64;
+    Ok(read)
+}
+
+fn copy_into_file(reader: &mut impl Read, mut file: &File, count: usize) -> io::Result<usize> {
+    let mut buffer = vec![0u8; min(count, COPY_BUFFER_SIZE)];
+    let read = reader.read(&mut buffer)?;
+    file.write_all(&buffer[..read])?;
+    Ok(read)
+}
+
+
+impl<W: SendFile + ?Sized> SendFile for &mut W {
+    fn send_file(&mut self, file: &File, offset: &mut u64, count: usize) -> io::Result<usize> {
+        (**self).send_file(file, offset, count)
+    }
+}
+
+impl<R: SpliceRead + ?Sized> SpliceRead for &mut R {
+    fn splice_to(&mut self, pipe: &Pipe, file: &File, count: usize) -> io::Result<usize> {
+        (**self).splice_to(pipe, file, count)
+    }
+}
+
+impl<W: SendFile> SendFile for CountingWriter<W> {
+    fn send_file(&mut self, file: &File, offset: &mut u64, count: usize) -> io::Result<usize> {
+        let start = Instant::now();
+        let sent = self.get_mut().send_file(file, offset, count);
+        self.busy += start.elapsed();
+        if let Ok(sent) = sent {
+            self.written += sent
+        }
+        sent
+    }
+}
+
+impl<R: SpliceRead> SpliceRead for CountingReader<R> {
+    fn splice_to(&mut self, pipe: &Pipe, file: &File, count: usize) -> io::Result<usize> {
+        let moved = self.get_mut().splice_to(pipe, file, count)?;
+        self.read += moved;
+        Ok(moved)
+    }
+}
+
+/// Sends what has been buffered first
+impl<W: SendFile> SendFile for VectoredBufWriter<W> {
+    fn send_file(&mut self, file: &File, offset: &mut u64, count: usize) -> io::Result<usize> {
+        self.flush_buffer()?;
+        self.get_mut().send_file(file, offset, count)
+    }
+}
+
+/// Passes on small chunks (like writes) only while limited
+impl<'a, W: SendFile> SendFile for RateLimitedWriter<'a, W> {
+    fn send_file(&mut self, file: &File, offset: &mut u64, count: usize) -> io::Result<usize> {
+        let limited = self.limiters().iter().any(|limiter| limiter.rate().is_some());
+        let sent = self.get_mut().send_file(file, offset, if limited { min(count, MAX_CHUNK) } else { count })?;
+        std::thread::sleep(take_all(self.limiters(), sent));
+        Ok(sent)
+    }
+}
+
+/// Passes on small chunks (like reads) only while limited
+impl<'a, R: SpliceRead> SpliceRead for RateLimitedReader<'a, R> {
+    fn splice_to(&mut self, pipe: &Pipe, file: &File, count: usize) -> io::Result<usize> {
+        let limited = self.limiters().iter().any(|limiter| limiter.rate().is_some());
+        let moved = self.get_mut().splice_to(pipe, file, if limited { min(count, MAX_CHUNK) } else { count })?;
+        std::thread::sleep(take_all(self.limiters(), moved));
+        Ok(moved)
+    }
+}
+
+/// Writes out what has already been buffered first
+impl<R: SpliceRead> SpliceRead for BufReader<R> {
+    fn splice_to(&mut self, pipe: &Pipe, mut file: &File, count: usize) -> io::Result<usize> {
+        let buffered = self.buffer();
+        if buffered.is_empty() {
+            return self.get_mut().splice_to(pipe, file, count)
+        }
+        let amount = min(count, buffered.len());
+        file.write_all(&buffered[..amount])?;
+        self.consume(amount);
+        Ok(amount)
+    }
+}
@@ -1,5 +1,9 @@
 use clap::Parser;
 
+use crate::{bench, client, CompressionAlgorithm};
+use crate::server::{BacklogPolicy, CompressionLevel};
+use crate::transport::SocketOptions;
+
 /// Simple-Mass-Data-Transfer is a capable but simple File Transfer utility.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = "https://github.com/Vescusia/simple_mass_data_transfer/blob/master/README.md")]
@@ -18,6 +22,37 @@ pub struct Args {
     /// Should the traffic be compressed? (zstd)
     #[arg(short, long, default_value_t = false)]
     pub compression: bool,
+
+    /// The size of the socket's send buffer (e.g. "4 MB"), instead of the OS default.
+    ///
+    /// Large buffers help fast connections with a high latency (about bandwidth times round trip time).
+    #[arg(long)]
+    pub send_buffer: Option<bytesize::ByteSize>,
+
+    /// The size of the socket's receive buffer (e.g. "4 MB"), instead of the OS default.
+    #[arg(long)]
+    pub recv_buffer: Option<bytesize::ByteSize>,
+
+    /// Send small messages right away, instead of waiting to fill a packet (TCP_NODELAY).
+    #[arg(long, default_value_t = false)]
+    pub nodelay: bool,
+
+    /// The TCP congestion control algorithm, like "bbr" (Linux only).
+    ///
+    /// See /proc/sys/net/ipv4/tcp_available_congestion_control for the ones available.
+    #[arg(long)]
+    pub congestion: Option<String>,
+}
+
+impl Args {
+    pub fn socket_options(&self) -> SocketOptions {
+        SocketOptions{
+            send_buffer: self.send_buffer.map(|size| size.as_u64() as usize),
+            recv_buffer: self.recv_buffer.map(|size| size.as_u64() as usize),
+            nodelay: self.nodelay,
+            congestion: self.congestion.clone()
+        }
+    }
 } 
 
 #[derive(clap::Subcommand, Debug)]
@@ -31,17 +66,86 @@ pub enum Action {
         /// The Files and Directories to host
         /// 
         /// Also supports wildcards, like '*'!
-        #[arg()]
-        path: String,
+        #[arg(required = true)]
+        paths: Vec<String>,
 
-        /// The level of zstd compression that should be used.
-        /// Should be between 1 and 22. 
+        /// The level of compression that should be used.
+        /// Should be between 1 and 21 for zstd (brotli: 1 to 11, lz4 has no levels), or "auto".
         /// 
         /// Compression speed generally goes from ~300 MB/s at level 1 to ~2.5 MB/s at level 22.
         /// You can choose a compression level that decently matches **twice** your upload speed
         /// or use the default for ~15 MB/s (~twice my upload speed :D).
-        #[arg(short('l'), long, default_value_t = 15, value_parser = clap::value_parser!(u8).range(1..22))]
-        comp_level: u8
+        ///
+        /// With "auto", the level is adjusted between files to each client's measured connection,
+        /// keeping it busy without the compression holding it back.
+        #[arg(short('l'), long, default_value_t = CompressionLevel::Fixed(15))]
+        comp_level: CompressionLevel,
+
+        /// The algorithm to compress with. Clients not supporting it get zstd.
+        ///
+        /// lz4 is the fastest by far (for quick networks), brotli compresses text best (for slow ones).
+        /// Dictionaries and the zstd options below only apply to zstd.
+        #[arg(long, value_enum, default_value_t = CompressionAlgorithm::Zstd)]
+        codec: CompressionAlgorithm,
+
+        /// A zstd dictionary to compress with (e.g. from 'zstd --train').
+        ///
+        /// Helps a lot with many similar small files, like JSON, logs or source code.
+        #[arg(long, conflicts_with = "train_dictionary")]
+        dictionary: Option<std::path::PathBuf>,
+
+        /// Train a zstd dictionary from a sample of the hosted files at startup.
+        ///
+        /// It is cached in the user's cache directory (e.g. ~/.cache/smd_transfer), until the sampled files change.
+        #[arg(long)]
+        train_dictionary: bool,
+
+        /// Threads compressing for each client, besides the one sending.
+        ///
+        /// Speeds up high levels a lot, but needs a build with the "zstdmt" feature.
+        #[arg(long, default_value_t = 0)]
+        comp_workers: u32,
+
+        /// Find repetitions up to 2^LOG Bytes apart (long-distance matching), 27 if no LOG is given.
+        ///
+        /// Helps with huge files, like disk images or backups, but every client needs about 3 times that much memory.
+        #[arg(long, value_name = "LOG", num_args = 0..=1, default_missing_value = "27",
+            value_parser = clap::value_parser!(u32).range(10..=31), conflicts_with_all = ["dictionary", "train_dictionary"])]
+        long_window: Option<u32>,
+
+        /// Seconds after which a silent client is disconnected.
+        #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+        idle_timeout: u64,
+
+        /// The maximum amount of clients served at once.
+        ///
+        /// Without it, every client is served right away (on its own thread).
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        max_clients: Option<u64>,
+
+        /// What to do with clients arriving while the maximum of clients is being served.
+        #[arg(long, value_enum, default_value_t = BacklogPolicy::Queue)]
+        backlog: BacklogPolicy,
+
+        /// The memory all clients may use for compression and buffers (e.g. "1 GB").
+        ///
+        /// Clients arriving once it is used up are turned away.
+        #[arg(long)]
+        memory_budget: Option<bytesize::ByteSize>,
+
+        /// The maximum rate sent to each client, per second (e.g. "2 MB").
+        #[arg(long)]
+        max_rate: Option<bytesize::ByteSize>,
+
+        /// The maximum rate sent to all clients together, per second (e.g. "10 MB").
+        #[arg(long)]
+        total_rate: Option<bytesize::ByteSize>,
+
+        /// Read, hash, compress, encrypt and send files on threads of their own.
+        ///
+        /// Helps when the CPU holds a transfer back, e.g. with compression and encryption on a fast network.
+        #[arg(long, default_value_t = false)]
+        pipeline: bool,
     },
     
     /// Download from a Hoster
@@ -54,5 +158,81 @@ pub enum Action {
         /// The Directory the Files will be downloaded to
         #[arg(short, long, default_value = "./")] 
         path: String,
+
+        /// The maximum total size the Hoster may send (e.g. "20 GB").
+        ///
+        /// Without it, the download is only limited by the free space at the destination.
+        #[arg(long)]
+        max_size: Option<bytesize::ByteSize>,
+
+        /// The maximum amount of files the Hoster may send.
+        #[arg(long)]
+        max_files: Option<u64>,
+
+        /// Reserve the disk space of every file before downloading it (fallocate).
+        #[arg(long, default_value_t = false)]
+        preallocate: bool,
+
+        /// The largest window (2^LOG Bytes) the Hoster may compress with, as decompressing needs about as much memory.
+        ///
+        /// Hosters compressing with a larger window (--long-window) use this one instead.
+        #[arg(long, value_name = "LOG", default_value_t = client::DEFAULT_MAX_WINDOW_LOG, value_parser = clap::value_parser!(u32).range(10..=31))]
+        max_window_log: u32,
+
+        /// How often to reconnect after losing the connection.
+        #[arg(long, default_value_t = 5)]
+        retries: u32,
+
+        /// Seconds to wait before the first reconnect.
+        /// 
+        /// The delay doubles with every further attempt (up to a minute).
+        #[arg(long, default_value_t = 1.)]
+        retry_delay: f64,
+
+        /// Seconds after which a silent Hoster is considered gone (0 for never).
+        /// 
+        /// The Hoster sends heartbeats while it is busy (e.g. compressing).
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+
+        /// The maximum rate to download with, per second (e.g. "2 MB").
+        #[arg(long)]
+        limit_rate: Option<bytesize::ByteSize>,
+
+        /// Receive, decrypt, decompress, hash and write files on threads of their own.
+        ///
+        /// Helps when the CPU holds a transfer back, e.g. with compression and encryption on a fast network.
+        #[arg(long, default_value_t = false)]
+        pipeline: bool,
+    },
+
+    /// Measure hashing, compression, encryption and transfers on this machine, recommending the Hoster's settings
+    Bench {
+        /// Files (or a directory) like the ones to be hosted, to measure on.
+        ///
+        /// Without it, generated log lines with some random data in between are measured on.
+        #[arg()]
+        path: Option<std::path::PathBuf>,
+
+        /// The most data to measure on (e.g. "100 MB").
+        #[arg(long, default_value_t = bytesize::ByteSize(bench::DEFAULT_SIZE))]
+        size: bytesize::ByteSize,
+
+        /// The algorithm to compress with.
+        #[arg(long, value_enum, default_value_t = CompressionAlgorithm::Zstd)]
+        codec: CompressionAlgorithm,
+
+        /// The compression levels to measure (comma separated).
+        #[arg(short('l'), long, value_delimiter = ',', default_values_t = bench::DEFAULT_LEVELS.to_vec(),
+            value_parser = clap::value_parser!(u8).range(1..=21))]
+        levels: Vec<u8>,
+
+        /// The speeds of the links to recommend for, per second (comma separated, e.g. "5 MB,50 MB").
+        #[arg(long, value_delimiter = ',', default_values_t = bench::DEFAULT_LINKS.iter().map(|&link| bytesize::ByteSize(link)))]
+        links: Vec<bytesize::ByteSize>,
+
+        /// Skip the transfers over loopback.
+        #[arg(long, default_value_t = false)]
+        no_loopback: bool,
     }
 }
\ No newline at end of file
@@ -2,49 +2,344 @@ use std::io::{BufRead, Read, Write};
 use rmp_serde::{Serializer, Deserializer};
 use serde::{Deserialize, Serialize};
 use chacha20poly1305::KeyInit;
+use bytesize::ByteSize;
 
-use std::net;
 use std::io;
+use std::collections::HashMap;
 use std::sync::mpsc::Sender;
+use std::time::Duration;
+use std::path::{Path, PathBuf};
 
-use crate::cli;
-use simple_mass_data_transfer::{Handshake, HandshakeResponse, FileHash, EntryHeader::{FileHeader, DirHeader}, EntryHeader, FileHashResponse};
-use simple_mass_data_transfer::buffered_io::{HashReader, PerhapsCompressedReader, PerhapsEncrReader, encrypt_io::{prepare_key, maybe_decrypt_path}};
-use simple_mass_data_transfer::client_events::{ClientEvent, ClientEventReader};
+use crate::{Capabilities, CompressionAlgorithm, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, MAX_HASH_MISMATCHES, SmdError};
+use crate::{Handshake, HandshakeResponse, FileHash, EntryHeader::{FileHeader, DirHeader, SolidHeader}, EntryHeader, FileHashResponse};
+use crate::buffered_io::{HashReader, PerhapsCompressedReader, PerhapsEncrReader, CountingReader, FramedReader, RateLimiter, RateLimitedReader, VectoredBufWriter, encrypt_io::{prepare_key, maybe_decrypt_path, create_key_check}};
+use crate::client_events::{ClientEvent, ClientEventReader};
+use crate::control::{ControlledReader, TransferControl};
+use crate::disk;
+#[cfg(target_os = "linux")]
+use crate::buffered_io::zero_copy::{self, Pipe, SpliceRead};
+use crate::transport::{Connector, SocketOptions, Transport};
 
+mod pipeline;
+#[cfg(feature = "async")]
+mod tokio_client;
 
-pub fn connect(args: cli::Args, handler: Sender<ClientEvent>) -> anyhow::Result<()> {
-    // get relative path
-    let rel_path = if let cli::Action::Download { path, .. } = &args.action {
-        let p = std::path::PathBuf::from(path);
 
+/// Log2 of the largest zstd window accepted by default (256 MiB)
+pub const DEFAULT_MAX_WINDOW_LOG: u32 = 28;
+
+/// User-set caps on what a Hoster is allowed to send.
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadLimits {
+    /// in Bytes
+    pub max_size: Option<u64>,
+    pub max_files: Option<u64>,
+    /// Reserve the disk space of each file before writing it
+    pub preallocate: bool,
+    /// Log2 of the largest window the Hoster may compress with, as decompressing needs about that many Bytes
+    pub max_window_log: u32,
+}
+
+impl Default for DownloadLimits {
+    fn default() -> Self {
+        Self{ max_size: None, max_files: None, preallocate: false, max_window_log: DEFAULT_MAX_WINDOW_LOG }
+    }
+}
+
+impl DownloadLimits {
+    /// Checks the window the Hoster compresses with, if it told
+    fn check_window_log(&self, window_log: Option<u32>) -> Result<(), SmdError> {
+        match window_log {
+            Some(log) if log > self.max_window_log => Err(SmdError::ProtocolViolation(
+                format!("Hoster compresses with a window of 2^{log} Bytes, above the set maximum of 2^{}", self.max_window_log))),
+            _ => Ok(())
+        }
+    }
+
+    /// Checks the Hoster's advertised `total_size` against the limits and the free space in `dir`
+    fn check_total_size(&self, total_size: u64, resuming: bool, dir: &Path) -> anyhow::Result<()> {
+        if let Some(max_size) = self.max_size {
+            if total_size > max_size {
+                anyhow::bail!("Hoster advertises {} in total, exceeding the set maximum of {}!", ByteSize(total_size), ByteSize(max_size))
+            }
+        }
+        // (when resuming, some of the files are already on disk, so only check per file)
+        if !resuming {
+            if let Some(available) = disk::available_space(dir)? {
+                if total_size > available {
+                    anyhow::bail!("Not enough free space in {dir:?}: {} needed, but only {} available!", ByteSize(total_size), ByteSize(available))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the next file (at `path` relative to `dir`) against the limits and the free space,
+    /// given what has been written so far, and counts it as written
+    fn check_file(&self, written: &mut Written, size: u64, dir: &Path, path: &Path) -> anyhow::Result<()> {
+        // a resent file replaces the one written before
+        let replaced = written.files.get(path).copied();
+        if let Some(max_files) = self.max_files {
+            if replaced.is_none() && written.files.len() as u64 >= max_files {
+                anyhow::bail!("Hoster sent more than the set maximum of {max_files} files!")
+            }
+        }
+        let bytes = (written.bytes - replaced.unwrap_or(0)).saturating_add(size);
+        if let Some(max_size) = self.max_size {
+            if bytes > max_size {
+                anyhow::bail!("Hoster sent more than the set maximum of {} (at {path:?})!", ByteSize(max_size))
+            }
+        }
+        if let Some(available) = disk::available_space(dir)? {
+            // an already existing file will be overwritten
+            let reclaimable = dir.join(path).metadata().map_or(0, |m| m.len());
+            if size > available.saturating_add(reclaimable) {
+                anyhow::bail!("Not enough free space for {path:?}: {} needed, but only {} available!", ByteSize(size), ByteSize(available))
+            }
+        }
+        written.files.insert(path.to_owned(), size);
+        written.bytes = bytes;
+        Ok(())
+    }
+}
+
+
+/// What has been written to disk over all connections of a download,
+/// whether its hashes matched or not (the [`DownloadLimits`] apply to all of it)
+#[derive(Debug, Default)]
+struct Written {
+    /// The size of each file
+    files: HashMap<PathBuf, u64>,
+    /// in Bytes
+    bytes: u64
+}
+
+
+/// How to reconnect after losing the connection to the Hoster.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Reconnection attempts (after the first connection)
+    pub max_attempts: u32,
+    /// Doubled with every further attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// The time to wait before `attempt` (starting at 1)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self{ max_attempts: 5, initial_backoff: Duration::from_secs(1), max_backoff: Duration::from_secs(60) }
+    }
+}
+
+
+/// Downloads everything a Hoster offers into a directory.
+///
+/// ```no_run
+/// use simple_mass_data_transfer::client::Client;
+///
+/// let (send, recv) = std::sync::mpsc::channel();
+/// let client = Client::new("my-friends-domain.com:4444", "/path/to/install/folder/")
+///     .with_encryption_key(Some("my_cool_passkey".into()));
+/// // client.control() pauses, resumes or cancels it from another thread
+/// client.download(send).unwrap();
+/// ```
+pub struct Client {
+    connector: Box<dyn Connector>,
+    /// Only known if not created with a custom connector (needed for async downloads)
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    address: Option<String>,
+    path: PathBuf,
+    compression: bool,
+    encryption_key: Option<String>,
+    limits: DownloadLimits,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    rate_limit: RateLimiter,
+    socket_options: SocketOptions,
+    /// Whether files are received, decrypted, decompressed, hashed and written on threads of their own
+    pipeline: bool,
+    control: TransferControl,
+}
+
+
+impl Client {
+    /// Download from `address` (a socket address or `unix:/path/to/socket`)
+    /// into the directory `path` (created if needed)
+    pub fn new<S: Into<String>, P: Into<PathBuf>>(address: S, path: P) -> Self {
+        let address = address.into();
+        Self{ address: Some(address.clone()), ..Self::with_connector(address, path) }
+    }
+
+    /// Download over the [`Transport`](crate::transport::Transport)s opened by `connector`
+    /// into the directory `path` (created if needed)
+    pub fn with_connector<C: Connector + 'static, P: Into<PathBuf>>(connector: C, path: P) -> Self {
+        Self{
+            connector: Box::new(connector),
+            address: None,
+            path: path.into(),
+            compression: false,
+            encryption_key: None,
+            limits: DownloadLimits::default(),
+            retry: RetryPolicy::default(),
+            timeout: Some(Duration::from_secs(30)),
+            rate_limit: RateLimiter::default(),
+            socket_options: SocketOptions::default(),
+            pipeline: false,
+            control: TransferControl::new(),
+        }
+    }
+
+    /// Request compression (the Hoster might force it anyway)
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Has to match the Hoster's key
+    pub fn with_encryption_key(mut self, key: Option<String>) -> Self {
+        self.encryption_key = key;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: DownloadLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// After which a silent Hoster is considered gone (`None` for never)
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Bytes per second to receive at most (None for unlimited)
+    pub fn with_rate_limit(self, rate: Option<u64>) -> Self {
+        self.rate_limit.set_rate(rate);
+        self
+    }
+
+    /// Socket buffer sizes, TCP_NODELAY and the congestion control algorithm of every connection
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Receive, decrypt, decompress, hash and write each file on threads of their own (only [`Client::download`]).
+    /// Speeds up downloads limited by the CPU, at the cost of about 1.5 MB and up to 4 more threads.
+    pub fn with_pipeline(mut self, pipeline: bool) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// Handle to change the rate limit while downloading.
+    pub fn rate_limit(&self) -> RateLimiter {
+        self.rate_limit.clone()
+    }
+
+    /// Handle to pause, resume or cancel the download, from any thread.
+    pub fn control(&self) -> TransferControl {
+        self.control.clone()
+    }
+
+    /// Download everything, reconnecting according to the [`RetryPolicy`].
+    ///
+    /// A cancelled download returns `Ok` (after sending [`ClientEvent::Cancelled`]).
+    pub fn download(&self, handler: Sender<ClientEvent>) -> Result<(), SmdError> {
+        Ok(self.download_retrying(handler)?)
+    }
+
+    fn download_retrying(&self, handler: Sender<ClientEvent>) -> anyhow::Result<()> {
+        let rel_path = &self.path;
         // the path exists but is not a file
-        if p.exists() && !p.is_dir() {
-            anyhow::bail!("Path {p:?} is not a directory!")
+        if rel_path.exists() && !rel_path.is_dir() {
+            anyhow::bail!("Path {rel_path:?} is not a directory!")
         }
         // if the path does not exist, create it
-        else if !p.exists() {
-            std::fs::create_dir_all(&p)?;
+        else if !rel_path.exists() {
+            std::fs::create_dir_all(rel_path)?;
         }
 
-        p
-    } else { panic!("This should not happen?") };
+        // (kept across reconnects, the limits apply to the whole download)
+        let mut written = Written::default();
+        let mut attempt = 0;
+        loop {
+            let pauses = self.control.pauses();
+            // connect to server and download
+            let result = self.connector.connect()
+                .and_then(|stream| stream.tune(&self.socket_options).map(|_| stream))
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| download(stream, self, &handler, &mut written));
 
-    // connect to server
-    let stream = if let cli::Action::Download { address, .. } = &args.action {
-        net::TcpStream::connect(address)?
-    } else { panic!("This should not happen?") };
+            match result {
+                // the resume list and partially downloaded files are kept
+                Err(_) if self.control.is_cancelled() => {
+                    handler.send(ClientEvent::Cancelled)?;
+                    return Ok(())
+                },
+                // the Hoster may have given up on a long pause, which does not count as a failed attempt
+                Err(e) if self.control.pauses() != pauses && is_connection_loss(&e) => {
+                    handler.send(ClientEvent::Reconnecting{ attempt: 0, reason: format!("{e:#}") })?;
+                },
+                // reconnect with the (updated) resume list
+                Err(e) if attempt < self.retry.max_attempts && is_connection_loss(&e) => {
+                    if let (true, Some(timeout)) = (is_timeout(&e), self.timeout) {
+                        handler.send(ClientEvent::HosterSilent(timeout))?;
+                    }
+                    attempt += 1;
+                    handler.send(ClientEvent::Reconnecting{ attempt, reason: format!("{e:#}") })?;
+                    if self.control.sleep(self.retry.backoff(attempt)).is_err() {
+                        handler.send(ClientEvent::Cancelled)?;
+                        return Ok(())
+                    }
+                },
+                result => return result
+            }
+        }
+    }
+}
 
-    download(stream, rel_path, args.compression, args.encryption_key, handler)?;
 
-    Ok(())
+/// Whether `error` was caused by the Hoster not sending anything in time
+fn is_timeout(error: &anyhow::Error) -> bool {
+    error.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock))
 }
 
-fn download(stream: net::TcpStream, rel_path: std::path::PathBuf, compression: bool, key: Option<String>, handler: Sender<ClientEvent>) -> anyhow::Result<()> {
-    stream.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+/// Whether `error` was caused by a lost (or timed out) connection,
+/// which might be fixed by reconnecting
+fn is_connection_loss(error: &anyhow::Error) -> bool {
+    error.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| matches!(e.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected | io::ErrorKind::BrokenPipe | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+        ))
+}
+
+fn download(stream: Box<dyn Transport>, client: &Client, handler: &Sender<ClientEvent>, written: &mut Written) -> anyhow::Result<()> {
+    let &Client{ path: ref rel_path, compression, encryption_key: ref key, limits, timeout, ref rate_limit, pipeline, ref control, .. } = client;
+    stream.set_read_timeout(timeout)?;
     
-    let mut serializer = Serializer::new(stream.try_clone()?);
-    let mut deserializer = Deserializer::new(io::BufReader::new(stream));
+    let limiters = [rate_limit.clone()];
+    // (flushed after every message, the Hoster waits for each)
+    let mut serializer = Serializer::new(VectoredBufWriter::new(stream.try_clone_boxed()?));
+    let mut deserializer = Deserializer::new(io::BufReader::new(RateLimitedReader::new(stream, &limiters)));
     
     // load resume list from file
     let mut smd_res_path = rel_path.to_owned(); smd_res_path.push(".smdres");
@@ -52,24 +347,48 @@ fn download(stream: net::TcpStream, rel_path: std::path::PathBuf, compression: b
     if let Some(list) = &resume_list {
         handler.send(ClientEvent::ResumeListFound(list.len()))?
     }
+    let resuming = resume_list.is_some();
+    
+    // create encryptor
+    let mut decryptor = key.as_ref().map(|key| 
+        chacha20poly1305::ChaCha20Poly1305::new(&prepare_key(key))
+    );
     
     // send handshake
     Handshake{
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
         version: env!("CARGO_PKG_VERSION").to_owned(),
+        capabilities: Capabilities::supported(),
         resume_list,
-        compression
+        compression,
+        key_check: decryptor.as_ref().map(create_key_check),
+        timeout_ms: timeout.map_or(0, |t| t.as_millis() as u64),
+        max_window_log: Some(limits.max_window_log)
     }.serialize(&mut serializer)?;
+    serializer.get_mut().flush()?;
 
     // receive response
-    let response = HandshakeResponse::deserialize(&mut deserializer)?;
-    handler.send(ClientEvent::HandShakeResponse{ total_size: response.total_size, compression: response.compression })?;
-    let compression = compression | response.compression;
-    
-    // create encryptor
-    let mut decryptor = key.map(|key| 
-        chacha20poly1305::ChaCha20Poly1305::new(&prepare_key(key))
-    );
+    let (total_size, codec, dictionary, window_log) = loop { match HandshakeResponse::deserialize(&mut deserializer)? {
+        HandshakeResponse::Accepted{ total_size, compression: descriptor, dictionary, window_log, .. } => break (total_size, descriptor.codec(compression), dictionary.flatten(), window_log),
+        HandshakeResponse::Rejected(rejection) => {
+            let context = format!("Hoster rejected the connection ({})", rejection.code);
+            return Err(anyhow::Error::new(SmdError::from(rejection)).context(context))
+        },
+        // the Hoster is full, wait for the final response
+        HandshakeResponse::Queued{ waiting } => {
+            control.check()?;
+            handler.send(ClientEvent::Queued(waiting))?
+        }
+    }};
+    if codec == Some(CompressionAlgorithm::Unknown) {
+        return Err(SmdError::ProtocolViolation("Unknown compression algorithm".into()).into())
+    }
+    limits.check_window_log(window_log)?;
+    handler.send(ClientEvent::HandShakeResponse{ total_size, compression: codec })?;
     
+    // check advertised size against limits and free space
+    limits.check_total_size(total_size, resuming, rel_path)?;
     
     // open resume list file
     let mut smd_res_file = std::fs::OpenOptions::new()
@@ -79,54 +398,132 @@ fn download(stream: net::TcpStream, rel_path: std::path::PathBuf, compression: b
     
     // write files
     let start = std::time::Instant::now();
+    let mut received = Received::default();
     
     while !deserializer.get_mut().fill_buf()?.is_empty() {
+        control.check()?;
+        
         // receive header
         match EntryHeader::deserialize(&mut deserializer)? {
             DirHeader{ path: extend_path } => {
                 let path = rel_path.join(maybe_decrypt_path(extend_path, &mut decryptor)?);
                 std::fs::create_dir_all(path)?;
             },
-            FileHeader{ path: extend_path, size } => loop {
+            FileHeader{ path: extend_path, size, compressed } => {
                 // decrypt and build path
-                let path = maybe_decrypt_path(extend_path.clone(), &mut decryptor)?;
+                let path = maybe_decrypt_path(extend_path, &mut decryptor)?;
+                let file_path = rel_path.join(&path);
+                
+                // enforce limits before touching the disk
+                limits.check_file(written, size, rel_path, &path)?;
                 handler.send(ClientEvent::FileHeader{ rel_path: path.to_string_lossy().into(), size })?;
                 
                 // open file
-                let mut file = std::fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&rel_path.join(path))?;
-                
-                // wrap file into decryptor
-                let reader = PerhapsEncrReader::with_decryptor(deserializer.get_mut(), &mut decryptor);
-                // into decompressor
-                let reader = PerhapsCompressedReader::with_compression(reader, compression);
-                // into hasher
-                let reader = HashReader::new(reader);
-                // and into message sender
-                let mut reader = ClientEventReader::new(reader, &handler);
-                    
-                // write size amount of bytes to file
-                io::copy(&mut (&mut reader).take(size), &mut file)?;
-
-                // calculate and receive both hashes 
-                let (_, local_hash) = reader.inner().finalize();
+                let mut file = create_file(&file_path, size, limits.preallocate)?;
+                let codec = codec.filter(|_| compressed.unwrap_or(true));
+
+                // neither compressed nor encrypted, the stream can go straight from the socket into the file
+                #[cfg(target_os = "linux")]
+                let zero_copy = codec.is_none() && decryptor.is_none()
+                    && zero_copy::supported(&**deserializer.get_ref().get_ref().get_ref());
+                #[cfg(not(target_os = "linux"))]
+                let zero_copy = false;
+
+                let (raw, wire, local_hash) = match zero_copy {
+                    #[cfg(target_os = "linux")]
+                    true => splice_file(FramedReader::new(CountingReader::new(deserializer.get_mut())), &file, &file_path, size, control, handler)?,
+                    _ if pipeline => {
+                        let stream = pipeline::FileStream{ codec, dictionary: dictionary.as_deref(), window_log };
+                        pipeline::receive_file(FramedReader::new(CountingReader::new(deserializer.get_mut())), &mut file, size, stream, &mut decryptor, control, handler)?
+                    },
+                    _ => {
+                        // wrap stream into frames and decryptor
+                        let reader = PerhapsEncrReader::with_decryptor(FramedReader::new(CountingReader::new(deserializer.get_mut())), &mut decryptor);
+                        // into decompressor
+                        let reader = PerhapsCompressedReader::with_params(reader, codec, dictionary.as_deref(), window_log)?;
+                        // into hasher
+                        let reader = HashReader::new(reader);
+                        // into message sender
+                        let reader = ClientEventReader::new(reader, handler);
+                        // and into pause/cancel checker
+                        let mut reader = ControlledReader::new(reader, control);
+
+                        // write size amount of bytes to file
+                        let raw = io::copy(&mut (&mut reader).take(size), &mut file)?;
+
+                        // calculate local hash
+                        let (decompressor, local_hash) = reader.inner().inner().finalize();
+                        // skip to the end of the stream (e.g. the end of the compressed frame)
+                        let wire = decompressor.into_inner().into_inner().finish()?.read as u64;
+                        (raw, wire, local_hash)
+                    }
+                };
+                received.streamed(raw, wire);
+                handler.send(ClientEvent::StreamReceived{ raw, wire })?;
                 let hash = FileHash::deserialize(&mut deserializer)?;
                 
-                // compare hashes
-                if hash.hash == local_hash {
-                    handler.send(ClientEvent::FileFinished(true))?;
-                    FileHashResponse{ matches: true }.serialize(&mut serializer)?;
+                // compare hashes (the Hoster will resend the whole file, including its header)
+                let matches = hash.hash == local_hash;
+                handler.send(ClientEvent::FileFinished(matches))?;
+                FileHashResponse{ matches }.serialize(&mut serializer)?;
+                serializer.get_mut().flush()?;
+                if matches {
                     // write hash to smd_res
                     smd_res_file.write_all(&local_hash.to_ne_bytes())?;
-                    break;
-                } 
-                else {
-                    handler.send(ClientEvent::FileFinished(false))?;
-                    FileHashResponse{ matches: false }.serialize(&mut serializer)?;
                 }
+                received.file_checked(matches, path)?;
+            },
+            SolidHeader{ entries, compressed } => {
+                // wrap stream into frames and decryptor
+                let reader = PerhapsEncrReader::with_decryptor(FramedReader::new(CountingReader::new(deserializer.get_mut())), &mut decryptor);
+                // and into decompressor, shared by all entries
+                let mut stream = PerhapsCompressedReader::with_params(reader, codec.filter(|_| compressed), dictionary.as_deref(), window_log)?;
+
+                let mut responses = Vec::new();
+                let mut raw = 0;
+                for _ in 0..entries {
+                    control.check()?;
+                    // the paths are encrypted along with the stream
+                    match EntryHeader::deserialize(&mut Deserializer::new(&mut stream))? {
+                        DirHeader{ path: extend_path } => {
+                            let path = rel_path.join(maybe_decrypt_path(extend_path, &mut None)?);
+                            std::fs::create_dir_all(path)?;
+                        },
+                        FileHeader{ path: extend_path, size, .. } => {
+                            let path = maybe_decrypt_path(extend_path, &mut None)?;
+                            let file_path = rel_path.join(&path);
+
+                            // enforce limits before touching the disk
+                            limits.check_file(written, size, rel_path, &path)?;
+                            handler.send(ClientEvent::FileHeader{ rel_path: path.to_string_lossy().into(), size })?;
+                            let mut file = create_file(&file_path, size, limits.preallocate)?;
+
+                            // hash, report and control the content
+                            let mut reader = ControlledReader::new(ClientEventReader::new(HashReader::new(&mut stream), handler), control);
+                            raw += io::copy(&mut (&mut reader).take(size), &mut file)?;
+                            let (_, local_hash) = reader.inner().inner().finalize();
+                            let hash = FileHash::deserialize(&mut Deserializer::new(&mut stream))?;
+
+                            // compare hashes (mismatching files are resent on their own)
+                            let matches = hash.hash == local_hash;
+                            handler.send(ClientEvent::FileFinished(matches))?;
+                            responses.push(FileHashResponse{ matches });
+                            if matches {
+                                // write hash to smd_res
+                                smd_res_file.write_all(&local_hash.to_ne_bytes())?;
+                            }
+                            received.file_checked(matches, path)?;
+                        },
+                        SolidHeader{ .. } => return Err(SmdError::ProtocolViolation("Nested solid stream".into()).into())
+                    }
+                }
+
+                // skip to the end of the stream (e.g. the end of the compressed frame)
+                let wire = stream.into_inner().into_inner().finish()?.read as u64;
+                received.streamed(raw, wire);
+                handler.send(ClientEvent::StreamReceived{ raw, wire })?;
+                responses.serialize(&mut serializer)?;
+                serializer.get_mut().flush()?;
             }
         }
     }
@@ -134,12 +531,81 @@ fn download(stream: net::TcpStream, rel_path: std::path::PathBuf, compression: b
     // delete resume list file after completion
     std::fs::remove_file(smd_res_path)?;
     // send finish event
-    handler.send(ClientEvent::Completed(start.elapsed()))?;
+    handler.send(ClientEvent::Completed{ time_taken: start.elapsed(), raw: received.raw, wire: received.wire })?;
     
     Ok(())
 }
 
 
+/// What has been received from the Hoster so far
+#[derive(Debug, Default)]
+struct Received {
+    /// Hash mismatches in a row
+    mismatches: u32,
+    /// Bytes of files and Bytes on the wire, of all streams (including mismatching files)
+    raw: u64,
+    wire: u64
+}
+
+impl Received {
+    /// Counts a checked file, failing if its hashes mismatched too often in a row
+    fn file_checked(&mut self, matches: bool, path: PathBuf) -> Result<(), SmdError> {
+        if matches {
+            self.mismatches = 0;
+        } else {
+            self.mismatches += 1;
+            if self.mismatches >= MAX_HASH_MISMATCHES {
+                return Err(SmdError::HashMismatchLimit{ path, attempts: self.mismatches })
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a received stream (a file or solid stream), of `raw` Bytes in `wire` Bytes
+    fn streamed(&mut self, raw: u64, wire: u64) {
+        self.raw += raw;
+        self.wire += wire;
+    }
+}
+
+/// Moves up to `size` Bytes of data frames from the socket through a pipe into `file` (at `file_path`),
+/// hashing each range from the page cache right after it has been written.
+/// Returns the Bytes written to the file and read from the stream, and the hash.
+#[cfg(target_os = "linux")]
+fn splice_file<R: BufRead + SpliceRead>(mut reader: FramedReader<CountingReader<R>>, file: &std::fs::File, file_path: &Path, size: u64, control: &TransferControl, handler: &Sender<ClientEvent>) -> anyhow::Result<(u64, u64, u128)> {
+    let pipe = Pipe::new()?;
+    let mut hasher = zero_copy::FileHasher::new();
+    let mut raw = 0;
+    while raw < size {
+        control.check()?;
+        let count = (size - raw).min(zero_copy::FRAME_SIZE as u64) as usize;
+        let moved = reader.splice_to(&pipe, file, count)?;
+        if moved == 0 {
+            break
+        }
+        hasher.update(file, raw, moved).map_err(SmdError::io(file_path))?;
+        handler.send(ClientEvent::FileUpdate(moved))?;
+        raw += moved as u64;
+    }
+    Ok((raw, reader.finish()?.read as u64, hasher.finalize()))
+}
+
+/// Creates (or truncates) the file to download into (readable too, to hash what is spliced into it)
+fn create_file(file_path: &Path, size: u64, preallocate: bool) -> Result<std::fs::File, SmdError> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(file_path)
+        .map_err(SmdError::io(file_path))?;
+    if preallocate {
+        disk::preallocate(&file, size).map_err(SmdError::io(file_path))?;
+    }
+    Ok(file)
+}
+
+
 fn load_resume_list(smd_res_path: &std::path::Path) -> io::Result<Option<std::collections::HashSet<FileHash>>> {
     // open file
     let resume_list = match std::fs::read(smd_res_path) {
@@ -0,0 +1,101 @@
+//! Receiving a file in stages, each on a thread of its own (see [`Client::with_pipeline`](super::Client::with_pipeline)):
+//! decrypting, decompressing, hashing and writing to disk overlap with each other and with receiving.
+use chacha20poly1305::ChaCha20Poly1305;
+
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::mpsc::Sender;
+
+use crate::CompressionAlgorithm;
+use crate::buffered_io::{CountingReader, FramedReader, HashReader, PerhapsCompressedReader, PerhapsEncrReader};
+use crate::buffered_io::channel_io::{channel, forward, join, ChannelReader, ChannelWriter, StageErrors};
+use crate::client_events::{ClientEvent, ClientEventReader};
+use crate::control::{ControlledReader, TransferControl};
+
+
+/// How a file is received
+pub(super) struct FileStream<'a> {
+    /// If some, decompressed with it
+    pub codec: Option<CompressionAlgorithm>,
+    pub dictionary: Option<&'a [u8]>,
+    pub window_log: Option<u32>,
+}
+
+/// Receives `size` Bytes into `file` like the serial copy of `download`, from `reader`.
+/// Returns the Bytes written, the Bytes read from the stream and the hash.
+pub(super) fn receive_file<R: BufRead>(reader: FramedReader<CountingReader<R>>, file: &mut File, size: u64, stream: FileStream<'_>, decryptor: &mut Option<ChaCha20Poly1305>, control: &TransferControl, handler: &Sender<ClientEvent>) -> anyhow::Result<(u64, u64, u128)> {
+    let FileStream{ codec, dictionary, window_log } = stream;
+    std::thread::scope(|scope| {
+        let (output, input) = channel();
+
+        // decrypt
+        let (decrypter, input) = match decryptor.is_some() {
+            true => {
+                let (mut output, next) = channel();
+                let decrypter = scope.spawn(move || forward(PerhapsEncrReader::with_decryptor(ChannelReader::new(input), decryptor), &mut output));
+                (Some(decrypter), next)
+            },
+            false => (None, input)
+        };
+
+        // decompress
+        let (decompressor, input) = match codec {
+            Some(codec) => {
+                let (mut output, next) = channel();
+                let decompressor = scope.spawn(move || -> io::Result<u64> {
+                    let reader = PerhapsCompressedReader::with_params(ChannelReader::new(input), Some(codec), dictionary, window_log)?;
+                    forward(reader, &mut output)
+                });
+                (Some(decompressor), next)
+            },
+            None => (None, input)
+        };
+
+        // hash
+        let (mut output_hashed, next) = channel();
+        let hasher = scope.spawn(move || -> io::Result<(u64, u128)> {
+            let mut reader = HashReader::new(ChannelReader::new(input));
+            let raw = forward((&mut reader).take(size), &mut output_hashed)?;
+            drop(output_hashed);
+            // (anything beyond the announced size is not written, but has to be read)
+            let (mut rest, hash) = reader.finalize();
+            io::copy(&mut rest, &mut io::sink())?;
+            Ok((raw, hash))
+        });
+        let input = next;
+
+        // write to disk (and report the progress, pause or cancel)
+        let writer = scope.spawn(move || -> io::Result<()> {
+            let mut reader = ControlledReader::new(ClientEventReader::new(ChannelReader::new(input), handler), control);
+            io::copy(&mut reader, file)?;
+            file.flush()
+        });
+
+        // receive, on this thread
+        let received = receive_chunks(reader, output);
+
+        let mut errors = StageErrors::default();
+        let wire = errors.take(received);
+        if let Some(decrypter) = decrypter {
+            errors.take(join(decrypter));
+        }
+        if let Some(decompressor) = decompressor {
+            errors.take(join(decompressor));
+        }
+        let hashed = errors.take(join(hasher));
+        errors.take(join(writer));
+        errors.into_result()?;
+        match (hashed, wire) {
+            (Some((raw, hash)), Some(wire)) => Ok((raw, wire, hash)),
+            _ => unreachable!("A stage failed without an error")
+        }
+    })
+}
+
+/// Passes on the data frames, returning the Bytes read from the stream
+fn receive_chunks<R: BufRead>(mut reader: FramedReader<CountingReader<R>>, mut output: ChannelWriter) -> io::Result<u64> {
+    forward(&mut reader, &mut output)?;
+    // (let the stages finish, while reading the end of the stream)
+    drop(output);
+    Ok(reader.finish()?.read as u64)
+}
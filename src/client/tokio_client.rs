@@ -0,0 +1,280 @@
+use chacha20poly1305::KeyInit;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+use std::cmp::min;
+use std::sync::mpsc::Sender;
+
+use super::{Client, Received, Written, create_file, is_timeout, is_connection_loss, load_resume_list};
+use crate::{Capabilities, CompressionAlgorithm, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, SmdError};
+use crate::{Handshake, HandshakeResponse, FileHash, EntryHeader::{FileHeader, DirHeader, SolidHeader}, EntryHeader, FileHashResponse};
+use crate::buffered_io::encrypt_io::{prepare_key, maybe_decrypt_path, create_key_check};
+use crate::buffered_io::tokio_io::{HashReader, PerhapsCompressedReader, PerhapsEncrReader, CountingReader, FramedReader, RateLimitedReader, MessageReader, write_message, with_timeout};
+use crate::client_events::ClientEvent;
+use crate::transport::tokio_net::{self, AsyncTransport};
+
+
+impl Client {
+    /// Like [`Client::download`], but on the current [`tokio`] runtime.
+    ///
+    /// Only for clients created with [`Client::new`], as custom connectors are blocking.
+    pub async fn download_async(&self, handler: Sender<ClientEvent>) -> Result<(), SmdError> {
+        Ok(self.download_retrying_async(handler).await?)
+    }
+
+    async fn download_retrying_async(&self, handler: Sender<ClientEvent>) -> anyhow::Result<()> {
+        let Some(address) = &self.address else {
+            anyhow::bail!("Asynchronous downloads need an address, not a custom connector!")
+        };
+        let rel_path = &self.path;
+        // the path exists but is not a file
+        if rel_path.exists() && !rel_path.is_dir() {
+            anyhow::bail!("Path {rel_path:?} is not a directory!")
+        }
+        // if the path does not exist, create it
+        else if !rel_path.exists() {
+            tokio::fs::create_dir_all(rel_path).await?;
+        }
+
+        // (kept across reconnects, the limits apply to the whole download)
+        let mut written = Written::default();
+        let mut attempt = 0;
+        loop {
+            let pauses = self.control.pauses();
+            // connect to server and download
+            let result = match tokio_net::connect(address, &self.socket_options).await {
+                Ok(stream) => download(stream, self, &handler, &mut written).await,
+                Err(e) => Err(e.into())
+            };
+
+            match result {
+                // the resume list and partially downloaded files are kept
+                Err(_) if self.control.is_cancelled() => {
+                    handler.send(ClientEvent::Cancelled)?;
+                    return Ok(())
+                },
+                // the Hoster may have given up on a long pause, which does not count as a failed attempt
+                Err(e) if self.control.pauses() != pauses && is_connection_loss(&e) => {
+                    handler.send(ClientEvent::Reconnecting{ attempt: 0, reason: format!("{e:#}") })?;
+                },
+                // reconnect with the (updated) resume list
+                Err(e) if attempt < self.retry.max_attempts && is_connection_loss(&e) => {
+                    if let (true, Some(timeout)) = (is_timeout(&e), self.timeout) {
+                        handler.send(ClientEvent::HosterSilent(timeout))?;
+                    }
+                    attempt += 1;
+                    handler.send(ClientEvent::Reconnecting{ attempt, reason: format!("{e:#}") })?;
+                    if self.control.sleep_async(self.retry.backoff(attempt)).await.is_err() {
+                        handler.send(ClientEvent::Cancelled)?;
+                        return Ok(())
+                    }
+                },
+                result => return result
+            }
+        }
+    }
+}
+
+
+async fn download(stream: Box<dyn AsyncTransport>, client: &Client, handler: &Sender<ClientEvent>, written: &mut Written) -> anyhow::Result<()> {
+    let &Client{ path: ref rel_path, compression, encryption_key: ref key, limits, timeout, ref rate_limit, ref control, .. } = client;
+    let limiters = [rate_limit.clone()];
+    let (reader, mut stream) = tokio::io::split(stream);
+    let mut reader = MessageReader::new(RateLimitedReader::new(reader, &limiters));
+
+    // load resume list from file
+    let mut smd_res_path = rel_path.to_owned(); smd_res_path.push(".smdres");
+    let resume_list = load_resume_list(&smd_res_path)?;
+    if let Some(list) = &resume_list {
+        handler.send(ClientEvent::ResumeListFound(list.len()))?
+    }
+    let resuming = resume_list.is_some();
+
+    // create encryptor
+    let mut decryptor = key.as_ref().map(|key|
+        chacha20poly1305::ChaCha20Poly1305::new(&prepare_key(key))
+    );
+
+    // send handshake
+    write_message(&mut stream, &Handshake{
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        capabilities: Capabilities::supported(),
+        resume_list,
+        compression,
+        key_check: decryptor.as_ref().map(create_key_check),
+        timeout_ms: timeout.map_or(0, |t| t.as_millis() as u64),
+        max_window_log: Some(limits.max_window_log)
+    }).await?;
+
+    // receive response
+    let (total_size, codec, dictionary, window_log) = loop { match with_timeout(timeout, reader.read_message()).await? {
+        HandshakeResponse::Accepted{ total_size, compression: descriptor, dictionary, window_log, .. } => break (total_size, descriptor.codec(compression), dictionary.flatten(), window_log),
+        HandshakeResponse::Rejected(rejection) => {
+            let context = format!("Hoster rejected the connection ({})", rejection.code);
+            return Err(anyhow::Error::new(SmdError::from(rejection)).context(context))
+        },
+        // the Hoster is full, wait for the final response
+        HandshakeResponse::Queued{ waiting } => {
+            control.check_async().await?;
+            handler.send(ClientEvent::Queued(waiting))?
+        }
+    }};
+    if codec == Some(CompressionAlgorithm::Unknown) {
+        return Err(SmdError::ProtocolViolation("Unknown compression algorithm".into()).into())
+    }
+    limits.check_window_log(window_log)?;
+    handler.send(ClientEvent::HandShakeResponse{ total_size, compression: codec })?;
+
+    // check advertised size against limits and free space
+    limits.check_total_size(total_size, resuming, rel_path)?;
+
+    // open resume list file
+    let mut smd_res_file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&smd_res_path).await?;
+
+    // write files
+    let start = std::time::Instant::now();
+    let mut received = Received::default();
+
+    while !with_timeout(timeout, reader.fill_buf()).await?.is_empty() {
+        control.check_async().await?;
+
+        // receive header
+        match with_timeout(timeout, reader.read_message::<EntryHeader>()).await? {
+            DirHeader{ path: extend_path } => {
+                let path = rel_path.join(maybe_decrypt_path(extend_path, &mut decryptor)?);
+                tokio::fs::create_dir_all(path).await?;
+            },
+            FileHeader{ path: extend_path, size, compressed } => {
+                // decrypt and build path
+                let path = maybe_decrypt_path(extend_path, &mut decryptor)?;
+                let file_path = rel_path.join(&path);
+
+                // enforce limits before touching the disk
+                limits.check_file(written, size, rel_path, &path)?;
+                handler.send(ClientEvent::FileHeader{ rel_path: path.to_string_lossy().into(), size })?;
+
+                // open file
+                let mut file = tokio::fs::File::from_std(create_file(&file_path, size, limits.preallocate)?);
+
+                // wrap stream into frames and decryptor
+                let file_reader = PerhapsEncrReader::with_decryptor(FramedReader::new(CountingReader::new(&mut reader)), &mut decryptor);
+                // into decompressor
+                let file_reader = PerhapsCompressedReader::with_params(file_reader, codec.filter(|_| compressed.unwrap_or(true)), dictionary.as_deref(), window_log)?;
+                // and into hasher
+                let mut file_reader = HashReader::new(file_reader);
+
+                // write size amount of bytes to file
+                let mut buffer = vec![0u8; 1 << 13];
+                let mut remaining = size;
+                while remaining > 0 {
+                    control.check_async().await?;
+                    let wanted = min(remaining, buffer.len() as u64) as usize;
+                    let read = with_timeout(timeout, file_reader.read(&mut buffer[..wanted])).await?;
+                    if read == 0 {
+                        break
+                    }
+                    file.write_all(&buffer[..read]).await.map_err(SmdError::io(&file_path))?;
+                    handler.send(ClientEvent::FileUpdate(read))?;
+                    remaining -= read as u64;
+                }
+                file.flush().await.map_err(SmdError::io(&file_path))?;
+
+                // calculate and receive both hashes
+                let (decompressor, local_hash) = file_reader.finalize();
+                // skip to the end of the stream (e.g. the end of the compressed frame)
+                let wire = with_timeout(timeout, decompressor.into_inner().into_inner().finish()).await?.read as u64;
+                let raw = size - remaining;
+                received.streamed(raw, wire);
+                handler.send(ClientEvent::StreamReceived{ raw, wire })?;
+                let hash: FileHash = with_timeout(timeout, reader.read_message()).await?;
+
+                // compare hashes (the Hoster will resend the whole file, including its header)
+                let matches = hash.hash == local_hash;
+                handler.send(ClientEvent::FileFinished(matches))?;
+                write_message(&mut stream, &FileHashResponse{ matches }).await?;
+                if matches {
+                    // write hash to smd_res
+                    smd_res_file.write_all(&local_hash.to_ne_bytes()).await?;
+                }
+                received.file_checked(matches, path)?;
+            },
+            SolidHeader{ entries, compressed } => {
+                // wrap stream into frames and decryptor
+                let entries_reader = PerhapsEncrReader::with_decryptor(FramedReader::new(CountingReader::new(&mut reader)), &mut decryptor);
+                // and into decompressor, shared by all entries
+                let mut entries_reader = MessageReader::new(PerhapsCompressedReader::with_params(entries_reader, codec.filter(|_| compressed), dictionary.as_deref(), window_log)?);
+
+                let mut responses = Vec::new();
+                let mut raw = 0;
+                let mut buffer = vec![0u8; 1 << 13];
+                for _ in 0..entries {
+                    control.check_async().await?;
+                    // the paths are encrypted along with the stream
+                    match with_timeout(timeout, entries_reader.read_message::<EntryHeader>()).await? {
+                        DirHeader{ path: extend_path } => {
+                            let path = rel_path.join(maybe_decrypt_path(extend_path, &mut None)?);
+                            tokio::fs::create_dir_all(path).await?;
+                        },
+                        FileHeader{ path: extend_path, size, .. } => {
+                            let path = maybe_decrypt_path(extend_path, &mut None)?;
+                            let file_path = rel_path.join(&path);
+
+                            // enforce limits before touching the disk
+                            limits.check_file(written, size, rel_path, &path)?;
+                            handler.send(ClientEvent::FileHeader{ rel_path: path.to_string_lossy().into(), size })?;
+                            let mut file = tokio::fs::File::from_std(create_file(&file_path, size, limits.preallocate)?);
+
+                            // write size amount of bytes to file, hashing them
+                            let mut file_reader = HashReader::new(&mut entries_reader);
+                            let mut remaining = size;
+                            while remaining > 0 {
+                                control.check_async().await?;
+                                let wanted = min(remaining, buffer.len() as u64) as usize;
+                                let read = with_timeout(timeout, file_reader.read(&mut buffer[..wanted])).await?;
+                                if read == 0 {
+                                    break
+                                }
+                                file.write_all(&buffer[..read]).await.map_err(SmdError::io(&file_path))?;
+                                handler.send(ClientEvent::FileUpdate(read))?;
+                                remaining -= read as u64;
+                            }
+                            file.flush().await.map_err(SmdError::io(&file_path))?;
+                            raw += size - remaining;
+                            let (_, local_hash) = file_reader.finalize();
+                            let hash: FileHash = with_timeout(timeout, entries_reader.read_message()).await?;
+
+                            // compare hashes (mismatching files are resent on their own)
+                            let matches = hash.hash == local_hash;
+                            handler.send(ClientEvent::FileFinished(matches))?;
+                            responses.push(FileHashResponse{ matches });
+                            if matches {
+                                // write hash to smd_res
+                                smd_res_file.write_all(&local_hash.to_ne_bytes()).await?;
+                            }
+                            received.file_checked(matches, path)?;
+                        },
+                        SolidHeader{ .. } => return Err(SmdError::ProtocolViolation("Nested solid stream".into()).into())
+                    }
+                }
+
+                // skip to the end of the stream (e.g. the end of the compressed frame)
+                let wire = with_timeout(timeout, entries_reader.into_inner().into_inner().into_inner().finish()).await?.read as u64;
+                received.streamed(raw, wire);
+                handler.send(ClientEvent::StreamReceived{ raw, wire })?;
+                write_message(&mut stream, &responses).await?;
+            }
+        }
+    }
+
+    // delete resume list file after completion
+    smd_res_file.flush().await?;
+    tokio::fs::remove_file(smd_res_path).await?;
+    // send finish event
+    handler.send(ClientEvent::Completed{ time_taken: start.elapsed(), raw: received.raw, wire: received.wire })?;
+
+    Ok(())
+}
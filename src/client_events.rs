@@ -3,18 +3,35 @@ use bytesize::ByteSize;
 use std::io::{Read, Write};
 use std::sync::mpsc::{Sender, Receiver};
 
+use crate::CompressionAlgorithm;
+use crate::buffered_io::counting_io::compression_ratio;
+
 
 #[derive(Debug)]
 pub enum ClientEvent {
-    HandShakeResponse{ total_size: u64, compression: bool },
+    /// The Hoster is full, `waiting` clients (including this one) wait for a free slot.
+    /// Sent again regularly, until the [`ClientEvent::HandShakeResponse`].
+    Queued(u32),
+    /// With the algorithm the Hoster compresses with, if any
+    HandShakeResponse{ total_size: u64, compression: Option<CompressionAlgorithm> },
     FileHeader{ rel_path: String, size: u64 },
-    /// Additional Bytes Downloaded
+    /// Additional (decompressed) Bytes Downloaded
     FileUpdate(usize),
+    /// A file (or solid stream of files) has been received, its `raw` Bytes took `wire` Bytes on the wire
+    /// (including frames, encryption and compression)
+    StreamReceived{ raw: u64, wire: u64 },
     /// Is true, if hashes matches, otherwise false
     FileFinished(bool),
-    Completed(std::time::Duration),
+    /// `raw` and `wire` are the sums of all [`ClientEvent::StreamReceived`]
+    Completed{ time_taken: std::time::Duration, raw: u64, wire: u64 },
     /// Amount of files being (maybe) skipped
-    ResumeListFound(usize)
+    ResumeListFound(usize),
+    /// The resume list has been kept
+    Cancelled,
+    /// The connection has been lost, `attempt` starts at 1 (0 after a pause, which does not count)
+    Reconnecting{ attempt: u32, reason: String },
+    /// The Hoster has not sent anything (not even heartbeats) for this long
+    HosterSilent(std::time::Duration)
 }
 
 
@@ -54,16 +71,24 @@ pub fn handle_events_cli(recv: Receiver<ClientEvent>) -> std::io::Result<()> {
     
     let mut total_bytes = 1;
     let mut total_downloaded = 0;
+    let mut streams_raw = 0;
+    let mut streams_wire = 0;
+    let mut last_waiting = None;
 
     while let Ok(msg) = recv.recv() {
         match msg {
             ClientEvent::ResumeListFound(file_amount) => {
                 writeln!(&mut stdout, "Resume List Found, containing {file_amount} hashes.")?;
             },
+            // (only when the number changes)
+            ClientEvent::Queued(waiting) => if last_waiting.replace(waiting) != Some(waiting) {
+                writeln!(&mut stdout, "Hoster is full, waiting for a free slot ({waiting} in the queue)...")?;
+            },
             ClientEvent::HandShakeResponse{ total_size, compression } => {
+                last_waiting = None;
                 writeln!(&mut stdout, "CONNECTED with advertised total size of {}", ByteSize(total_size))?;
-                if compression {
-                    writeln!(&mut stdout, "(server is forcing compression)")?;
+                if let Some(codec) = compression {
+                    writeln!(&mut stdout, "(compressed with {codec})")?;
                 }
                 total_bytes = total_size;
             },
@@ -79,7 +104,7 @@ pub fn handle_events_cli(recv: Receiver<ClientEvent>) -> std::io::Result<()> {
 
                 // build progress bar
                 progress_bar.clear();
-                let progress = current_file_downloaded * 30 / current_file_size;
+                let progress = (current_file_downloaded * 30).checked_div(current_file_size).unwrap_or(30).min(30);
                 for _ in 0..progress {
                     progress_bar.push('=')
                 }
@@ -90,22 +115,42 @@ pub fn handle_events_cli(recv: Receiver<ClientEvent>) -> std::io::Result<()> {
                 
                 write!(&mut stdout, "\r[{progress_bar}] {}/{}  ", ByteSize(current_file_downloaded), ByteSize(current_file_size))?;
             },
+            ClientEvent::StreamReceived{ raw, wire } => {
+                streams_raw += raw;
+                streams_wire += wire;
+            },
             ClientEvent::FileFinished(hashes_match) => if hashes_match {
                 let time_taken = current_file_timer.elapsed();
                 let dl_speed = current_file_size as f32 / time_taken.as_secs_f32();
+                let ratio = compression_ratio(streams_raw, streams_wire).map(|ratio| format!(", ratio {ratio:.2}")).unwrap_or_default();
                 writeln!(&mut stdout, 
-                         "\rFile Completely Downloaded in {time_taken:7.1?} ({:08}/s){:>10} {}/{} Downloaded ({}%)",
+                         "\rFile Completely Downloaded in {time_taken:7.1?} ({:08}/s){:>10} {}/{} Downloaded ({}%{ratio})",
                          ByteSize(dl_speed as u64),
                          '|',
                          ByteSize(total_downloaded), 
                          ByteSize(total_bytes), 
-                         total_downloaded * 100 / total_bytes)?;
+                         (total_downloaded * 100).checked_div(total_bytes).unwrap_or(100))?;
             } else {
                 writeln!(&mut stdout, "\rFile Completely Downloaded... Hashes did NOT Match... Retrying...")?;
             },
-            ClientEvent::Completed(time_taken) => {
-                let dl_speed = total_bytes / time_taken.as_secs();
-                writeln!(&mut stdout, "\nDownloading {} finished in {time_taken:?} ({}/s)", ByteSize(total_bytes), ByteSize(dl_speed))?;
+            ClientEvent::Completed{ time_taken, raw, wire } => {
+                let dl_speed = raw as f64 / time_taken.as_secs_f64().max(f64::EPSILON);
+                let ratio = compression_ratio(raw, wire).map(|ratio| format!(", ratio {ratio:.2}")).unwrap_or_default();
+                writeln!(&mut stdout, "\nDownloading {} finished in {time_taken:?} ({}/s), {} on the wire{ratio}",
+                         ByteSize(raw), ByteSize(dl_speed as u64), ByteSize(wire))?;
+                break;
+            },
+            ClientEvent::HosterSilent(timeout) => {
+                writeln!(&mut stdout, "\nHoster has not responded for {timeout:?}.")?;
+            },
+            ClientEvent::Reconnecting{ attempt: 0, reason } => {
+                writeln!(&mut stdout, "\nConnection lost while paused ({reason}). Reconnecting...")?;
+            },
+            ClientEvent::Reconnecting{ attempt, reason } => {
+                writeln!(&mut stdout, "\nConnection lost ({reason}). Reconnecting (attempt {attempt})...")?;
+            },
+            ClientEvent::Cancelled => {
+                writeln!(&mut stdout, "\nDownload cancelled. Reconnect to resume it.")?;
                 break;
             }
         }
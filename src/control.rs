@@ -0,0 +1,142 @@
+use std::io::Read;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ControlState {
+    #[default]
+    Running,
+    Paused,
+    /// Final, a cancelled transfer can not be resumed
+    Cancelled
+}
+
+
+/// Shared handle to pause, resume or cancel a running transfer.
+///
+/// Clones control the same transfer.
+#[derive(Debug, Clone, Default)]
+pub struct TransferControl {
+    state: Arc<(Mutex<ControlState>, Condvar)>,
+    /// How often the transfer has been paused
+    pauses: Arc<AtomicU64>
+}
+
+impl TransferControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> ControlState {
+        *self.state.0.lock().unwrap()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state() == ControlState::Cancelled
+    }
+
+    /// Pauses the transfer, by no longer reading from (or writing to) the connection.
+    ///
+    /// The Hoster gives up on a connection silent for its idle timeout (a minute by default).
+    /// After a longer pause, the download reconnects once resumed (without using up a retry),
+    /// and the interrupted file is sent again.
+    pub fn pause(&self) {
+        if self.transition(ControlState::Running, ControlState::Paused) {
+            self.pauses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// How often the transfer has been paused so far
+    pub fn pauses(&self) -> u64 {
+        self.pauses.load(Ordering::Relaxed)
+    }
+
+    pub fn resume(&self) {
+        self.transition(ControlState::Paused, ControlState::Running);
+    }
+
+    pub fn cancel(&self) {
+        let (state, changed) = &*self.state;
+        *state.lock().unwrap() = ControlState::Cancelled;
+        changed.notify_all();
+    }
+
+    /// Blocks while the transfer is paused.
+    /// Errors, if the transfer has been cancelled.
+    pub fn check(&self) -> std::io::Result<()> {
+        let (state, changed) = &*self.state;
+        let state = changed.wait_while(state.lock().unwrap(), |s| *s == ControlState::Paused).unwrap();
+
+        match *state {
+            ControlState::Cancelled => Err(std::io::Error::other("Transfer has been cancelled!")),
+            _ => Ok(())
+        }
+    }
+
+    /// Waits for `duration`.
+    /// Errors (early), if the transfer has been cancelled.
+    pub fn sleep(&self, duration: Duration) -> std::io::Result<()> {
+        let (state, changed) = &*self.state;
+        let (state, _) = changed.wait_timeout_while(state.lock().unwrap(), duration, |s| *s != ControlState::Cancelled).unwrap();
+
+        match *state {
+            ControlState::Cancelled => Err(std::io::Error::other("Transfer has been cancelled!")),
+            _ => Ok(())
+        }
+    }
+
+    /// Like [`TransferControl::check`], without blocking the [`tokio`] runtime
+    #[cfg(feature = "async")]
+    pub async fn check_async(&self) -> std::io::Result<()> {
+        if self.state() == ControlState::Running {
+            return Ok(())
+        }
+        let control = self.clone();
+        tokio::task::spawn_blocking(move || control.check()).await?
+    }
+
+    /// Like [`TransferControl::sleep`], without blocking the [`tokio`] runtime
+    #[cfg(feature = "async")]
+    pub async fn sleep_async(&self, duration: Duration) -> std::io::Result<()> {
+        let control = self.clone();
+        tokio::task::spawn_blocking(move || control.sleep(duration)).await?
+    }
+
+    /// Whether the state was `from` (and has changed to `to`)
+    fn transition(&self, from: ControlState, to: ControlState) -> bool {
+        let (state, changed) = &*self.state;
+        let mut state = state.lock().unwrap();
+        if *state != from {
+            return false
+        }
+        *state = to;
+        changed.notify_all();
+        true
+    }
+}
+
+
+/// Checks the [`TransferControl`] before every read.
+pub struct ControlledReader<'a, R: Read> {
+    reader: R,
+    control: &'a TransferControl
+}
+
+impl<'a, R: Read> ControlledReader<'a, R> {
+    pub fn new(reader: R, control: &'a TransferControl) -> Self {
+        Self{ reader, control }
+    }
+
+    pub fn inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<'a, R: Read> Read for ControlledReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.control.check()?;
+        self.reader.read(buf)
+    }
+}
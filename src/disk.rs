@@ -0,0 +1,48 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+
+/// Free space (in Bytes) available to unprivileged users
+/// on the filesystem containing `path`.
+///
+/// Returns `None` on platforms where this can not be queried.
+pub fn available_space(path: &Path) -> io::Result<Option<u64>> {
+    #[cfg(unix)]
+    {
+        let stat = nix::sys::statvfs::statvfs(path)?;
+        Ok(Some(stat.blocks_available() as u64 * stat.fragment_size() as u64))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(None)
+    }
+}
+
+
+/// Reserve `size` Bytes on disk for `file`.
+///
+/// Filesystems (or platforms) that do not support preallocation are silently ignored,
+/// running out of space however is reported as an error.
+pub fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        use nix::fcntl::{fallocate, FallocateFlags};
+        use nix::errno::Errno;
+
+        if size == 0 {
+            return Ok(())
+        }
+        match fallocate(file.as_raw_fd(), FallocateFlags::empty(), 0, size as i64) {
+            Ok(()) | Err(Errno::EOPNOTSUPP) => Ok(()),
+            Err(e) => Err(e.into())
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (file, size);
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{Rejection, RejectionCode};
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+
+/// Errors of this library, which tooling might want to react to.
+#[derive(Debug)]
+pub enum SmdError {
+    /// The peers use different encryption keys (or only one of them uses one), noticed during the handshake
+    WrongKey,
+    /// The peer sent something that does not follow the protocol
+    ProtocolViolation(String),
+    /// The hashes of a file did not match `attempts` times in a row
+    HashMismatchLimit{ path: PathBuf, attempts: u32 },
+    /// I/O failed on a specific local path
+    Io{ path: PathBuf, source: io::Error },
+    /// A received path would end up outside the download directory
+    UnsafePath(PathBuf),
+    /// The peers do not share a protocol version (or a required capability)
+    VersionMismatch(String),
+    /// Refused by the server for any other reason
+    Rejected(Rejection),
+    /// Anything else (like a lost connection, or I/O on no specific path), with its context
+    Other(anyhow::Error)
+}
+
+impl SmdError {
+    /// For use with `map_err`, to attach `path` to an [`io::Error`]
+    pub fn io<P: AsRef<Path>>(path: P) -> impl FnOnce(io::Error) -> Self {
+        move |source| Self::Io{ path: path.as_ref().to_owned(), source }
+    }
+
+    /// Exit code of the CLI, when failing with this error
+    pub fn exit_code(&self) -> Option<u8> {
+        match self {
+            Self::VersionMismatch(_) => Some(RejectionCode::WrongVersion.exit_code()),
+            Self::WrongKey => Some(RejectionCode::WrongKey.exit_code()),
+            Self::Rejected(rejection) => Some(rejection.code.exit_code()),
+            _ => None
+        }
+    }
+}
+
+impl From<Rejection> for SmdError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection.code {
+            RejectionCode::WrongVersion => Self::VersionMismatch(rejection.message),
+            RejectionCode::WrongKey => Self::WrongKey,
+            _ => Self::Rejected(rejection)
+        }
+    }
+}
+
+/// Keeps an [`SmdError`] within `error` (dropping its context), the rest becomes [`SmdError::Other`]
+impl From<anyhow::Error> for SmdError {
+    fn from(error: anyhow::Error) -> Self {
+        error.downcast().unwrap_or_else(Self::Other)
+    }
+}
+
+impl std::fmt::Display for SmdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WrongKey => write!(f, "Wrong encryption key used!"),
+            Self::ProtocolViolation(details) => write!(f, "Protocol violation: {details}"),
+            Self::HashMismatchLimit{ path, attempts } => write!(f, "Hashes of {path:?} did not match {attempts} times in a row!"),
+            Self::Io{ path, source } => write!(f, "I/O error on {path:?}: {source}"),
+            Self::UnsafePath(path) => write!(f, "Refusing unsafe path {path:?}!"),
+            Self::VersionMismatch(details) => write!(f, "Version mismatch: {details}"),
+            Self::Rejected(rejection) => write!(f, "Rejected by server: {rejection}"),
+            // (along with the errors causing it)
+            Self::Other(error) => write!(f, "{error:#}"),
+        }
+    }
+}
+
+impl std::error::Error for SmdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io{ source, .. } => Some(source),
+            Self::Rejected(rejection) => Some(rejection),
+            _ => None
+        }
+    }
+}
@@ -1,8 +1,19 @@
 use serde::{Serialize, Deserialize};
 
+pub mod bench;
+pub mod bench_events;
 pub mod buffered_io;
 pub mod client_events;
+pub mod server_events;
 pub mod cli;
+pub mod control;
+pub mod disk;
+pub mod error;
+pub mod server;
+pub mod client;
+pub mod transport;
+
+pub use error::SmdError;
 
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -10,12 +21,25 @@ pub enum EntryHeader {
     FileHeader{ 
         /// Could be an utf-8 String or encrypted
         path: Vec<u8>,
-        /// If compression is used, this is probably inaccurate
-        size: u64
+        /// Of the file itself, as written to disk (compression and encryption only change the Bytes on the wire)
+        size: u64,
+        /// Whether this file is compressed, since [`PER_FILE_COMPRESSION_VERSION`]
+        /// (before, all files are compressed if the connection is)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compressed: Option<bool>
     },
     DirHeader{
         /// Could be an utf-8 String or encrypted
         path: Vec<u8>
+    },
+    /// Several small files and directories in one stream, to clients supporting [`TransferFeature::SolidStreams`].
+    ///
+    /// For every entry, the stream holds an [`EntryHeader`] (with an unencrypted path, as the stream is encrypted),
+    /// followed by the content and [`FileHash`] of files.
+    /// Answered with one [`FileHashResponse`] per file, mismatching files are resent on their own.
+    SolidHeader{
+        entries: u32,
+        compressed: bool
     }
 }
 
@@ -29,24 +53,268 @@ pub struct FileHashResponse {
     pub matches: bool
 }
 
+/// Version of the wire protocol, independent of the crate version.
+/// Only bumped on incompatible changes, optional features are negotiated through [`Capabilities`].
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest version of the wire protocol still spoken
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// First protocol version deciding on compression per file (see [`EntryHeader::FileHeader`]),
+/// which changes what older clients expect of every file header
+pub const PER_FILE_COMPRESSION_VERSION: u32 = 2;
+
+/// How often the hashes of a file may mismatch in a row, before both sides give up on it
+pub const MAX_HASH_MISMATCHES: u32 = 5;
+
+
+/// lz4 and brotli are only used with clients supporting them
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+pub enum CompressionAlgorithm {
+    /// Balanced, from fast to strong (levels 1 to 21)
+    Zstd,
+    /// Fastest, for quick links (levels are ignored)
+    Lz4,
+    /// Strongest at its highest levels, for slow links (levels 1 to 11)
+    Brotli,
+    /// Sent by a newer peer
+    #[serde(other)]
+    #[value(skip)]
+    Unknown
+}
+
+impl std::fmt::Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+            Self::Brotli => "brotli",
+            Self::Unknown => "unknown"
+        })
+    }
+}
+
+/// How compressed streams are compressed, as sent in [`HandshakeResponse::Accepted`]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum CompressionDescriptor {
+    /// Whether zstd is used, to clients supporting no other algorithm
+    Zstd(bool),
+    /// The algorithm used (None for no compression), to clients supporting others than zstd
+    Codec(Option<CompressionAlgorithm>)
+}
+
+impl CompressionDescriptor {
+    /// The algorithm used, if any, given whether the client `requested` compression
+    /// (clients only supporting zstd are only told whether the server forces compression)
+    pub fn codec(self, requested: bool) -> Option<CompressionAlgorithm> {
+        match self {
+            Self::Zstd(forced) => (requested || forced).then_some(CompressionAlgorithm::Zstd),
+            Self::Codec(codec) => codec
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    /// Sent by a newer peer
+    #[serde(other)]
+    Unknown
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum EncryptionMode {
+    ChaCha20Poly1305,
+    /// Sent by a newer peer
+    #[serde(other)]
+    Unknown
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TransferFeature {
+    /// Small files and directories batched into one stream (see [`EntryHeader::SolidHeader`])
+    SolidStreams,
+    /// zstd dictionaries (see [`HandshakeResponse::Accepted`])
+    Dictionary,
+    /// Windows above zstd's default limit (see [`HandshakeResponse::Accepted`])
+    LongWindow,
+    /// Waiting clients being kept informed (see [`HandshakeResponse::Queued`])
+    Queue,
+    /// Sent by a newer peer
+    #[serde(other)]
+    Unknown
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ResumeFeature {
+    /// Skipping files whose hash is in the resume list
+    HashList,
+    /// Sent by a newer peer
+    #[serde(other)]
+    Unknown
+}
+
+/// Optional features a peer supports.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Capabilities {
+    pub compression: Vec<CompressionAlgorithm>,
+    pub hashes: Vec<HashAlgorithm>,
+    pub encryption: Vec<EncryptionMode>,
+    pub resume: Vec<ResumeFeature>,
+    /// Left out if empty, for peers not knowing it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transfer: Vec<TransferFeature>,
+}
+
 /// Sent from the Client to the Server.
-/// Server can decline by closing connection. (right now)
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Handshake {
+    /// Newest protocol version spoken by the client
+    pub protocol_version: u32,
+    /// Oldest protocol version spoken by the client
+    pub min_protocol_version: u32,
+    /// Crate version of the client, only informational
     pub version: String,
+    pub capabilities: Capabilities,
     pub resume_list: Option<std::collections::HashSet<FileHash>>,
     pub compression: bool,
+    /// If the client uses an encryption key, a known text encrypted with it.
+    /// See [`buffered_io::encrypt_io::create_key_check`]
+    pub key_check: Option<Vec<u8>>,
+    /// After how many milliseconds of silence the client gives up (0 for never).
+    /// The Server sends heartbeats often enough to stay below it.
+    pub timeout_ms: u64,
+    /// Log2 of the largest window the client decompresses with [`TransferFeature::LongWindow`]
+    /// (without it, [`buffered_io::comp_io::DEFAULT_WINDOW_LOG_MAX`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_window_log: Option<u32>,
 }
 
-/// Reply (if client got accepted)
+/// Reply to the [`Handshake`].
 /// Client can decline by closing connection. (right now)
 #[derive(Debug, Serialize, Deserialize)]
-pub struct HandshakeResponse {
-    /// of all files, in Bytes.
-    pub total_size: u64,
-    pub compression: bool,
+pub enum HandshakeResponse {
+    Accepted{
+        /// Protocol version used from now on
+        protocol_version: u32,
+        /// Supported by both sides
+        capabilities: Capabilities,
+        /// of all files, in Bytes.
+        total_size: u64,
+        compression: CompressionDescriptor,
+        /// zstd dictionary every compressed stream is compressed with, with [`TransferFeature::Dictionary`].
+        /// `Some(None)` (sent as nil) keeps the place of the following field, as fields are sent in order.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dictionary: Option<Option<Vec<u8>>>,
+        /// Log2 of the window compressed streams use for long-distance matching, with [`TransferFeature::LongWindow`]
+        /// (without, it is at most [`buffered_io::comp_io::DEFAULT_WINDOW_LOG_MAX`]), at most [`Handshake::max_window_log`].
+        /// Never sent along with a dictionary.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        window_log: Option<u32>,
+    },
+    /// The server closes the connection after sending this
+    Rejected(Rejection),
+    /// The server is full, `waiting` clients (including this one) wait for a free slot.
+    /// Sent again often enough for the client not to time out, until the final response, with [`TransferFeature::Queue`].
+    Queued{ waiting: u32 }
+}
+
+/// Why the server refused a client
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RejectionCode {
+    /// No common protocol version or a required capability is missing
+    WrongVersion,
+    /// The client uses no, or a different encryption key
+    WrongKey,
+    /// Too many clients are connected
+    ServerFull,
+    /// The client is not allowed to download
+    Forbidden,
+    /// The client has downloaded too much already
+    QuotaExceeded,
+    /// The server can not serve anybody right now
+    ServerError,
+    /// Sent by a newer peer
+    #[serde(other)]
+    Unknown
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rejection {
+    pub code: RejectionCode,
+    /// Human readable details
+    pub message: String
+}
+
+impl Capabilities {
+    /// Everything this build supports
+    pub fn supported() -> Self {
+        Self{
+            compression: vec![CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4, CompressionAlgorithm::Brotli],
+            hashes: vec![HashAlgorithm::Md5],
+            encryption: vec![EncryptionMode::ChaCha20Poly1305],
+            resume: vec![ResumeFeature::HashList],
+            transfer: vec![TransferFeature::SolidStreams, TransferFeature::Dictionary, TransferFeature::LongWindow, TransferFeature::Queue],
+        }
+    }
+
+    /// Features supported by both `self` and `other`, in the order of `self`
+    pub fn intersection(&self, other: &Self) -> Self {
+        fn common<T: PartialEq + Copy>(ours: &[T], theirs: &[T]) -> Vec<T> {
+            ours.iter().filter(|t| theirs.contains(t)).copied().collect()
+        }
+        Self{
+            compression: common(&self.compression, &other.compression),
+            hashes: common(&self.hashes, &other.hashes),
+            encryption: common(&self.encryption, &other.encryption),
+            resume: common(&self.resume, &other.resume),
+            transfer: common(&self.transfer, &other.transfer),
+        }
+    }
+}
+
+impl RejectionCode {
+    /// Exit code of the CLI, when rejected with this code
+    pub fn exit_code(self) -> u8 {
+        match self {
+            RejectionCode::WrongVersion => 10,
+            RejectionCode::WrongKey => 11,
+            RejectionCode::ServerFull => 12,
+            RejectionCode::Forbidden => 13,
+            RejectionCode::QuotaExceeded => 14,
+            RejectionCode::ServerError => 15,
+            RejectionCode::Unknown => 19,
+        }
+    }
+}
+
+impl std::fmt::Display for RejectionCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RejectionCode::WrongVersion => "Incompatible version",
+            RejectionCode::WrongKey => "Wrong encryption key",
+            RejectionCode::ServerFull => "Server is full",
+            RejectionCode::Forbidden => "Forbidden",
+            RejectionCode::QuotaExceeded => "Quota exceeded",
+            RejectionCode::ServerError => "Server error",
+            RejectionCode::Unknown => "Unknown reason",
+        })
+    }
+}
+
+impl Rejection {
+    pub fn new<S: Into<String>>(code: RejectionCode, message: S) -> Self {
+        Self{ code, message: message.into() }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
 }
 
+impl std::error::Error for Rejection {}
+
 
 impl From<u128> for FileHash {
     fn from(hash: u128) -> Self {
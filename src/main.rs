@@ -1,8 +1,8 @@
 use clap::Parser;
 
-mod server;
-mod client;
-mod cli;
+use simple_mass_data_transfer::{cli, bench::{Benchmark, Sample}, buffered_io::ZstdParams, client::{Client, DownloadLimits, RetryPolicy}, server::{Dictionary, Server}};
+use std::time::Duration;
+
 #[cfg(feature = "gui")]
 mod ui;
 
@@ -11,33 +11,90 @@ fn main() -> anyhow::Result<()> {
     let args = std::env::args_os();
     if args.len() > 1 {
         let args = cli::Args::parse_from(args);
+        let socket_options = args.socket_options();
         
         match args.action {
-            cli::Action::Download{ .. } => {
+            cli::Action::Download{ address, path, max_size, max_files, preallocate, max_window_log, retries, retry_delay, timeout, limit_rate, pipeline } => {
+                let client = Client::new(address, path)
+                    .with_compression(args.compression)
+                    .with_encryption_key(args.encryption_key)
+                    .with_limits(DownloadLimits{ max_size: max_size.map(|s| s.as_u64()), max_files, preallocate, max_window_log })
+                    .with_retry_policy(RetryPolicy{ max_attempts: retries, initial_backoff: Duration::from_secs_f64(retry_delay), ..Default::default() })
+                    .with_timeout((timeout > 0).then(|| Duration::from_secs(timeout)))
+                    .with_rate_limit(limit_rate.map(|rate| rate.as_u64()))
+                    .with_socket_options(socket_options)
+                    .with_pipeline(pipeline);
+                
                 let (tx, rx) = std::sync::mpsc::channel();
                 // start printing thread
                 let handle = std::thread::spawn(|| simple_mass_data_transfer::client_events::handle_events_cli(rx));
                 // connect
-                client::connect(args, tx)?;
+                let result = client.download(tx);
+                handle.join().expect("CLI-Handler panicked, please report bug.")?;
+                // rejections get their own exit code
+                if let Err(e) = result {
+                    if let Some(code) = e.exit_code() {
+                        eprintln!("Error: {e}");
+                        std::process::exit(code.into())
+                    }
+                    return Err(e.into())
+                }
+            },
+            cli::Action::Host{ bind_address, paths, comp_level, codec, dictionary, train_dictionary, comp_workers, long_window, idle_timeout, max_clients, backlog, memory_budget, max_rate, total_rate, pipeline } => {
+                let server = Server::new(bind_address)
+                    .with_paths(paths)
+                    .with_compression(args.compression, comp_level)
+                    .with_codec(codec)
+                    .with_dictionary(match (dictionary, train_dictionary) {
+                        (Some(path), _) => Dictionary::File(path),
+                        (None, true) => Dictionary::Trained,
+                        (None, false) => Dictionary::None
+                    })
+                    .with_zstd_params(ZstdParams{ workers: comp_workers, long_window })
+                    .with_encryption_key(args.encryption_key)
+                    .with_idle_timeout(Duration::from_secs(idle_timeout))
+                    .with_max_clients(max_clients.map(|max| max as usize), backlog)
+                    .with_memory_budget(memory_budget.map(|budget| budget.as_u64()))
+                    .with_rate_limits(max_rate.map(|rate| rate.as_u64()), total_rate.map(|rate| rate.as_u64()))
+                    .with_socket_options(socket_options)
+                    .with_pipeline(pipeline);
+                
+                let (tx, rx) = std::sync::mpsc::channel();
+                // start printing thread
+                let handle = std::thread::spawn(|| simple_mass_data_transfer::server_events::handle_events_cli(rx));
+                // serve
+                server.serve(tx)?;
                 handle.join().expect("CLI-Handler panicked, please report bug.")?;
             },
-            cli::Action::Host{ .. } => {
-                server::serve(args)?
+            cli::Action::Bench{ path, size, codec, levels, links, no_loopback } => {
+                let benchmark = Benchmark::new(path.map_or(Sample::Synthetic, Sample::Files))
+                    .with_size(size.as_u64())
+                    .with_levels(codec, levels)
+                    .with_links(links.into_iter().map(|link| link.as_u64()).collect())
+                    .with_loopback(!no_loopback);
+
+                let (tx, rx) = std::sync::mpsc::channel();
+                // start printing thread
+                let handle = std::thread::spawn(|| simple_mass_data_transfer::bench_events::handle_events_cli(rx));
+                // measure
+                let result = benchmark.run(tx);
+                handle.join().expect("CLI-Handler panicked, please report bug.")?;
+                result?;
             }
         }
     }
     else {
         #[cfg(feature = "gui")]
         {
-            println!("Starting Client-GUI. To use CLI, please add arguments (--help)");
+            println!("Starting GUI. To use CLI, please add arguments (--help)");
             eframe::run_native(
-                "SMD-Client",
+                "SMD-Transfer",
                 eframe::NativeOptions::default(),
-                Box::new(|_cc| Box::<ui::ClientUi>::default())
+                Box::new(|_cc| Box::<ui::SmdUi>::default())
             ).expect("Could not create GUI.");
         }
         #[cfg(not(feature = "gui"))]
-        println!("Client-GUI Feature not enabled. Please add at least one argument! (--help)");
+        println!("GUI Feature not enabled. Please add at least one argument! (--help)");
     }
     
     Ok(())
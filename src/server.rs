@@ -1,176 +1,796 @@
 use walkdir::WalkDir;
-use once_cell::sync::Lazy;
 use rmp_serde::{Serializer, Deserializer};
 use serde::{Serialize, Deserialize};
-use bytesize::ByteSize;
 use chacha20poly1305::KeyInit;
 
-use std::net;
 use std::collections::HashMap;
 use std::io;
-use std::sync::{Arc, RwLock};
+use std::io::{Read, Seek, Write};
+use std::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::Sender;
 use std::path::{Path, PathBuf};
 
-use crate::cli::Action;
-use simple_mass_data_transfer::{EntryHeader::{FileHeader, DirHeader}, FileHash, FileHashResponse, Handshake, HandshakeResponse};
-use simple_mass_data_transfer::buffered_io::{PerhapsHashingWriter, PerhapsCompressedWriter, PerhapsEncrWriter, encrypt_io::{prepare_key, maybe_encrypt_path}, CountingWriter};
+use crate::{EntryHeader::{FileHeader, DirHeader, SolidHeader}, FileHash, FileHashResponse, Handshake, HandshakeResponse, Rejection, RejectionCode};
+use crate::{CompressionAlgorithm, SmdError};
+use crate::buffered_io::{PerhapsHashingWriter, PerhapsCompressedWriter, PerhapsEncrWriter, encrypt_io::{prepare_key, maybe_encrypt_path}, comp_io::{worth_compressing, SAMPLE_SIZE}, ZstdParams, CountingWriter, FramedWriter, RateLimiter, RateLimitedWriter, VectoredBufWriter};
+#[cfg(target_os = "linux")]
+use crate::buffered_io::zero_copy::{self, SendFile};
+use crate::server_events::{ServerEvent, ServerEventWriter};
+use crate::control::TransferControl;
+use crate::transport::{self, Listener, PeerAddr, SocketOptions, Transport};
+
+mod dictionary;
+mod pipeline;
+mod pool;
+mod queue;
+mod session;
+mod tuning;
+#[cfg(feature = "async")]
+mod tokio_server;
+
+use pool::{WorkerPool, MemoryBudget};
+use queue::WaitingRoom;
+use session::{Session, Step};
+use tuning::LevelTuner;
 
 
 // type definitions for simplification
-type StaticHashmap<K, V> = Lazy<Arc<RwLock<HashMap<K, V>>>>;
+type HashCache = Arc<RwLock<HashMap<Arc<PathBuf>, (u128, std::time::SystemTime)>>>;
 type PathVec = Vec<(Arc<PathBuf>, Arc<Path>)>;
 
-// statics
-static HASH_CASH: StaticHashmap<Arc<PathBuf>, (u128, std::time::SystemTime)> = Lazy::new(|| Arc::new(RwLock::new(HashMap::new())));
-static FILES: Lazy<Arc<RwLock<PathVec>>> = Lazy::new(|| Arc::new(RwLock::new(Vec::new())));
+/// How long to wait between checking for new clients
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long to wait after accepting failed (e.g. while out of file descriptors)
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+/// Clients turned away at once (further ones are disconnected right away),
+/// and how long each may take to send its handshake and receive the rejection
+const REJECT_WORKERS: usize = 4;
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// Files up to this size are batched into solid streams
+const SOLID_MAX_FILE_SIZE: u64 = 1 << 20;
+/// Content of one solid stream (about), to keep retries and losses on reconnects small
+const SOLID_MAX_SIZE: u64 = 16 << 20;
+const SOLID_MAX_ENTRIES: usize = 4096;
 
-/// Start serving the `args`
-pub fn serve(args: crate::cli::Args) -> anyhow::Result<()> {
-    // extract compression level
-    let comp_level = if let Action::Host { comp_level, .. } = args.action {
-        comp_level
-    } else { panic!("this should not happen") };
-    
-    // collect all files
-    if let Action::Host{ path, .. } = &args.action {
-        for path in glob::glob(path)?.filter_map(|p| p.ok()) {
+
+/// What happens to clients arriving while the server is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum BacklogPolicy {
+    /// Wait for a free slot
+    #[default]
+    Queue,
+    /// Turn them away, as the server is full
+    Reject
+}
+
+
+/// The zstd level a [`Server`] compresses with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionLevel {
+    Fixed(u8),
+    /// Adjusted between files to each client's connection,
+    /// as high as possible without holding it back
+    Auto
+}
+
+impl CompressionLevel {
+    /// The highest level that may be used
+    pub fn max(self) -> u8 {
+        match self {
+            Self::Fixed(level) => level,
+            Self::Auto => *tuning::AUTO_LEVELS.end()
+        }
+    }
+}
+
+impl From<u8> for CompressionLevel {
+    fn from(level: u8) -> Self {
+        Self::Fixed(level)
+    }
+}
+
+impl std::str::FromStr for CompressionLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto)
+        }
+        match s.parse() {
+            Ok(level @ 1..=21) => Ok(Self::Fixed(level)),
+            _ => Err(format!("'{s}' is neither 'auto' nor a level between 1 and 21"))
+        }
+    }
+}
+
+impl std::fmt::Display for CompressionLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fixed(level) => write!(f, "{level}"),
+            Self::Auto => write!(f, "auto")
+        }
+    }
+}
+
+
+/// The zstd dictionary a [`Server`] compresses with, sent to every client using compression.
+/// Dictionaries help most with many small files, which hardly compress on their own.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Dictionary {
+    #[default]
+    None,
+    /// Trained from a sample of the hosted files (cached in the user's cache directory, by the content of the sample)
+    Trained,
+    /// Loaded from a file, as written by `zstd --train`
+    File(PathBuf)
+}
+
+
+/// Handles to the rate limits of a [`Server`], adjustable while serving.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    /// Every client has its own bucket, following this rate
+    pub per_client: RateLimiter,
+    /// Shared by all clients
+    pub total: RateLimiter,
+}
+
+
+/// Hosts files and directories to any amount of clients.
+///
+/// ```no_run
+/// use simple_mass_data_transfer::server::Server;
+///
+/// let (send, recv) = std::sync::mpsc::channel();
+/// let server = Server::new("0.0.0.0:4444")
+///     .with_paths(["/path/to/some/directory/"])
+///     .with_compression(true, 3);
+/// // server.control().cancel() stops it from another thread
+/// server.serve(send).unwrap();
+/// ```
+pub struct Server {
+    bind_address: String,
+    /// Glob patterns of the files and directories to host
+    paths: Vec<String>,
+    /// Forced onto every client
+    compression: bool,
+    comp_level: CompressionLevel,
+    /// Used with clients supporting it, others get zstd
+    codec: CompressionAlgorithm,
+    dictionary: Dictionary,
+    zstd_params: ZstdParams,
+    encryption_key: Option<String>,
+    idle_timeout: Duration,
+    /// Clients served at once (None for unlimited)
+    max_clients: Option<usize>,
+    backlog: BacklogPolicy,
+    /// in Bytes (None for unlimited)
+    memory_budget: Option<u64>,
+    rate_limits: RateLimits,
+    socket_options: SocketOptions,
+    /// Whether files are read, hashed, compressed, encrypted and sent on threads of their own
+    pipeline: bool,
+    control: TransferControl,
+    /// Hashes of already sent files, with the modification time they belong to
+    hash_cache: HashCache,
+}
+
+/// Everything the client threads share
+struct Shared {
+    files: PathVec,
+    total_size: u64,
+    compression: bool,
+    comp_level: CompressionLevel,
+    codec: CompressionAlgorithm,
+    dictionary: Option<Vec<u8>>,
+    zstd_params: ZstdParams,
+    key: Option<String>,
+    hash_cache: HashCache,
+    memory: MemoryBudget,
+    rate_limits: RateLimits,
+    pipeline: bool,
+    control: TransferControl,
+}
+
+
+impl Server {
+    pub fn new<S: Into<String>>(bind_address: S) -> Self {
+        Self{
+            bind_address: bind_address.into(),
+            paths: Vec::new(),
+            compression: false,
+            comp_level: CompressionLevel::Fixed(15),
+            codec: CompressionAlgorithm::Zstd,
+            dictionary: Dictionary::None,
+            zstd_params: ZstdParams::default(),
+            encryption_key: None,
+            idle_timeout: Duration::from_secs(60),
+            max_clients: None,
+            backlog: BacklogPolicy::default(),
+            memory_budget: None,
+            rate_limits: RateLimits::default(),
+            socket_options: SocketOptions::default(),
+            pipeline: false,
+            control: TransferControl::new(),
+            hash_cache: Default::default(),
+        }
+    }
+
+    /// Adds files and directories to host, also supporting glob patterns like `*`
+    pub fn with_paths<I: IntoIterator<Item = S>, S: Into<String>>(mut self, paths: I) -> Self {
+        self.paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Whether compression is forced onto every client (clients can still request it),
+    /// and the zstd `level` used (a number or [`CompressionLevel::Auto`]).
+    pub fn with_compression<L: Into<CompressionLevel>>(mut self, forced: bool, level: L) -> Self {
+        self.compression = forced;
+        self.comp_level = level.into();
+        self
+    }
+
+    /// The algorithm to compress with, for clients supporting it (others get zstd, at the same level).
+    /// Dictionaries and [`ZstdParams`] only apply to zstd.
+    pub fn with_codec(mut self, codec: CompressionAlgorithm) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// The zstd dictionary to compress with
+    pub fn with_dictionary(mut self, dictionary: Dictionary) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+
+    /// Worker threads compressing for each client (needs the `zstdmt` feature)
+    /// and long-distance matching, which help with huge files.
+    /// Long-distance matching can not be combined with a dictionary.
+    pub fn with_zstd_params(mut self, params: ZstdParams) -> Self {
+        self.zstd_params = params;
+        self
+    }
+
+    /// If some, everything is encrypted with the `key`
+    pub fn with_encryption_key(mut self, key: Option<String>) -> Self {
+        self.encryption_key = key;
+        self
+    }
+
+    /// After which a silent client is disconnected
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Serve at most `max` clients at once (on as many threads),
+    /// treating further ones according to `backlog`.
+    pub fn with_max_clients(mut self, max: Option<usize>, backlog: BacklogPolicy) -> Self {
+        self.max_clients = max;
+        self.backlog = backlog;
+        self
+    }
+
+    /// Memory (in Bytes) all clients share for compression and buffers.
+    /// Clients are rejected as [`RejectionCode::ServerFull`] once it is exhausted.
+    pub fn with_memory_budget(mut self, budget: Option<u64>) -> Self {
+        self.memory_budget = budget;
+        self
+    }
+
+    /// Bytes per second sent to each client and to all of them together (None for unlimited).
+    pub fn with_rate_limits(self, per_client: Option<u64>, total: Option<u64>) -> Self {
+        self.rate_limits.per_client.set_rate(per_client);
+        self.rate_limits.total.set_rate(total);
+        self
+    }
+
+    /// Socket buffer sizes, TCP_NODELAY and the congestion control algorithm of every connection
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Read, hash, compress, encrypt and send each file on threads of their own (only [`Server::serve`]).
+    /// Speeds up clients whose transfer is limited by the CPU, at the cost of about 1.5 MB and up to 4 more threads per client.
+    pub fn with_pipeline(mut self, pipeline: bool) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// Handles to change the rate limits while serving.
+    pub fn rate_limits(&self) -> RateLimits {
+        self.rate_limits.clone()
+    }
+
+    /// Handle to stop serving (cancelling), from any thread.
+    pub fn control(&self) -> TransferControl {
+        self.control.clone()
+    }
+
+    /// Start serving on the bind address, until stopped through [`Server::control`].
+    pub fn serve(&self, handler: Sender<ServerEvent>) -> Result<(), SmdError> {
+        let listener = transport::bind(&self.bind_address).map_err(|e| SmdError::Other(e.into()))?;
+        self.serve_on(listener, handler)
+    }
+
+    /// Start serving clients from `listener` (instead of the bind address),
+    /// until stopped through [`Server::control`].
+    pub fn serve_on(&self, listener: Box<dyn Listener>, handler: Sender<ServerEvent>) -> Result<(), SmdError> {
+        Ok(self.serve_clients(listener, handler)?)
+    }
+
+    fn serve_clients(&self, listener: Box<dyn Listener>, handler: Sender<ServerEvent>) -> anyhow::Result<()> {
+        // (rather than failing every client)
+        listener.tune(&self.socket_options)?;
+        let shared = Arc::new(self.prepare()?);
+        handler.send(ServerEvent::Listening{
+            address: listener.local_addr(),
+            total_size: shared.total_size,
+            dictionary: shared.dictionary.as_ref().map(Vec::len)
+        })?;
+
+        // main loop
+        let idle_timeout = self.idle_timeout;
+        let clients: Arc<Mutex<HashMap<PeerAddr, Box<dyn Transport>>>> = Default::default();
+        let pool = WorkerPool::new(self.max_clients);
+        let rejecting = WorkerPool::new(Some(REJECT_WORKERS));
+        let room = WaitingRoom::new(self.control.clone(), handler.clone());
+        while !self.control.is_cancelled() {
+            // accept client
+            let (client, socket) = match listener.accept() {
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => {
+                    std::thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue
+                },
+                // (which might pass, so keep serving)
+                Err(e) => {
+                    handler.send(ServerEvent::AcceptFailed{ error: e.to_string(), retry_in: ACCEPT_ERROR_BACKOFF })?;
+                    std::thread::sleep(ACCEPT_ERROR_BACKOFF);
+                    continue
+                }
+            };
+            handler.send(ServerEvent::ClientConnected(socket))?;
+            // (only this client is lost if it can not be prepared)
+            let prepared = client.set_read_timeout(Some(idle_timeout))
+                .and_then(|()| client.set_write_timeout(Some(idle_timeout)))
+                .and_then(|()| client.tune(&self.socket_options))
+                .and_then(|()| client.try_clone_boxed());
+            match prepared {
+                Ok(clone) => { clients.lock().unwrap().insert(socket, clone); },
+                Err(e) => {
+                    handler.send(ServerEvent::ClientFailed{ client: socket, error: e.to_string() })?;
+                    continue
+                }
+            }
+
+            // all workers are busy (None while the client waits)
+            let client = if pool.is_full() {
+                match self.backlog {
+                    BacklogPolicy::Queue => match room.enter(client, socket) {
+                        Ok(waiting) => {
+                            handler.send(ServerEvent::ClientQueued{ client: socket, waiting })?;
+                            None
+                        },
+                        Err(e) => {
+                            clients.lock().unwrap().remove(&socket);
+                            handler.send(ServerEvent::ClientFailed{ client: socket, error: e.to_string() })?;
+                            continue
+                        }
+                    },
+                    // (disconnected without an answer, while enough clients are being turned away)
+                    BacklogPolicy::Reject if rejecting.is_full() => {
+                        let _ = client.shutdown();
+                        clients.lock().unwrap().remove(&socket);
+                        handler.send(ServerEvent::ClientFailed{ client: socket, error: "Disconnected, the server is full".into() })?;
+                        continue
+                    },
+                    BacklogPolicy::Reject => {
+                        let (handler, panic_handler) = (handler.clone(), handler.clone());
+                        let (clients, panic_clients) = (clients.clone(), clients.clone());
+                        rejecting.execute(move || {
+                            let rejection = Rejection::new(RejectionCode::ServerFull, "The server is serving its maximum of clients");
+                            let rejected = client.set_read_timeout(Some(REJECT_TIMEOUT))
+                                .and_then(|()| client.set_write_timeout(Some(REJECT_TIMEOUT)))
+                                .map_err(anyhow::Error::from)
+                                .and_then(|()| reject_client(client, rejection));
+                            if let Err(e) = rejected {
+                                let _ = handler.send(ServerEvent::ClientFailed{ client: socket, error: format!("{e:#}") });
+                            }
+                            clients.lock().unwrap().remove(&socket);
+                        }, move |panic| {
+                            let _ = panic_handler.send(ServerEvent::ClientFailed{ client: socket, error: format!("Handler panicked: {panic}") });
+                            panic_clients.lock().unwrap().remove(&socket);
+                        });
+                        continue
+                    }
+                }
+            } else {
+                Some(client)
+            };
+
+            // start handler (once a worker is free)
+            let shared = shared.clone();
+            let room = room.clone();
+            let (handler, panic_handler) = (handler.clone(), handler.clone());
+            let (clients, panic_clients) = (clients.clone(), clients.clone());
+            pool.execute(move || {
+                let result = match client {
+                    Some(client) => handle_client(client, None, socket, &shared, &handler),
+                    // (unless it has hung up while waiting)
+                    None => match room.leave(socket, idle_timeout) {
+                        Some(waited) => waited.and_then(|(client, handshake)| handle_client(client, Some(handshake), socket, &shared, &handler)),
+                        None => Ok(())
+                    }
+                };
+                match result {
+                    Err(e) if is_timeout(&e) => {
+                        let _ = handler.send(ServerEvent::ClientSilent{ client: socket, timeout: idle_timeout });
+                    },
+                    Err(e) => {
+                        let _ = handler.send(ServerEvent::ClientFailed{ client: socket, error: format!("{e:#}") });
+                    },
+                    Ok(()) => ()
+                }
+                clients.lock().unwrap().remove(&socket);
+            }, move |panic| {
+                let _ = panic_handler.send(ServerEvent::ClientFailed{ client: socket, error: format!("Handler panicked: {panic}") });
+                panic_clients.lock().unwrap().remove(&socket);
+            });
+        }
+
+        // disconnect remaining clients
+        for client in clients.lock().unwrap().values() {
+            let _ = client.shutdown();
+        }
+
+        Ok(())
+    }
+
+    /// Collects all files and the dictionary, into what the clients share
+    fn prepare(&self) -> anyhow::Result<Shared> {
+        // collect all files
+        let files = collect_files(&self.paths)?;
+
+        // calculate total size
+        let total_size: u64 = files.iter()
+            .filter(|p| p.0.is_file())
+            .filter_map(|p| p.0.metadata().ok().map(|p| p.len()))
+            .sum();
+
+        // train or load dictionary
+        let dictionary = match &self.dictionary {
+            Dictionary::None => None,
+            Dictionary::Trained => dictionary::trained(&files)?,
+            Dictionary::File(path) => {
+                let dictionary = std::fs::read(path).map_err(SmdError::io(path))?;
+                // reject broken dictionaries now, instead of failing every client
+                zstd::Encoder::with_dictionary(io::sink(), 1, &dictionary).map_err(SmdError::io(path))?;
+                Some(dictionary)
+            }
+        };
+        // check the zstd parameters once, rather than failing every client
+        if dictionary.is_some() && self.zstd_params.long_window.is_some() {
+            anyhow::bail!("A dictionary can not be combined with long-distance matching")
+        }
+        PerhapsCompressedWriter::with_params(io::sink(), Some(CompressionAlgorithm::Zstd), 1, None, &self.zstd_params)?;
+        if self.codec == CompressionAlgorithm::Unknown {
+            anyhow::bail!("Can not compress with an unknown algorithm")
+        }
+
+        Ok(Shared{
+            files,
+            total_size,
+            compression: self.compression,
+            comp_level: self.comp_level,
+            codec: self.codec,
+            dictionary,
+            zstd_params: self.zstd_params,
+            key: self.encryption_key.clone(),
+            hash_cache: self.hash_cache.clone(),
+            memory: MemoryBudget::new(self.memory_budget),
+            rate_limits: self.rate_limits.clone(),
+            pipeline: self.pipeline,
+            control: self.control.clone(),
+        })
+    }
+}
+
+
+/// Resolves the glob `patterns` into (absolute path, path relative to the pattern's parent) pairs,
+/// recursing over directories.
+fn collect_files(patterns: &[String]) -> anyhow::Result<PathVec> {
+    let mut files = PathVec::new();
+    for pattern in patterns {
+        for path in glob::glob(pattern)?.filter_map(|p| p.ok()) {
             let prefix = path.parent().unwrap_or(Path::new("/"));
-            
+
             // recurse over directories
             if path.is_dir() {
-                FILES.write().unwrap().extend(
+                files.extend(
                     WalkDir::new(&path).into_iter()
                         .filter_map(|p| p.ok())
                         .map(|p| p.path().to_path_buf())
                         .map(|p| (Arc::new(p.canonicalize().unwrap()), Arc::from(p.strip_prefix(prefix).unwrap())))
                 )
             } else {
-                FILES.write().unwrap().push(
+                files.push(
                     (Arc::new(path.canonicalize().unwrap()), Arc::from(path.strip_prefix(prefix).unwrap()))
                 );
             }
         }
     }
-    
-    // calculate total size
-    let total_size: u64 = FILES.read().unwrap().iter()
-        .filter(|p| p.0.is_file())
-        .filter_map(|p| p.0.metadata().ok().map(|p| p.len()))
-        .sum();
-    println!("total size: {}", ByteSize(total_size));
-
-    // create listener
-    let listener = if let Action::Host { bind_address, .. } = &args.action {
-        println!("Binding to {bind_address}...");
-        net::TcpListener::bind(bind_address)?
-    }
-    else { panic!("this should not happen?") };
-
-    // main loop
-    let key = Arc::new(args.encryption_key);
-    loop {
-        // accept client
-        let (client, socket) = listener.accept()?;
-        println!("Client arrived: {socket}");
-        
-        // start handle thread
-        let key = key.clone();
-        std::thread::spawn(move || { handle_client(client, args.compression, total_size, key, comp_level) });
-    }
+    Ok(files)
 }
 
 
-fn handle_client(stream: net::TcpStream, compression: bool, total_size: u64, key: Arc<Option<String>>, comp_level: u8) -> anyhow::Result<()> {
-    let mut deserializer = Deserializer::new(&stream);
-    let mut serializer = Serializer::new(&stream);
-    let mut total_sent = 0;
+/// Serves a client, whose `handshake` might have been received while it waited
+fn handle_client(stream: Box<dyn Transport>, handshake: Option<Handshake>, socket: PeerAddr, shared: &Arc<Shared>, handler: &Sender<ServerEvent>) -> anyhow::Result<()> {
+    let mut deserializer = Deserializer::new(io::BufReader::new(stream.try_clone_boxed()?));
+    // (flushed whenever waiting for the client)
+    let mut serializer = Serializer::new(VectoredBufWriter::new(stream));
     
     // receive Handshake
-    let handshake = Handshake::deserialize(&mut deserializer)?;
-    println!("Client sent handshake: {handshake:?}");
-    if env!("CARGO_PKG_VERSION") != handshake.version {
-        println!("Invalid version of client!");
-        return Ok(()) 
-    }
-    let compression = compression | handshake.compression;
+    let handshake = match handshake {
+        Some(handshake) => handshake,
+        None => Handshake::deserialize(&mut deserializer)?
+    };
     
     // create encryptor
-    let mut encryptor = key.as_ref().as_ref().map(|key|
+    let mut encryptor = shared.key.as_ref().map(|key|
         chacha20poly1305::ChaCha20Poly1305::new(&prepare_key(key))
     );
     
-    // send reply
-    HandshakeResponse{
-        total_size,
-        compression
-    }.serialize(&mut serializer)?;
+    // accept or reject client
+    let pipeline_memory = if shared.pipeline { pipeline::MEMORY } else { 0 };
+    let (response, session) = Session::start(shared, handshake, &encryptor, pipeline_memory, socket, handler)?;
+    response.serialize(&mut serializer)?;
+    serializer.get_mut().flush()?;
+    let (mut session, mut schedule) = session?;
+    let heartbeat_interval = session.heartbeat_interval;
     
     // main loop
-    let start = std::time::Instant::now();
-    for (abs_path, rel_path) in FILES.read().unwrap().iter() {
-        let path_bytes = maybe_encrypt_path(rel_path, &mut encryptor)?;
-        let metadata = abs_path.metadata()?;
+    while let Some(step) = schedule.next_step()? {
+        let (index, metadata, first_attempt) = match step {
+            Step::Entry{ index, metadata, first_attempt } => (index, metadata, first_attempt),
+            // send small files and directories in one solid stream
+            Step::Solid(batch) => {
+                SolidHeader{ entries: batch.len() as u32, compressed: session.compression }.serialize(&mut serializer)?;
+                let batch_start = std::time::Instant::now();
+
+                // wrap stream into rate limiter, Byte counter, frames and encryptor
+                let writer = CountingWriter::new(RateLimitedWriter::new(serializer.get_mut(), &session.limiters));
+                let writer = PerhapsEncrWriter::with_encryptor(FramedWriter::new(writer), &mut encryptor);
+                // into compressor, shared by all entries
+                let writer = PerhapsCompressedWriter::with_params(writer, session.codec, session.level(), session.dictionary, &session.params)?;
+                // and into a buffer, as the entries are written in small pieces
+                let mut entries = io::BufWriter::with_capacity(1 << 13, writer);
+
+                let mut sent = Vec::new();
+                let mut raw = 0;
+                for entry in &batch {
+                    let (abs_path, rel_path) = &shared.files[entry.index];
+                    // the path is encrypted along with the stream
+                    let path = maybe_encrypt_path(rel_path, &mut None);
+                    let Some(metadata) = &entry.file else {
+                        DirHeader{ path }.serialize(&mut Serializer::new(&mut entries))?;
+                        continue
+                    };
+
+                    // send header
+                    FileHeader{ path, size: metadata.len(), compressed: None }.serialize(&mut Serializer::new(&mut entries))?;
+                    handler.send(ServerEvent::FileStarted{ client: socket, rel_path: rel_path.to_string_lossy().into(), size: metadata.len() })?;
+
+                    // send exactly the announced size, the client relies on it to find the next entry
+                    let file = std::fs::File::open(abs_path.as_path()).map_err(SmdError::io(abs_path.as_path()))?;
+                    let mut writer = ServerEventWriter::new(PerhapsHashingWriter::with_hash(&mut entries, entry.hash), socket, handler);
+                    let copied = io::copy(&mut file.take(metadata.len()), &mut writer).map_err(SmdError::io(abs_path.as_path()))?;
+                    if copied < metadata.len() {
+                        return Err(SmdError::io(abs_path.as_path())(io::ErrorKind::UnexpectedEof.into()).into())
+                    }
+                    raw += copied;
+
+                    // send Hash
+                    let (_, hash) = writer.inner().finalize();
+                    FileHash{ hash }.serialize(&mut Serializer::new(&mut entries))?;
+                    sent.push(entry.index);
+                    // cache hash
+                    if entry.hash.is_none() {
+                        shared.cache_hash(abs_path, hash, metadata)?;
+                    }
+
+                    // the compressor might not have produced any output for a while
+                    if let Some(interval) = heartbeat_interval {
+                        entries.get_mut().get_mut().get_mut().heartbeat_if_idle(interval)?;
+                    }
+                }
+
+                // finish compression and frames, and add sent bytes
+                let compressor = entries.into_inner().map_err(io::IntoInnerError::into_error)?;
+                let counter = compressor.finish()?.into_inner().finish()?;
+                session.stream_sent(raw, counter.written as u64, counter.busy, batch_start.elapsed(), session.compression)?;
+
+                // handle responses of client, one per file
+                serializer.get_mut().flush()?;
+                let responses = Vec::<FileHashResponse>::deserialize(&mut deserializer)?;
+                session.solid_checked(&mut schedule, sent, responses)?;
+                continue
+            }
+        };
+        let (abs_path, rel_path) = &shared.files[index];
+        let path_bytes = maybe_encrypt_path(rel_path, &mut encryptor);
  
         // send dir
         if metadata.is_dir() {
             DirHeader{ path: path_bytes }.serialize(&mut serializer)?;
         }
         // send file
-        else { loop {
+        else { for attempt in first_attempt.. {
             // check for cached hash
-            let hash = HASH_CASH.read().unwrap().get(abs_path)
-                .filter(|(_, modified)| modified == &metadata.modified().expect("FUCK MAN, why are u using an OS without modified metadata????"))
-                .map(|(h, _)| *h);
+            let hash = shared.cached_hash(abs_path, &metadata);
             let precomputed_hash = hash.is_some();
 
             // check in Resume List
-            if let Some(hash) = hash {
-                if let Some(resume_list) = &handshake.resume_list {
-                    if resume_list.get(&hash.into()).is_some() {
-                        break
-                    }
-                }
+            if hash.is_some_and(|hash| schedule.is_resumed(hash)) {
+                break
             }
             
-            // send header
-            FileHeader{ path: path_bytes.clone(), size: metadata.len() }.serialize(&mut serializer)?;
-
-            // wrap stream into Byte counter and encryptor
-            let writer = PerhapsEncrWriter::with_encryptor(CountingWriter::new(&stream), &mut encryptor);
-            // into compressor
-            let writer = PerhapsCompressedWriter::with_compression(writer, compression, comp_level);
-            // and into hasher
-            let mut writer = PerhapsHashingWriter::with_hash(writer, hash);
-            
             // open file
             let mut file = std::fs::OpenOptions::new().read(true)
-                .open(abs_path.as_path())?;
-            // send file
-            io::copy(&mut file, &mut writer)?;
-
-            // get hash
-            let (compressor, hash) = writer.finalize();
-            // finish compression and add sent bytes
-            total_sent += compressor.finish()?.into_inner().written;
+                .open(abs_path.as_path())
+                .map_err(SmdError::io(abs_path.as_path()))?;
+            // skip compressing incompressible files (older clients expect every file compressed)
+            let file_compression = session.compression && (!session.per_file_compression()
+                || sample_compressibility(abs_path, &mut file).map_err(SmdError::io(abs_path.as_path()))?);
+
+            // send header
+            FileHeader{
+                path: path_bytes.clone(),
+                size: metadata.len(),
+                compressed: session.per_file_compression().then_some(file_compression)
+            }.serialize(&mut serializer)?;
+            handler.send(ServerEvent::FileStarted{ client: socket, rel_path: rel_path.to_string_lossy().into(), size: metadata.len() })?;
+            let file_start = std::time::Instant::now();
+
+            // neither compressed nor encrypted, the file can go straight from the page cache into the socket
+            #[cfg(target_os = "linux")]
+            let zero_copy = !file_compression && encryptor.is_none() && zero_copy::supported(serializer.get_ref().get_ref().as_ref());
+            #[cfg(not(target_os = "linux"))]
+            let zero_copy = false;
+
+            // wrap stream into rate limiter, Byte counter and frames
+            let writer = FramedWriter::new(CountingWriter::new(RateLimitedWriter::new(serializer.get_mut(), &session.limiters)));
+            let codec = session.codec.filter(|_| file_compression);
+            let (counter, hash, raw) = match zero_copy {
+                #[cfg(target_os = "linux")]
+                true => send_file_zero_copy(writer, &file, metadata.len(), hash, socket, handler)?,
+                _ if shared.pipeline => {
+                    let stream = pipeline::FileStream{
+                        codec,
+                        level: session.level(),
+                        dictionary: session.dictionary,
+                        params: &session.params,
+                        hash,
+                        heartbeat_interval
+                    };
+                    pipeline::send_file(file, abs_path, stream, &mut encryptor, writer, socket, handler)?
+                },
+                _ => {
+                    // into encryptor
+                    let writer = PerhapsEncrWriter::with_encryptor(writer, &mut encryptor);
+                    // into compressor
+                    let writer = PerhapsCompressedWriter::with_params(writer, codec, session.level(), session.dictionary, &session.params)?;
+                    // into hasher
+                    let writer = PerhapsHashingWriter::with_hash(writer, hash);
+                    // and into event sender
+                    let mut writer = ServerEventWriter::new(writer, socket, handler);
+
+                    // send file
+                    let mut buffer = [0u8; 1 << 13];
+                    let mut raw = 0;
+                    loop {
+                        let read = file.read(&mut buffer).map_err(SmdError::io(abs_path.as_path()))?;
+                        if read == 0 {
+                            break
+                        }
+                        writer.write_all(&buffer[..read])?;
+                        raw += read as u64;
+
+                        // the compressor might not have produced any output for a while
+                        if let Some(interval) = heartbeat_interval {
+                            writer.get_mut().get_mut().get_mut().get_mut().heartbeat_if_idle(interval)?;
+                        }
+                    }
+
+                    // get hash
+                    let (compressor, hash) = writer.inner().finalize();
+                    // finish compression and frames
+                    (compressor.finish()?.into_inner().finish()?, hash, raw)
+                }
+            };
+            // add sent bytes (the time not spent waiting for the connection went to reading and compressing)
+            session.stream_sent(raw, counter.written as u64, counter.busy, file_start.elapsed(), file_compression)?;
             
             // send Hash
             FileHash{ hash }.serialize(&mut serializer)?;
             // cache hash
             if !precomputed_hash {
-                HASH_CASH.write().unwrap().insert(abs_path.clone(), (hash, metadata.modified()?));
+                shared.cache_hash(abs_path, hash, &metadata)?;
             }
 
             // handle response of client
-            if FileHashResponse::deserialize(&mut deserializer)?.matches {
+            serializer.get_mut().flush()?;
+            if session.file_checked(FileHashResponse::deserialize(&mut deserializer)?, attempt, rel_path)? {
                 break
             }
         }}
     }
+    // (trailing directories)
+    serializer.get_mut().flush()?;
     
-	let time_taken = start.elapsed();
-    println!("Total Sent {} - deflation: {}%", ByteSize(total_sent as u64), (total_sent*100)/(total_size as usize));
-    println!("{} in {time_taken:?} ({}/s)", ByteSize(total_size), ByteSize(total_size / time_taken.as_secs()));
-    
-    Ok(())
+    session.finish()
+}
+
+
+impl Shared {
+    /// Hash of an already sent file, if it has not been modified since
+    fn cached_hash(&self, abs_path: &Arc<PathBuf>, metadata: &std::fs::Metadata) -> Option<u128> {
+        self.hash_cache.read().unwrap().get(abs_path)
+            .filter(|(_, modified)| modified == &metadata.modified().expect("FUCK MAN, why are u using an OS without modified metadata????"))
+            .map(|(h, _)| *h)
+    }
+
+    /// Caches the `hash` of a file just sent
+    fn cache_hash(&self, abs_path: &Arc<PathBuf>, hash: u128, metadata: &std::fs::Metadata) -> io::Result<()> {
+        self.hash_cache.write().unwrap().insert(abs_path.clone(), (hash, metadata.modified()?));
+        Ok(())
+    }
+}
+
+
+/// Sends `file` (of `size` Bytes) in data frames from the page cache into the socket,
+/// hashing each frame from there right after it has been sent, unless its `hash` is known.
+/// Returns the Byte counter, the hash and the Bytes sent.
+#[cfg(target_os = "linux")]
+fn send_file_zero_copy<W: SendFile>(mut writer: FramedWriter<CountingWriter<W>>, file: &std::fs::File, size: u64, hash: Option<u128>, socket: PeerAddr, handler: &Sender<ServerEvent>) -> anyhow::Result<(CountingWriter<W>, u128, u64)> {
+    let mut hasher = hash.is_none().then(zero_copy::FileHasher::new);
+    let mut sent = 0;
+    while sent < size {
+        let count = (size - sent).min(zero_copy::FRAME_SIZE as u64) as u32;
+        writer.send_file(file, sent, count)?;
+        if let Some(hasher) = &mut hasher {
+            hasher.update(file, sent, count as usize)?;
+        }
+        handler.send(ServerEvent::BytesSent{ client: socket, bytes: count as usize })?;
+        sent += u64::from(count);
+    }
+    let hash = hash.or_else(|| hasher.map(zero_copy::FileHasher::finalize)).unwrap();
+    Ok((writer.finish()?, hash, sent))
+}
+
+/// Whether a sample of `file` is worth compressing, rewinding it afterwards
+fn sample_compressibility(path: &Path, file: &mut std::fs::File) -> io::Result<bool> {
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    file.take(SAMPLE_SIZE as u64).read_to_end(&mut sample)?;
+    file.rewind()?;
+    Ok(worth_compressing(path, &sample))
+}
+
+
+/// Turns a client away, after receiving its handshake
+fn reject_client(stream: Box<dyn Transport>, rejection: Rejection) -> anyhow::Result<()> {
+    let handshake = Handshake::deserialize(&mut Deserializer::new(stream.try_clone_boxed()?))?;
+    HandshakeResponse::Rejected(rejection.clone()).serialize(&mut Serializer::new(stream))?;
+    anyhow::bail!("Rejected client (version {}): {rejection}", handshake.version)
+}
+
+
+/// Whether `error` was caused by the client not responding in time
+fn is_timeout(error: &anyhow::Error) -> bool {
+    error.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock))
 }
@@ -0,0 +1,103 @@
+use md5::{Digest, Md5};
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::PathVec;
+
+
+/// Size of trained dictionaries (zstd's default)
+const DICTIONARY_SIZE: usize = 110 << 10;
+/// Larger files hardly profit from a dictionary, so they are not sampled
+const MAX_SAMPLE_FILE_SIZE: u64 = 128 << 10;
+/// Sampled in total, zstd recommends about a hundred times the dictionary size
+const MAX_SAMPLES_SIZE: u64 = 100 * DICTIONARY_SIZE as u64;
+/// Fewer samples are not worth training on
+const MIN_SAMPLES: usize = 32;
+
+
+/// A dictionary trained from a sample of `files`, or the one cached for the same sample.
+/// None, if there are too few small files to train on.
+pub(super) fn trained(files: &PathVec) -> io::Result<Option<Vec<u8>>> {
+    // spread the samples evenly over all small files
+    let mut small_files = Vec::new();
+    let mut small_size = 0;
+    for (abs_path, _) in files {
+        let metadata = abs_path.metadata()?;
+        if metadata.is_file() && metadata.len() > 0 && metadata.len() <= MAX_SAMPLE_FILE_SIZE {
+            small_files.push(abs_path);
+            small_size += metadata.len();
+        }
+    }
+    let step = small_size.div_ceil(MAX_SAMPLES_SIZE).max(1) as usize;
+    let samples = small_files.into_iter()
+        .step_by(step)
+        .map(|path| std::fs::read(path.as_path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    if samples.len() < MIN_SAMPLES {
+        return Ok(None)
+    }
+
+    // (the cache is only a shortcut, training works without it)
+    let cache_path = cache_dir().map(|dir| dir.join(format!("{:032x}.dict", sample_key(&samples))));
+    if let Some(dictionary) = cache_path.as_deref().and_then(load_cached) {
+        return Ok(Some(dictionary))
+    }
+
+    // training fails for samples without anything in common
+    let Ok(dictionary) = zstd::dict::from_samples(&samples, DICTIONARY_SIZE) else {
+        return Ok(None)
+    };
+    if let Some(cache_path) = &cache_path {
+        let _ = store_cached(cache_path, &dictionary);
+    }
+    Ok(Some(dictionary))
+}
+
+/// Names the dictionary trained on `samples` after their content
+fn sample_key(samples: &[Vec<u8>]) -> u128 {
+    let mut hasher = Md5::new();
+    hasher.update(DICTIONARY_SIZE.to_le_bytes());
+    for sample in samples {
+        hasher.update(sample.len().to_le_bytes());
+        hasher.update(sample);
+    }
+    u128::from_be_bytes(hasher.finalize().into())
+}
+
+/// The user's own cache directory for dictionaries, None if there is none
+fn cache_dir() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Caches"))
+    } else {
+        std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from).filter(|dir| dir.is_absolute())
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    };
+    Some(base?.join("smd_transfer").join("dictionaries"))
+}
+
+/// The dictionary cached at `path`, if there is a usable one
+fn load_cached(path: &Path) -> Option<Vec<u8>> {
+    let dictionary = std::fs::read(path).ok()?;
+    // (only trained dictionaries are cached, they all have an id)
+    zstd::zstd_safe::get_dict_id_from_dict(&dictionary)?;
+    zstd::Encoder::with_dictionary(io::sink(), 1, &dictionary).ok()?;
+    Some(dictionary)
+}
+
+/// Caches `dictionary` at `path`, readable only by the user.
+/// Written to a temporary file first, so other servers never read a partial one.
+fn store_cached(path: &Path, dictionary: &[u8]) -> io::Result<()> {
+    let Some(dir) = path.parent() else { return Ok(()) };
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)?;
+
+    let temporary = path.with_extension(format!("{}.tmp", std::process::id()));
+    std::fs::write(&temporary, dictionary)?;
+    std::fs::rename(&temporary, path).inspect_err(|_| { let _ = std::fs::remove_file(&temporary); })
+}
@@ -0,0 +1,138 @@
+//! Sending a file in stages, each on a thread of its own (see [`Server::with_pipeline`](super::Server::with_pipeline)):
+//! reading from disk, hashing, compressing and encrypting overlap with each other and with sending.
+use chacha20poly1305::ChaCha20Poly1305;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use crate::{CompressionAlgorithm, SmdError};
+use crate::buffered_io::{CountingWriter, FramedWriter, PerhapsCompressedWriter, PerhapsEncrWriter, PerhapsHashingWriter, ZstdParams};
+use crate::buffered_io::channel_io::{channel, join, write_chunks, StageErrors, CHANNEL_DEPTH, CHUNK_SIZE};
+use crate::server_events::{ServerEvent, ServerEventWriter};
+use crate::transport::PeerAddr;
+
+
+/// Buffers of a client's pipeline: four channels, plus a chunk being filled and one being emptied at each
+pub(super) const MEMORY: u64 = 4 * (CHANNEL_DEPTH as u64 + 2) * CHUNK_SIZE as u64;
+
+
+/// How a file is sent
+pub(super) struct FileStream<'a> {
+    /// If some, compressed with it
+    pub codec: Option<CompressionAlgorithm>,
+    pub level: u8,
+    pub dictionary: Option<&'a [u8]>,
+    pub params: &'a ZstdParams,
+    /// Cached hash of the file
+    pub hash: Option<u128>,
+    pub heartbeat_interval: Option<Duration>,
+}
+
+/// Sends `file` like the serial loop of `handle_client`, into `writer`.
+/// Returns the Byte counter, the hash and the Bytes read.
+pub(super) fn send_file<W: Write>(mut file: File, path: &Path, stream: FileStream<'_>, encryptor: &mut Option<ChaCha20Poly1305>, writer: FramedWriter<CountingWriter<W>>, socket: PeerAddr, handler: &Sender<ServerEvent>) -> anyhow::Result<(CountingWriter<W>, u128, u64)> {
+    let FileStream{ codec, level, dictionary, params, hash, heartbeat_interval } = stream;
+    std::thread::scope(|scope| {
+        // read from disk
+        let (mut output, input) = channel();
+        let reader = scope.spawn(move || -> anyhow::Result<u64> {
+            let mut raw = 0;
+            loop {
+                let mut chunk = vec![0u8; CHUNK_SIZE];
+                let read = file.read(&mut chunk).map_err(SmdError::io(path))?;
+                if read == 0 {
+                    return Ok(raw)
+                }
+                chunk.truncate(read);
+                output.send(chunk)?;
+                raw += read as u64;
+            }
+        });
+
+        // hash (and report the progress)
+        let (output, next) = channel();
+        let hasher = scope.spawn(move || -> io::Result<u128> {
+            let mut writer = ServerEventWriter::new(PerhapsHashingWriter::with_hash(output, hash), socket, handler);
+            write_chunks(input, &mut writer)?;
+            let (mut output, hash) = writer.inner().finalize();
+            output.flush()?;
+            Ok(hash)
+        });
+        let input = next;
+
+        // compress
+        let (compressor, input) = match codec {
+            Some(codec) => {
+                let (output, next) = channel();
+                let compressor = scope.spawn(move || -> io::Result<()> {
+                    let mut writer = PerhapsCompressedWriter::with_params(output, Some(codec), level, dictionary, params)?;
+                    write_chunks(input, &mut writer)?;
+                    writer.finish()?.flush()
+                });
+                (Some(compressor), next)
+            },
+            None => (None, input)
+        };
+
+        // encrypt
+        let (encrypter, input) = match encryptor.is_some() {
+            true => {
+                let (output, next) = channel();
+                let encrypter = scope.spawn(move || -> io::Result<()> {
+                    let mut writer = PerhapsEncrWriter::with_encryptor(output, encryptor);
+                    write_chunks(input, &mut writer)?;
+                    writer.into_inner().flush()
+                });
+                (Some(encrypter), next)
+            },
+            false => (None, input)
+        };
+
+        // and send, on this thread
+        let sent = send_chunks(input, writer, heartbeat_interval);
+
+        let mut errors = StageErrors::default();
+        let raw = errors.take(join(reader));
+        let hash = errors.take(join(hasher));
+        if let Some(compressor) = compressor {
+            errors.take(join(compressor));
+        }
+        if let Some(encrypter) = encrypter {
+            errors.take(join(encrypter));
+        }
+        let counter = errors.take(sent);
+        errors.into_result()?;
+        match (counter, hash, raw) {
+            (Some(counter), Some(hash), Some(raw)) => Ok((counter, hash, raw)),
+            _ => unreachable!("A stage failed without an error")
+        }
+    })
+}
+
+/// Writes the chunks into frames, sending heartbeats while waiting for them
+fn send_chunks<W: Write>(input: Receiver<Vec<u8>>, mut writer: FramedWriter<CountingWriter<W>>, heartbeat_interval: Option<Duration>) -> io::Result<CountingWriter<W>> {
+    loop {
+        let chunk = match heartbeat_interval {
+            Some(interval) => match input.recv_timeout(interval) {
+                Ok(chunk) => Some(chunk),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break
+            },
+            None => match input.recv() {
+                Ok(chunk) => Some(chunk),
+                Err(_) => break
+            }
+        };
+        if let Some(chunk) = chunk {
+            writer.write_all(&chunk)?;
+        }
+        // the stages before might not have produced any output for a while
+        if let Some(interval) = heartbeat_interval {
+            writer.heartbeat_if_idle(interval)?;
+        }
+    }
+    writer.finish()
+}
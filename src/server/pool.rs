@@ -0,0 +1,137 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+
+use crate::CompressionAlgorithm;
+use crate::buffered_io::{ZstdParams, comp_io::BROTLI_WINDOW_LOG};
+
+
+type Job = Box<dyn FnOnce() + Send>;
+
+
+/// Runs client handlers on at most `max_workers` threads (or a thread each, without a maximum).
+/// Jobs beyond that are queued.
+pub(super) struct WorkerPool {
+    /// None, if every job gets its own thread
+    jobs: Option<Sender<Job>>,
+    max_workers: Option<usize>,
+    /// Running and queued jobs
+    pending: Arc<AtomicUsize>
+}
+
+impl WorkerPool {
+    pub fn new(max_workers: Option<usize>) -> Self {
+        let pending = Arc::new(AtomicUsize::new(0));
+        let Some(max_workers) = max_workers else {
+            return Self{ jobs: None, max_workers: None, pending }
+        };
+
+        // the workers share the queue, they stop once the pool is dropped
+        let (send, recv) = mpsc::channel::<Job>();
+        let recv = Arc::new(Mutex::new(recv));
+        for _ in 0..max_workers {
+            let recv = recv.clone();
+            std::thread::spawn(move || loop {
+                let job = recv.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break
+                }
+            });
+        }
+        Self{ jobs: Some(send), max_workers: Some(max_workers), pending }
+    }
+
+    /// Whether every worker is busy (new jobs would be queued)
+    pub fn is_full(&self) -> bool {
+        self.max_workers.is_some_and(|max| self.pending.load(Ordering::Relaxed) >= max)
+    }
+
+    /// Runs `job` as soon as a worker is free.
+    /// A panicking job is passed to `on_panic` (instead of killing its worker).
+    pub fn execute<F: FnOnce() + Send + 'static, P: FnOnce(String) + Send + 'static>(&self, job: F, on_panic: P) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let pending = self.pending.clone();
+        let job: Job = Box::new(move || {
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                let message = panic.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                on_panic(message);
+            }
+            pending.fetch_sub(1, Ordering::Relaxed);
+        });
+
+        match &self.jobs {
+            Some(jobs) => jobs.send(job).expect("Workers are only gone, once the pool is dropped"),
+            None => { std::thread::spawn(job); }
+        }
+    }
+}
+
+
+/// Memory shared by all clients of a server, for their compression contexts and buffers.
+#[derive(Debug, Clone, Default)]
+pub(super) struct MemoryBudget {
+    /// None, if unlimited
+    remaining: Option<Arc<Mutex<u64>>>
+}
+
+/// Returns its memory to the [`MemoryBudget`] when dropped
+pub(super) struct Reservation {
+    budget: MemoryBudget,
+    amount: u64
+}
+
+impl MemoryBudget {
+    pub fn new(total: Option<u64>) -> Self {
+        Self{ remaining: total.map(|total| Arc::new(Mutex::new(total))) }
+    }
+
+    /// None, if there is not enough memory left
+    pub fn reserve(&self, amount: u64) -> Option<Reservation> {
+        if let Some(remaining) = &self.remaining {
+            let mut remaining = remaining.lock().unwrap();
+            *remaining = remaining.checked_sub(amount)?;
+        }
+        Some(Reservation{ budget: self.clone(), amount })
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(remaining) = &self.budget.remaining {
+            *remaining.lock().unwrap() += self.amount;
+        }
+    }
+}
+
+
+/// Buffers of one client (file buffer, frames, encryption, socket), generously
+const CLIENT_BUFFERS: u64 = 1 << 17;
+
+/// Rough estimate of the memory a client needs
+pub(super) fn client_memory(codec: Option<CompressionAlgorithm>, comp_level: u8, params: &ZstdParams) -> u64 {
+    let zstd_window_log = || params.long_window.unwrap_or(match comp_level {
+        ..=1 => 19,
+        2 => 20,
+        3..=8 => 21,
+        9..=16 => 22,
+        17..=19 => 23,
+        20 => 25,
+        21 => 26,
+        _ => 27
+    });
+    // the window plus its match tables take about three windows
+    let window_log = match codec {
+        None => return CLIENT_BUFFERS,
+        // (lz4 only looks back 64 KiB)
+        Some(CompressionAlgorithm::Lz4) => 16,
+        Some(CompressionAlgorithm::Brotli) => BROTLI_WINDOW_LOG,
+        // zstd's window log per level (for large files) unless set
+        Some(_) => zstd_window_log()
+    };
+    // and every zstd worker has its own
+    CLIENT_BUFFERS + (3 << window_log) * u64::from(params.workers.max(1))
+}
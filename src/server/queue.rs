@@ -0,0 +1,243 @@
+use rmp_serde::{Serializer, Deserializer};
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use crate::{Handshake, HandshakeResponse, TransferFeature, SmdError};
+use crate::control::TransferControl;
+use crate::server_events::ServerEvent;
+use crate::transport::{PeerAddr, Transport};
+
+
+/// How often the waiting clients are looked after
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for each client's data while doing so
+const READ_TIMEOUT: Duration = Duration::from_millis(1);
+/// and for an update to be sent (only a client not reading at all takes longer)
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Largest handshake accepted from a waiting client (a resume list of over a million files)
+const MAX_HANDSHAKE_SIZE: usize = 32 << 20;
+/// Deepest nesting of arrays and maps in a handshake
+const MAX_HANDSHAKE_DEPTH: usize = 8;
+
+
+/// Clients waiting for a worker of the [`super::WorkerPool`].
+///
+/// Their handshakes are read while waiting, newer clients are kept from timing out with [`HandshakeResponse::Queued`],
+/// and clients hanging up are dropped, instead of taking up a worker once it is their turn.
+#[derive(Clone)]
+pub(super) struct WaitingRoom {
+    clients: Arc<Mutex<VecDeque<(PeerAddr, Seat)>>>
+}
+
+/// Where a client waits, locked on its own while it is looked after.
+/// Empty once it has left (or has been dropped).
+type Seat = Arc<Mutex<Option<Waiting>>>;
+
+struct Waiting {
+    stream: Box<dyn Transport>,
+    socket: PeerAddr,
+    /// Of the handshake, until it is complete
+    received: Vec<u8>,
+    /// How much of it has arrived
+    end: MessageEnd,
+    handshake: Option<Handshake>,
+    last_update: Instant
+}
+
+impl WaitingRoom {
+    /// Looks after the waiting clients on a thread of its own, until stopped through `control` (or dropped)
+    pub fn new(control: TransferControl, handler: Sender<ServerEvent>) -> Self {
+        let clients = Arc::new(Mutex::new(VecDeque::new()));
+        let weak = Arc::downgrade(&clients);
+        std::thread::spawn(move || look_after(weak, control, handler));
+        Self{ clients }
+    }
+
+    /// Lets `stream` wait, returning how many clients wait now (including it)
+    pub fn enter(&self, stream: Box<dyn Transport>, socket: PeerAddr) -> io::Result<usize> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let waiting = Waiting{ stream, socket, received: Vec::new(), end: MessageEnd::default(), handshake: None, last_update: Instant::now() };
+        let mut clients = self.clients.lock().unwrap();
+        clients.push_back((socket, Arc::new(Mutex::new(Some(waiting)))));
+        Ok(clients.len())
+    }
+
+    /// Takes out the client at `socket` once it is its turn, along with its handshake.
+    /// None, if it has hung up while waiting.
+    pub fn leave(&self, socket: PeerAddr, idle_timeout: Duration) -> Option<anyhow::Result<(Box<dyn Transport>, Handshake)>> {
+        let mut clients = self.clients.lock().unwrap();
+        let index = clients.iter().position(|(waiting, _)| *waiting == socket)?;
+        let (_, seat) = clients.remove(index)?;
+        drop(clients);
+        // (once it is not being looked after)
+        let waiting = seat.lock().unwrap().take()?;
+        Some(waiting.finish(idle_timeout))
+    }
+}
+
+/// Looks after all `clients` regularly, dropping the ones that hung up (or misbehaved).
+/// Each is only locked on its own, not keeping others from entering or leaving.
+fn look_after(clients: Weak<Mutex<VecDeque<(PeerAddr, Seat)>>>, control: TransferControl, handler: Sender<ServerEvent>) {
+    while !control.is_cancelled() {
+        std::thread::sleep(POLL_INTERVAL);
+        let Some(clients) = clients.upgrade() else { break };
+        let seats: Vec<Seat> = clients.lock().unwrap().iter().map(|(_, seat)| seat.clone()).collect();
+        let waiting = seats.len() as u32;
+        for seat in seats {
+            let mut seat = seat.lock().unwrap();
+            // (unless it has left in the meantime)
+            let Some(client) = seat.as_mut() else { continue };
+            if let Err(e) = client.look_after(waiting) {
+                let _ = client.stream.shutdown();
+                let _ = handler.send(ServerEvent::ClientFailed{ client: client.socket, error: format!("Left the queue: {e:#}") });
+                let socket = client.socket;
+                *seat = None;
+                clients.lock().unwrap().retain(|(waiting, _)| *waiting != socket);
+            }
+        }
+    }
+}
+
+impl Waiting {
+    /// Reads what the client has sent (or notices it hanging up), and sends it an update when due
+    fn look_after(&mut self, waiting: u32) -> anyhow::Result<()> {
+        let mut buffer = [0; 1 << 12];
+        // (until nothing more has arrived)
+        loop { match self.stream.read(&mut buffer) {
+            Ok(0) => anyhow::bail!("The client has hung up"),
+            Ok(read) if self.handshake.is_none() => {
+                self.received.extend_from_slice(&buffer[..read]);
+                match self.end.scan(&self.received)? {
+                    // (the client waits for the response before sending anything else)
+                    Some(end) if end < self.received.len() => return Err(SmdError::ProtocolViolation("Sent more than its handshake".into()).into()),
+                    Some(_) => {
+                        let handshake = Handshake::deserialize(&mut Deserializer::new(self.received.as_slice()))
+                            .map_err(|e| SmdError::ProtocolViolation(e.to_string()))?;
+                        self.handshake = Some(handshake);
+                        self.received = Vec::new();
+                    },
+                    None if self.received.len() > MAX_HANDSHAKE_SIZE => return Err(SmdError::ProtocolViolation(format!("Sent a handshake of over {MAX_HANDSHAKE_SIZE} Bytes")).into()),
+                    None => ()
+                }
+            },
+            Ok(_) => return Err(SmdError::ProtocolViolation("Sent more than its handshake".into()).into()),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e.into())
+        }}
+
+        // tell newer clients how many wait, often enough for them not to time out
+        let Some(handshake) = &self.handshake else { return Ok(()) };
+        if handshake.capabilities.transfer.contains(&TransferFeature::Queue) && handshake.timeout_ms > 0
+            && self.last_update.elapsed() >= Duration::from_millis(handshake.timeout_ms) / 4 {
+            HandshakeResponse::Queued{ waiting }.serialize(&mut Serializer::new(&mut self.stream))?;
+            self.stream.flush()?;
+            self.last_update = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Restores the timeouts and reads the rest of the handshake, if needed
+    fn finish(self, idle_timeout: Duration) -> anyhow::Result<(Box<dyn Transport>, Handshake)> {
+        let Waiting{ mut stream, received, handshake, .. } = self;
+        stream.set_read_timeout(Some(idle_timeout))?;
+        stream.set_write_timeout(Some(idle_timeout))?;
+        let handshake = match handshake {
+            Some(handshake) => handshake,
+            None => {
+                let rest = (&mut stream).take(MAX_HANDSHAKE_SIZE.saturating_sub(received.len()) as u64);
+                Handshake::deserialize(&mut Deserializer::new(io::Cursor::new(received).chain(rest)))?
+            }
+        };
+        Ok((stream, handshake))
+    }
+}
+
+/// Finds the end of a msgpack message arriving piece by piece, scanning each Byte once (without decoding)
+struct MessageEnd {
+    /// Bytes scanned, up to the first incomplete value
+    scanned: usize,
+    /// Values still missing of the message and each array or map scanned into
+    missing: Vec<u64>
+}
+
+impl Default for MessageEnd {
+    fn default() -> Self {
+        Self{ scanned: 0, missing: vec![1] }
+    }
+}
+
+impl MessageEnd {
+    /// Scans on in `received` (which only grows), returning the length of the message once complete
+    fn scan(&mut self, received: &[u8]) -> Result<Option<usize>, SmdError> {
+        loop {
+            // leave finished arrays and maps
+            while self.missing.last() == Some(&0) {
+                self.missing.pop();
+            }
+            if self.missing.is_empty() {
+                return Ok(Some(self.scanned))
+            }
+
+            // the next value, without its items
+            let Some((length, items)) = value_header(&received[self.scanned..])? else { return Ok(None) };
+            if received.len() - self.scanned < length {
+                return Ok(None)
+            }
+            self.scanned += length;
+            *self.missing.last_mut().unwrap() -= 1;
+            if items > 0 {
+                if self.missing.len() >= MAX_HANDSHAKE_DEPTH {
+                    return Err(SmdError::ProtocolViolation("Sent a handshake nested too deeply".into()))
+                }
+                self.missing.push(items);
+            }
+        }
+    }
+}
+
+/// The length of the msgpack value starting `data` (including its marker, but not its items),
+/// and how many items follow it (of an array or map). None if `data` does not tell yet.
+fn value_header(data: &[u8]) -> Result<Option<(usize, u64)>, SmdError> {
+    let Some(&marker) = data.first() else { return Ok(None) };
+    // the big-endian length in the `size` Bytes after the marker
+    let length = |size: usize| data.get(1..1 + size).map(|bytes| bytes.iter().fold(0, |length, byte| length << 8 | *byte as usize));
+    Ok(match marker {
+        // positive and negative fixint, nil, false and true
+        0x00..=0x7f | 0xe0..=0xff | 0xc0 | 0xc2 | 0xc3 => Some((1, 0)),
+        // fixmap, fixarray and fixstr
+        0x80..=0x8f => Some((1, 2 * (marker & 0x0f) as u64)),
+        0x90..=0x9f => Some((1, (marker & 0x0f) as u64)),
+        0xa0..=0xbf => Some((1 + (marker & 0x1f) as usize, 0)),
+        // bin and str
+        0xc4 | 0xd9 => length(1).map(|length| (2 + length, 0)),
+        0xc5 | 0xda => length(2).map(|length| (3 + length, 0)),
+        0xc6 | 0xdb => length(4).map(|length| (5 + length, 0)),
+        // ext (with its type)
+        0xc7 => length(1).map(|length| (3 + length, 0)),
+        0xc8 => length(2).map(|length| (4 + length, 0)),
+        0xc9 => length(4).map(|length| (6 + length, 0)),
+        // numbers
+        0xcc | 0xd0 => Some((2, 0)),
+        0xcd | 0xd1 => Some((3, 0)),
+        0xca | 0xce | 0xd2 => Some((5, 0)),
+        0xcb | 0xcf | 0xd3 => Some((9, 0)),
+        // fixext (with its type)
+        0xd4 => Some((3, 0)),
+        0xd5 => Some((4, 0)),
+        0xd6 => Some((6, 0)),
+        0xd7 => Some((10, 0)),
+        0xd8 => Some((18, 0)),
+        // array and map
+        0xdc => length(2).map(|items| (3, items as u64)),
+        0xdd => length(4).map(|items| (5, items as u64)),
+        0xde => length(2).map(|items| (3, 2 * items as u64)),
+        0xdf => length(4).map(|items| (5, 2 * items as u64)),
+        0xc1 => return Err(SmdError::ProtocolViolation("Sent an invalid msgpack marker".into()))
+    })
+}
@@ -0,0 +1,336 @@
+//! Serving a client apart from the I/O, shared by `handle_client` and its [`tokio`] version:
+//! negotiating, picking what to send next, and keeping track of what has been sent.
+use chacha20poly1305::ChaCha20Poly1305;
+
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use crate::{Capabilities, CompressionAlgorithm, CompressionDescriptor, EncryptionMode, FileHash, FileHashResponse, Handshake, HandshakeResponse, HashAlgorithm, Rejection, RejectionCode, ResumeFeature, TransferFeature, SmdError};
+use crate::{PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, PER_FILE_COMPRESSION_VERSION, MAX_HASH_MISMATCHES};
+use crate::buffered_io::{RateLimiter, ZstdParams, comp_io::DEFAULT_WINDOW_LOG_MAX, encrypt_io::verify_key_check};
+use crate::server_events::ServerEvent;
+use crate::transport::PeerAddr;
+use super::{pool::{self, Reservation}, LevelTuner, Shared, SOLID_MAX_ENTRIES, SOLID_MAX_FILE_SIZE, SOLID_MAX_SIZE};
+
+
+/// What has been agreed on with a client, and how much it has been sent
+pub(super) struct Session<'a> {
+    socket: PeerAddr,
+    handler: &'a Sender<ServerEvent>,
+    pub protocol_version: u32,
+    /// Whether the transfer is compressed (newer clients get incompressible files uncompressed)
+    pub compression: bool,
+    /// The algorithm compressed with, if compressed
+    pub codec: Option<CompressionAlgorithm>,
+    /// zstd dictionary, if the client decompresses with it
+    pub dictionary: Option<&'a [u8]>,
+    /// zstd's, limited to the window the client accepts
+    pub params: ZstdParams,
+    /// Often enough for the client not to time out (None, if it never does)
+    pub heartbeat_interval: Option<Duration>,
+    /// This client's and the total rate
+    pub limiters: [RateLimiter; 2],
+    tuner: LevelTuner,
+    _reservation: Reservation,
+    /// Bytes of files and Bytes on the wire
+    total_raw: u64,
+    total_wire: u64,
+    start: Instant,
+}
+
+/// Picks what to send a client next, leaving out the files it already has
+pub(super) struct Schedule {
+    shared: Arc<Shared>,
+    resume_list: Option<HashSet<FileHash>>,
+    /// Whether small files are batched into solid streams
+    solid: bool,
+    /// Into [`Shared::files`], the first entry not sent yet
+    next: usize,
+    /// Files of solid streams whose hashes did not match, to be sent again on their own
+    resend: VecDeque<usize>,
+}
+
+/// What to send next
+pub(super) enum Step {
+    /// Small files and directories in one solid stream
+    Solid(Vec<BatchEntry>),
+    /// The directory or file at `index` into [`Shared::files`], for the `first_attempt` at sending it
+    Entry{ index: usize, metadata: std::fs::Metadata, first_attempt: u32 },
+}
+
+/// An entry of a solid stream
+pub(super) struct BatchEntry {
+    /// into [`Shared::files`]
+    pub index: usize,
+    /// None for directories
+    pub file: Option<std::fs::Metadata>,
+    /// Cached hash of the file
+    pub hash: Option<u128>
+}
+
+
+impl<'a> Session<'a> {
+    /// Negotiates with a client, whose `handshake` has been received, reserving memory for it (and `extra_memory`).
+    /// Returns the response to send, and the session along with its schedule
+    /// (or the error to fail with after sending the response, if the client is turned away).
+    pub fn start(shared: &'a Arc<Shared>, handshake: Handshake, encryptor: &Option<ChaCha20Poly1305>, extra_memory: u64, socket: PeerAddr, handler: &'a Sender<ServerEvent>)
+        -> anyhow::Result<(HandshakeResponse, anyhow::Result<(Self, Schedule)>)> {
+        handler.send(ServerEvent::HandShake{
+            client: socket,
+            version: handshake.version.clone(),
+            compression: handshake.compression,
+            resume_list: handshake.resume_list.as_ref().map(|list| list.len())
+        })?;
+        let compression = shared.compression | handshake.compression;
+
+        // accept or reject client
+        let negotiated = if shared.control.is_cancelled() {
+            Err(Rejection::new(RejectionCode::ServerError, "The server is shutting down"))
+        } else {
+            negotiate(&handshake, compression, shared.codec, encryptor)
+        };
+        // reserve memory for compression and buffers
+        let negotiated = negotiated.and_then(|(protocol_version, capabilities, codec)|
+            shared.memory.reserve(pool::client_memory(codec, shared.comp_level.max(), &shared.zstd_params) + extra_memory)
+                .map(|reservation| ((protocol_version, capabilities, codec), reservation))
+                .ok_or_else(|| Rejection::new(RejectionCode::ServerFull, "The server's memory budget is exhausted"))
+        );
+        let ((protocol_version, capabilities, codec), reservation) = match negotiated {
+            Ok(negotiated) => negotiated,
+            Err(rejection) => {
+                let error = anyhow::anyhow!("Rejected client (version {}): {rejection}", handshake.version);
+                return Ok((HandshakeResponse::Rejected(rejection), Err(error)))
+            }
+        };
+
+        // some clients can not decompress with a dictionary
+        let dictionary = shared.dictionary.as_deref().filter(|_| codec == Some(CompressionAlgorithm::Zstd) && capabilities.transfer.contains(&TransferFeature::Dictionary));
+        // or larger windows than zstd's default limit, or than they are willing to
+        let long_window = capabilities.transfer.contains(&TransferFeature::LongWindow);
+        let max_window_log = handshake.max_window_log.filter(|_| long_window).unwrap_or(DEFAULT_WINDOW_LOG_MAX);
+        let params = ZstdParams{
+            long_window: shared.zstd_params.long_window.map(|log| log.min(max_window_log)),
+            ..shared.zstd_params
+        };
+        let window_log = params.long_window.filter(|_| codec == Some(CompressionAlgorithm::Zstd) && long_window);
+        // clients only knowing zstd are only told whether it is used
+        let knows_codecs = capabilities.compression.iter().any(|codec| *codec != CompressionAlgorithm::Zstd);
+        let solid = capabilities.transfer.contains(&TransferFeature::SolidStreams);
+
+        let response = HandshakeResponse::Accepted{
+            protocol_version,
+            capabilities,
+            total_size: shared.total_size,
+            compression: if knows_codecs {
+                CompressionDescriptor::Codec(codec)
+            } else {
+                CompressionDescriptor::Zstd(compression)
+            },
+            // (holding the window log's place)
+            dictionary: (dictionary.is_some() || window_log.is_some()).then(|| dictionary.map(<[u8]>::to_vec)),
+            window_log
+        };
+        let session = Self{
+            socket,
+            handler,
+            protocol_version,
+            compression,
+            codec,
+            dictionary,
+            params,
+            // send heartbeats often enough for the client not to time out
+            heartbeat_interval: (handshake.timeout_ms > 0).then(|| Duration::from_millis(handshake.timeout_ms) / 4),
+            limiters: [shared.rate_limits.per_client.new_bucket(), shared.rate_limits.total.clone()],
+            // adjust the level to this client's connection
+            tuner: LevelTuner::new(shared.comp_level, codec),
+            _reservation: reservation,
+            total_raw: 0,
+            total_wire: 0,
+            start: Instant::now(),
+        };
+        let schedule = Schedule{
+            shared: shared.clone(),
+            resume_list: handshake.resume_list,
+            solid,
+            next: 0,
+            resend: VecDeque::new(),
+        };
+        Ok((response, Ok((session, schedule))))
+    }
+
+    /// Whether files are compressed one by one (or not), as told in their headers.
+    /// Before, every file is compressed if the transfer is.
+    pub fn per_file_compression(&self) -> bool {
+        self.protocol_version >= PER_FILE_COMPRESSION_VERSION
+    }
+
+    /// For the next file or solid stream
+    pub fn level(&self) -> u8 {
+        self.tuner.level()
+    }
+
+    /// Accounts for a file or solid stream, whose `raw` Bytes went over the wire as `wire` Bytes.
+    /// If `compressed`, the level is adjusted by how much of the `elapsed` time went to `waiting` for the connection.
+    pub fn stream_sent(&mut self, raw: u64, wire: u64, waiting: Duration, elapsed: Duration, compressed: bool) -> anyhow::Result<()> {
+        self.total_raw += raw;
+        self.total_wire += wire;
+        self.handler.send(ServerEvent::StreamSent{ client: self.socket, raw, wire })?;
+        if compressed {
+            if let Some(level) = self.tuner.file_sent(waiting, elapsed) {
+                self.handler.send(ServerEvent::CompressionLevelChanged{ client: self.socket, level })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the client's `response` to the hash of the file at `rel_path`, after its `attempt`.
+    /// Returns whether the file has arrived, errors once it failed too often.
+    pub fn file_checked(&self, response: FileHashResponse, attempt: u32, rel_path: &Path) -> anyhow::Result<bool> {
+        if response.matches {
+            return Ok(true)
+        }
+        if attempt >= MAX_HASH_MISMATCHES {
+            return Err(SmdError::HashMismatchLimit{ path: rel_path.to_path_buf(), attempts: attempt }.into())
+        }
+        self.handler.send(ServerEvent::FileRetried(self.socket))?;
+        Ok(false)
+    }
+
+    /// Handles the client's `responses` to the files `sent` in a solid stream,
+    /// scheduling the ones whose hashes did not match to be sent again
+    pub fn solid_checked(&self, schedule: &mut Schedule, sent: Vec<usize>, responses: Vec<FileHashResponse>) -> anyhow::Result<()> {
+        if responses.len() != sent.len() {
+            return Err(SmdError::ProtocolViolation(format!("Expected {} hash responses, got {}", sent.len(), responses.len())).into())
+        }
+        for (index, response) in sent.into_iter().zip(responses) {
+            if !response.matches {
+                self.handler.send(ServerEvent::FileRetried(self.socket))?;
+                schedule.resend.push_back(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Everything has been sent
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.handler.send(ServerEvent::ClientFinished{
+            client: self.socket,
+            raw: self.total_raw,
+            wire: self.total_wire,
+            time_taken: self.start.elapsed()
+        })?;
+        Ok(())
+    }
+}
+
+
+impl Schedule {
+    /// What to send next, None once everything has been sent.
+    /// Reads the metadata of the files (so it blocks).
+    pub fn next_step(&mut self) -> anyhow::Result<Option<Step>> {
+        if self.shared.control.is_cancelled() {
+            anyhow::bail!("Server has been stopped!")
+        }
+        let (index, first_attempt) = match self.resend.pop_front() {
+            Some(index) => (index, 2),
+            None if self.next < self.shared.files.len() => (self.next, 1),
+            None => return Ok(None)
+        };
+
+        // send small files and directories in one solid stream
+        if first_attempt == 1 && self.solid {
+            if let Some((batch, end)) = self.solid_batch(index)? {
+                self.next = end;
+                return Ok(Some(Step::Solid(batch)))
+            }
+        }
+        if first_attempt == 1 {
+            self.next += 1;
+        }
+        let metadata = self.shared.files[index].0.metadata()?;
+        Ok(Some(Step::Entry{ index, metadata, first_attempt }))
+    }
+
+    /// Whether the client already has the file with this `hash`
+    pub fn is_resumed(&self, hash: u128) -> bool {
+        self.resume_list.as_ref().is_some_and(|list| list.contains(&hash.into()))
+    }
+
+    /// The small files and directories starting at `start`, worth sending in one solid stream,
+    /// and the index after them. Files the client already has are left out.
+    fn solid_batch(&self, start: usize) -> std::io::Result<Option<(Vec<BatchEntry>, usize)>> {
+        let mut batch = Vec::new();
+        let (mut files, mut size) = (0, 0);
+        let mut end = start;
+        for (index, (abs_path, _)) in self.shared.files.iter().enumerate().skip(start) {
+            if batch.len() >= SOLID_MAX_ENTRIES || size >= SOLID_MAX_SIZE {
+                break
+            }
+            let metadata = abs_path.metadata()?;
+            if metadata.is_dir() {
+                batch.push(BatchEntry{ index, file: None, hash: None });
+            }
+            else if metadata.len() <= SOLID_MAX_FILE_SIZE {
+                let hash = self.shared.cached_hash(abs_path, &metadata);
+                if !hash.is_some_and(|hash| self.is_resumed(hash)) {
+                    files += 1;
+                    size += metadata.len();
+                    batch.push(BatchEntry{ index, file: Some(metadata), hash });
+                }
+            }
+            else {
+                break
+            }
+            end = index + 1;
+        }
+
+        // a single file is sent on its own
+        Ok((files >= 2).then_some((batch, end)))
+    }
+}
+
+
+/// Picks the protocol version, the capabilities and the compression algorithm (if `compression`) used with this client,
+/// or explains why it can not be served.
+fn negotiate(handshake: &Handshake, compression: bool, codec: CompressionAlgorithm, encryptor: &Option<ChaCha20Poly1305>) -> Result<(u32, Capabilities, Option<CompressionAlgorithm>), Rejection> {
+    // highest version both sides speak
+    let protocol_version = PROTOCOL_VERSION.min(handshake.protocol_version);
+    if protocol_version < MIN_PROTOCOL_VERSION.max(handshake.min_protocol_version) {
+        return Err(Rejection::new(RejectionCode::WrongVersion,
+            format!("The server speaks protocol versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}")))
+    }
+
+    // the client must use the same key (or none at all)
+    match (encryptor, &handshake.key_check) {
+        (None, None) => (),
+        (Some(_), None) => return Err(Rejection::new(RejectionCode::WrongKey, "The server requires an encryption key")),
+        (None, Some(_)) => return Err(Rejection::new(RejectionCode::WrongKey, "The server does not use encryption, but a key was given")),
+        (Some(decryptor), Some(check)) => if !verify_key_check(check.clone(), decryptor) {
+            return Err(Rejection::new(RejectionCode::WrongKey, "The given encryption key does not match the server's"))
+        }
+    }
+
+    // check that everything needed for this transfer is supported by both
+    let capabilities = Capabilities::supported().intersection(&handshake.capabilities);
+    if !capabilities.hashes.contains(&HashAlgorithm::Md5) {
+        return Err(Rejection::new(RejectionCode::WrongVersion, "Missing capability: hash algorithm md5"))
+    }
+    // the preferred algorithm, or zstd (which every client supports)
+    let codec = match compression {
+        false => None,
+        true if capabilities.compression.contains(&codec) => Some(codec),
+        true if capabilities.compression.contains(&CompressionAlgorithm::Zstd) => Some(CompressionAlgorithm::Zstd),
+        true => return Err(Rejection::new(RejectionCode::WrongVersion, "Missing capability: compression algorithm zstd"))
+    };
+    if encryptor.is_some() && !capabilities.encryption.contains(&EncryptionMode::ChaCha20Poly1305) {
+        return Err(Rejection::new(RejectionCode::WrongVersion, "Missing capability: encryption mode chacha20poly1305"))
+    }
+    if handshake.resume_list.is_some() && !capabilities.resume.contains(&ResumeFeature::HashList) {
+        return Err(Rejection::new(RejectionCode::WrongVersion, "Missing capability: resuming by hash list"))
+    }
+
+    Ok((protocol_version, capabilities, codec))
+}
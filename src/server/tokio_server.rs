@@ -0,0 +1,403 @@
+use chacha20poly1305::KeyInit;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use super::{BacklogPolicy, Server, Shared, Session, Step, is_timeout};
+use super::{ACCEPT_POLL_INTERVAL, ACCEPT_ERROR_BACKOFF, REJECT_WORKERS, REJECT_TIMEOUT};
+use crate::{EntryHeader::{FileHeader, DirHeader, SolidHeader}, FileHash, FileHashResponse, Handshake, HandshakeResponse, Rejection, RejectionCode};
+use crate::{TransferFeature, SmdError};
+use crate::buffered_io::encrypt_io::{prepare_key, maybe_encrypt_path};
+use crate::buffered_io::comp_io::{worth_compressing, SAMPLE_SIZE};
+use crate::buffered_io::tokio_io::{PerhapsHashingWriter, PerhapsCompressedWriter, PerhapsEncrWriter, CountingWriter, FramedWriter, RateLimitedWriter, MessageReader, write_message, write_message_unflushed, with_timeout};
+use crate::server_events::ServerEvent;
+use crate::transport::PeerAddr;
+use crate::transport::tokio_net::{AsyncListener, AsyncTransport};
+
+
+impl Server {
+    /// Like [`Server::serve`], but on the current [`tokio`] runtime, with a task per client
+    /// (at most as many as the maximum of clients).
+    pub async fn serve_async(&self, handler: Sender<ServerEvent>) -> Result<(), SmdError> {
+        Ok(self.serve_clients_async(handler).await?)
+    }
+
+    async fn serve_clients_async(&self, handler: Sender<ServerEvent>) -> anyhow::Result<()> {
+        let mut listener = AsyncListener::bind(&self.bind_address).await?;
+        // (rather than failing every client)
+        listener.tune(&self.socket_options)?;
+        let shared = Arc::new(self.prepare()?);
+        handler.send(ServerEvent::Listening{
+            address: listener.local_addr(),
+            total_size: shared.total_size,
+            dictionary: shared.dictionary.as_ref().map(Vec::len)
+        })?;
+
+        // main loop
+        let idle_timeout = self.idle_timeout;
+        let mut clients = JoinSet::new();
+        // (to report panicked clients)
+        let mut sockets = HashMap::new();
+        // free slots (no limit without a maximum of clients), and clients waiting for one
+        let slots = self.max_clients.map(|max| Arc::new(Semaphore::new(max)));
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let mut rejecting = JoinSet::new();
+        while !self.control.is_cancelled() {
+            // forget finished clients
+            while let Some(finished) = clients.try_join_next_with_id() {
+                match finished {
+                    Ok((id, ())) => { sockets.remove(&id); },
+                    Err(e) => if let Some(socket) = sockets.remove(&e.id()) {
+                        handler.send(ServerEvent::ClientFailed{ client: socket, error: format!("Handler panicked: {e}") })?;
+                    }
+                }
+            }
+            while rejecting.try_join_next().is_some() {}
+
+            // accept client (waking up regularly, to notice being stopped)
+            let (client, socket) = tokio::select! {
+                accepted = listener.accept(&self.socket_options) => match accepted {
+                    Ok(accepted) => accepted,
+                    // (which might pass, so keep serving)
+                    Err(e) => {
+                        handler.send(ServerEvent::AcceptFailed{ error: e.to_string(), retry_in: ACCEPT_ERROR_BACKOFF })?;
+                        tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        continue
+                    }
+                },
+                _ = tokio::time::sleep(ACCEPT_POLL_INTERVAL) => continue
+            };
+            handler.send(ServerEvent::ClientConnected(socket))?;
+            // (only this client is lost if it can not be tuned)
+            let client = match client {
+                Ok(client) => client,
+                Err(e) => {
+                    handler.send(ServerEvent::ClientFailed{ client: socket, error: e.to_string() })?;
+                    continue
+                }
+            };
+
+            // take a free slot
+            let slot = match &slots {
+                None => Slot::Taken(None),
+                Some(slots) => match slots.clone().try_acquire_owned() {
+                    Ok(permit) => Slot::Taken(Some(permit)),
+                    // all slots are taken
+                    Err(_) => match self.backlog {
+                        BacklogPolicy::Queue => {
+                            let waiting_now = waiting.fetch_add(1, Ordering::Relaxed) + 1;
+                            handler.send(ServerEvent::ClientQueued{ client: socket, waiting: waiting_now })?;
+                            Slot::Queued{ slots: slots.clone(), waiting: waiting.clone() }
+                        },
+                        // (disconnected without an answer, while enough clients are being turned away)
+                        BacklogPolicy::Reject if rejecting.len() >= REJECT_WORKERS => {
+                            drop(client);
+                            handler.send(ServerEvent::ClientFailed{ client: socket, error: "Disconnected, the server is full".into() })?;
+                            continue
+                        },
+                        BacklogPolicy::Reject => {
+                            let handler = handler.clone();
+                            rejecting.spawn(async move {
+                                let rejection = Rejection::new(RejectionCode::ServerFull, "The server is serving its maximum of clients");
+                                if let Err(e) = reject_client(client, rejection).await {
+                                    let _ = handler.send(ServerEvent::ClientFailed{ client: socket, error: format!("{e:#}") });
+                                }
+                            });
+                            continue
+                        }
+                    }
+                }
+            };
+
+            // start handle task
+            let task = clients.spawn(run_client(client, socket, slot, shared.clone(), handler.clone(), idle_timeout));
+            sockets.insert(task.id(), socket);
+        }
+
+        // disconnect remaining clients
+        clients.shutdown().await;
+        rejecting.shutdown().await;
+
+        Ok(())
+    }
+}
+
+
+/// How a client gets to be served
+enum Slot {
+    /// Right away (without a permit, if there is no maximum of clients)
+    Taken(Option<OwnedSemaphorePermit>),
+    /// Once one of `slots` is free, until then it is one of the `waiting` clients
+    Queued{ slots: Arc<Semaphore>, waiting: Arc<AtomicUsize> }
+}
+
+/// Handles a client (once it has its slot), reporting how it failed
+async fn run_client(client: Box<dyn AsyncTransport>, socket: PeerAddr, slot: Slot, shared: Arc<Shared>, handler: Sender<ServerEvent>, idle_timeout: Duration) {
+    let result = match slot {
+        Slot::Taken(_permit) => handle_client(client, None, socket, &shared, &handler, idle_timeout).await,
+        Slot::Queued{ slots, waiting } => {
+            let waited = wait_for_slot(client, slots, &waiting, idle_timeout).await;
+            waiting.fetch_sub(1, Ordering::Relaxed);
+            match waited {
+                Ok((client, handshake, _permit)) => handle_client(client, Some(handshake), socket, &shared, &handler, idle_timeout).await,
+                Err(e) => Err(e.context("Left the queue"))
+            }
+        }
+    };
+    match result {
+        Err(e) if is_timeout(&e) => {
+            let _ = handler.send(ServerEvent::ClientSilent{ client: socket, timeout: idle_timeout });
+        },
+        Err(e) => {
+            let _ = handler.send(ServerEvent::ClientFailed{ client: socket, error: format!("{e:#}") });
+        },
+        Ok(()) => ()
+    }
+}
+
+/// Receives the handshake of a queued client and lets it wait for a free slot, noticing it hanging up.
+/// Newer clients are told how many wait, often enough for them not to time out.
+async fn wait_for_slot(stream: Box<dyn AsyncTransport>, slots: Arc<Semaphore>, waiting: &AtomicUsize, idle_timeout: Duration) -> anyhow::Result<(Box<dyn AsyncTransport>, Handshake, OwnedSemaphorePermit)> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = MessageReader::new(reader);
+    let handshake: Handshake = with_timeout(Some(idle_timeout), reader.read_message()).await?;
+    let update_interval = (handshake.capabilities.transfer.contains(&TransferFeature::Queue) && handshake.timeout_ms > 0)
+        .then(|| Duration::from_millis(handshake.timeout_ms) / 4);
+
+    // (kept across the loop, to keep the place in the semaphore's queue)
+    let acquire = slots.acquire_owned();
+    tokio::pin!(acquire);
+    let permit = loop {
+        tokio::select! {
+            permit = &mut acquire => break permit?,
+            // the client sends nothing else until the response
+            read = reader.fill_buf() => match read?.len() {
+                0 => anyhow::bail!("The client has hung up"),
+                _ => return Err(SmdError::ProtocolViolation("Sent more than its handshake".into()).into())
+            },
+            // (a sleep this long never ends)
+            _ = tokio::time::sleep(update_interval.unwrap_or(Duration::MAX)) => {
+                let waiting = waiting.load(Ordering::Relaxed) as u32;
+                with_timeout(Some(idle_timeout), write_message(&mut writer, &HandshakeResponse::Queued{ waiting })).await?;
+            }
+        }
+    };
+    Ok((reader.into_inner().unsplit(writer), handshake, permit))
+}
+
+/// Turns a client away, after receiving its handshake
+async fn reject_client(stream: Box<dyn AsyncTransport>, rejection: Rejection) -> anyhow::Result<()> {
+    let (reader, mut stream) = tokio::io::split(stream);
+    let handshake: Handshake = with_timeout(Some(REJECT_TIMEOUT), MessageReader::new(reader).read_message()).await?;
+    with_timeout(Some(REJECT_TIMEOUT), write_message(&mut stream, &HandshakeResponse::Rejected(rejection.clone()))).await?;
+    anyhow::bail!("Rejected client (version {}): {rejection}", handshake.version)
+}
+
+
+/// Serves a client, whose `handshake` might have been received while it waited
+async fn handle_client(stream: Box<dyn AsyncTransport>, handshake: Option<Handshake>, socket: PeerAddr, shared: &Arc<Shared>, handler: &Sender<ServerEvent>, idle_timeout: Duration) -> anyhow::Result<()> {
+    let idle_timeout = Some(idle_timeout);
+    let (reader, mut stream) = tokio::io::split(stream);
+    let mut reader = MessageReader::new(reader);
+
+    // receive Handshake
+    let handshake: Handshake = match handshake {
+        Some(handshake) => handshake,
+        None => with_timeout(idle_timeout, reader.read_message()).await?
+    };
+
+    // create encryptor
+    let mut encryptor = shared.key.as_ref().map(|key|
+        chacha20poly1305::ChaCha20Poly1305::new(&prepare_key(key))
+    );
+
+    // accept or reject client
+    let (response, session) = Session::start(shared, handshake, &encryptor, 0, socket, handler)?;
+    with_timeout(idle_timeout, write_message(&mut stream, &response)).await?;
+    let (mut session, mut schedule) = session?;
+    let heartbeat_interval = session.heartbeat_interval;
+
+    // main loop
+    loop {
+        // (reading metadata blocks)
+        let (step, returned) = tokio::task::spawn_blocking(move || (schedule.next_step(), schedule)).await?;
+        schedule = returned;
+        let (index, metadata, first_attempt) = match step? {
+            None => break,
+            Some(Step::Entry{ index, metadata, first_attempt }) => (index, metadata, first_attempt),
+            // send small files and directories in one solid stream
+            Some(Step::Solid(batch)) => {
+                with_timeout(idle_timeout, write_message(&mut stream, &SolidHeader{ entries: batch.len() as u32, compressed: session.compression })).await?;
+                let batch_start = std::time::Instant::now();
+
+                // wrap stream into rate limiter, Byte counter, frames and encryptor
+                let writer = CountingWriter::new(RateLimitedWriter::new(&mut stream, &session.limiters));
+                let writer = PerhapsEncrWriter::with_encryptor(FramedWriter::new(writer), &mut encryptor);
+                // into compressor, shared by all entries
+                let writer = PerhapsCompressedWriter::with_params(writer, session.codec, session.level(), session.dictionary, &session.params)?;
+                // and into a buffer, as the entries are written in small pieces
+                let mut entries = tokio::io::BufWriter::with_capacity(1 << 13, writer);
+
+                let mut sent = Vec::new();
+                let mut raw = 0;
+                let mut buffer = vec![0u8; 1 << 13];
+                for entry in &batch {
+                    let (abs_path, rel_path) = &shared.files[entry.index];
+                    // the path is encrypted along with the stream
+                    let path = maybe_encrypt_path(rel_path, &mut None);
+                    let Some(metadata) = &entry.file else {
+                        with_timeout(idle_timeout, write_message_unflushed(&mut entries, &DirHeader{ path })).await?;
+                        continue
+                    };
+
+                    // send header
+                    with_timeout(idle_timeout, write_message_unflushed(&mut entries, &FileHeader{ path, size: metadata.len(), compressed: None })).await?;
+                    handler.send(ServerEvent::FileStarted{ client: socket, rel_path: rel_path.to_string_lossy().into(), size: metadata.len() })?;
+
+                    // send exactly the announced size, the client relies on it to find the next entry
+                    let mut file = tokio::fs::File::open(abs_path.as_path()).await
+                        .map_err(SmdError::io(abs_path.as_path()))?
+                        .take(metadata.len());
+                    let mut writer = PerhapsHashingWriter::with_hash(&mut entries, entry.hash);
+                    let mut copied = 0;
+                    loop {
+                        let read = file.read(&mut buffer).await.map_err(SmdError::io(abs_path.as_path()))?;
+                        if read == 0 {
+                            break
+                        }
+                        with_timeout(idle_timeout, writer.write_all(&buffer[..read])).await?;
+                        handler.send(ServerEvent::BytesSent{ client: socket, bytes: read })?;
+                        copied += read as u64;
+                    }
+                    if copied < metadata.len() {
+                        return Err(SmdError::io(abs_path.as_path())(std::io::ErrorKind::UnexpectedEof.into()).into())
+                    }
+                    raw += copied;
+
+                    // send Hash
+                    let (_, hash) = writer.finalize();
+                    with_timeout(idle_timeout, write_message_unflushed(&mut entries, &FileHash{ hash })).await?;
+                    sent.push(entry.index);
+                    // cache hash
+                    if entry.hash.is_none() {
+                        shared.cache_hash(abs_path, hash, metadata)?;
+                    }
+
+                    // the compressor might not have produced any output for a while
+                    if let Some(interval) = heartbeat_interval {
+                        with_timeout(idle_timeout, entries.get_mut().get_mut().get_mut().heartbeat_if_idle(interval)).await?;
+                    }
+                }
+
+                // finish compression and frames, and add sent bytes
+                with_timeout(idle_timeout, entries.shutdown()).await?;
+                let counter = entries.into_inner().into_inner().into_inner().into_inner();
+                session.stream_sent(raw, counter.written as u64, counter.busy, batch_start.elapsed(), session.compression)?;
+
+                // handle responses of client, one per file
+                let responses = with_timeout(idle_timeout, reader.read_message::<Vec<FileHashResponse>>()).await?;
+                session.solid_checked(&mut schedule, sent, responses)?;
+                continue
+            }
+        };
+        let (abs_path, rel_path) = &shared.files[index];
+        let path_bytes = maybe_encrypt_path(rel_path, &mut encryptor);
+
+        // send dir
+        if metadata.is_dir() {
+            with_timeout(idle_timeout, write_message(&mut stream, &DirHeader{ path: path_bytes })).await?;
+        }
+        // send file
+        else { for attempt in first_attempt.. {
+            // check for cached hash
+            let hash = shared.cached_hash(abs_path, &metadata);
+            let precomputed_hash = hash.is_some();
+
+            // check in Resume List
+            if hash.is_some_and(|hash| schedule.is_resumed(hash)) {
+                break
+            }
+
+            // open file
+            let mut file = tokio::fs::File::open(abs_path.as_path()).await
+                .map_err(SmdError::io(abs_path.as_path()))?;
+            // skip compressing incompressible files (older clients expect every file compressed)
+            let file_compression = session.compression && (!session.per_file_compression()
+                || sample_compressibility(abs_path, &mut file).await.map_err(SmdError::io(abs_path.as_path()))?);
+
+            // send header
+            with_timeout(idle_timeout, write_message(&mut stream, &FileHeader{
+                path: path_bytes.clone(),
+                size: metadata.len(),
+                compressed: session.per_file_compression().then_some(file_compression)
+            })).await?;
+            handler.send(ServerEvent::FileStarted{ client: socket, rel_path: rel_path.to_string_lossy().into(), size: metadata.len() })?;
+            let file_start = std::time::Instant::now();
+
+            // wrap stream into rate limiter, Byte counter, frames and encryptor
+            let writer = CountingWriter::new(RateLimitedWriter::new(&mut stream, &session.limiters));
+            let writer = PerhapsEncrWriter::with_encryptor(FramedWriter::new(writer), &mut encryptor);
+            // into compressor
+            let writer = PerhapsCompressedWriter::with_params(writer, session.codec.filter(|_| file_compression), session.level(), session.dictionary, &session.params)?;
+            // and into hasher
+            let mut writer = PerhapsHashingWriter::with_hash(writer, hash);
+
+            // send file
+            let mut buffer = vec![0u8; 1 << 13];
+            let mut raw = 0;
+            loop {
+                let read = file.read(&mut buffer).await.map_err(SmdError::io(abs_path.as_path()))?;
+                if read == 0 {
+                    break
+                }
+                with_timeout(idle_timeout, writer.write_all(&buffer[..read])).await?;
+                handler.send(ServerEvent::BytesSent{ client: socket, bytes: read })?;
+                raw += read as u64;
+
+                // the compressor might not have produced any output for a while
+                if let Some(interval) = heartbeat_interval {
+                    with_timeout(idle_timeout, writer.get_mut().get_mut().get_mut().heartbeat_if_idle(interval)).await?;
+                }
+            }
+
+            // finish compression and frames
+            with_timeout(idle_timeout, writer.shutdown()).await?;
+            // get hash, and add sent bytes
+            let (compressor, hash) = writer.finalize();
+            let counter = compressor.into_inner().into_inner().into_inner();
+            // the time not spent waiting for the connection went to reading and compressing
+            session.stream_sent(raw, counter.written as u64, counter.busy, file_start.elapsed(), file_compression)?;
+
+            // send Hash
+            with_timeout(idle_timeout, write_message(&mut stream, &FileHash{ hash })).await?;
+            // cache hash
+            if !precomputed_hash {
+                shared.cache_hash(abs_path, hash, &metadata)?;
+            }
+
+            // handle response of client
+            let response = with_timeout(idle_timeout, reader.read_message::<FileHashResponse>()).await?;
+            if session.file_checked(response, attempt, rel_path)? {
+                break
+            }
+        }}
+    }
+
+    session.finish()
+}
+
+
+/// Whether the `file` at `path` is worth compressing, judging by its start.
+/// Rewinds the file afterwards.
+async fn sample_compressibility(path: &std::path::Path, file: &mut tokio::fs::File) -> std::io::Result<bool> {
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    (&mut *file).take(SAMPLE_SIZE as u64).read_to_end(&mut sample).await?;
+    file.rewind().await?;
+    Ok(worth_compressing(path, &sample))
+}
@@ -0,0 +1,84 @@
+use super::CompressionLevel;
+use crate::CompressionAlgorithm;
+use crate::buffered_io::comp_io::BROTLI_MAX_LEVEL;
+
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+
+/// zstd levels picked automatically (higher ones need far more memory)
+pub(super) const AUTO_LEVELS: RangeInclusive<u8> = 1..=19;
+/// zstd's default, a fair guess for most connections
+const AUTO_START: u8 = 3;
+/// Files sent quicker than this are judged together with the following ones
+const MIN_SAMPLE: Duration = Duration::from_millis(200);
+/// Share of time spent waiting for the connection,
+/// above which the CPU has time to spare for a higher level
+/// (compressing overlaps with the connection draining its buffers)
+const RAISE_ABOVE: f64 = 0.3;
+/// and below which compressing keeps the connection from being used fully
+const LOWER_BELOW: f64 = 0.1;
+
+
+/// Picks the zstd level for a client's next file, from how its previous files went.
+///
+/// Waiting for the connection most of the time means the compressor could do more,
+/// rarely waiting for it means the compressor is holding it back.
+pub(super) struct LevelTuner {
+    level: u8,
+    auto: bool,
+    /// Highest level of the codec picked automatically
+    max: u8,
+    /// Sums of the files not judged yet
+    waiting: Duration,
+    total: Duration
+}
+
+impl LevelTuner {
+    pub fn new(level: CompressionLevel, codec: Option<CompressionAlgorithm>) -> Self {
+        let (level, auto) = match level {
+            CompressionLevel::Fixed(level) => (level, false),
+            CompressionLevel::Auto => (AUTO_START, true)
+        };
+        // lz4 has no levels to pick from
+        let (auto, max) = match codec {
+            Some(CompressionAlgorithm::Zstd) => (auto, *AUTO_LEVELS.end()),
+            Some(CompressionAlgorithm::Brotli) => (auto, BROTLI_MAX_LEVEL),
+            _ => (false, level)
+        };
+        Self{ level, auto, max, waiting: Duration::ZERO, total: Duration::ZERO }
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// A compressed file took `total`, out of which `waiting` went to waiting for the connection.
+    /// Returns the new level, if it changed.
+    pub fn file_sent(&mut self, waiting: Duration, total: Duration) -> Option<u8> {
+        if !self.auto {
+            return None
+        }
+        self.waiting += waiting;
+        self.total += total;
+        if self.total < MIN_SAMPLE {
+            return None
+        }
+
+        let share = self.waiting.as_secs_f64() / self.total.as_secs_f64();
+        self.waiting = Duration::ZERO;
+        self.total = Duration::ZERO;
+        let level = if share > RAISE_ABOVE {
+            self.level + 1
+        } else if share < LOWER_BELOW {
+            self.level - 1
+        } else {
+            self.level
+        }.clamp(*AUTO_LEVELS.start(), self.max);
+
+        (level != self.level).then(|| {
+            self.level = level;
+            level
+        })
+    }
+}
@@ -0,0 +1,240 @@
+use bytesize::ByteSize;
+
+use crate::buffered_io::counting_io::compression_ratio;
+use crate::transport::PeerAddr;
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+
+#[derive(Debug)]
+pub enum ServerEvent {
+    /// All files have been collected and the listener is bound
+    /// `dictionary` is the size of the zstd dictionary compressed with, if any
+    Listening{ address: String, total_size: u64, dictionary: Option<usize> },
+    /// Accepting the next client failed (e.g. while out of file descriptors), trying again in `retry_in`
+    AcceptFailed{ error: String, retry_in: Duration },
+    ClientConnected(PeerAddr),
+    /// All workers are busy, `waiting` clients (including this one) wait for one
+    ClientQueued{ client: PeerAddr, waiting: usize },
+    /// `resume_list` is the amount of files being (maybe) skipped
+    HandShake{ client: PeerAddr, version: String, compression: bool, resume_list: Option<usize> },
+    FileStarted{ client: PeerAddr, rel_path: String, size: u64 },
+    /// Additional (uncompressed) Bytes sent
+    BytesSent{ client: PeerAddr, bytes: usize },
+    /// The hashes did not match, the file is being sent again
+    FileRetried(PeerAddr),
+    /// The automatic zstd level of this client changed, for its following files
+    CompressionLevelChanged{ client: PeerAddr, level: u8 },
+    /// A file (or solid stream of files) has been sent, its `raw` Bytes took `wire` Bytes on the wire
+    /// (including frames, encryption and compression)
+    StreamSent{ client: PeerAddr, raw: u64, wire: u64 },
+    /// `raw` and `wire` are the sums of all [`ServerEvent::StreamSent`] (including resent files)
+    ClientFinished{ client: PeerAddr, raw: u64, wire: u64, time_taken: Duration },
+    ClientFailed{ client: PeerAddr, error: String },
+    /// The client has not responded for `timeout` and was disconnected
+    ClientSilent{ client: PeerAddr, timeout: Duration }
+}
+
+
+pub struct ServerEventWriter<'a, W: Write>{
+    writer: W,
+    client: PeerAddr,
+    sender: &'a Sender<ServerEvent>
+}
+
+impl<'a, W: Write> ServerEventWriter<'a, W> {
+    pub fn new(writer: W, client: PeerAddr, sender: &'a Sender<ServerEvent>) -> Self {
+        Self{ writer, client, sender }
+    }
+
+    pub fn inner(self) -> W {
+        self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+}
+
+impl<'a, W: Write> Write for ServerEventWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf);
+        if let Ok(written) = written {
+            self.sender.send(ServerEvent::BytesSent{ client: self.client, bytes: written }).expect("Channel has been poisoned! (Please report bug!)");
+        }
+        written
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+
+/// The state of one connected client, as shown in the live table
+struct ClientRow {
+    rel_path: String,
+    file_size: u64,
+    file_sent: u64,
+    total_sent: u64,
+    /// Of the streams sent so far
+    streams_raw: u64,
+    streams_wire: u64,
+    retries: usize
+}
+
+/// How often the live table is redrawn (at most)
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+
+pub fn handle_events_cli(recv: Receiver<ServerEvent>) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    let mut clients: BTreeMap<PeerAddr, ClientRow> = BTreeMap::new();
+    let mut table_lines = 0;
+    let mut last_draw = Instant::now();
+    let mut dirty = false;
+
+    loop {
+        let msg = match recv.recv_timeout(REDRAW_INTERVAL) {
+            Ok(msg) => msg,
+            Err(RecvTimeoutError::Timeout) => {
+                if dirty {
+                    clear_table(&mut stdout, table_lines)?;
+                    table_lines = draw_table(&mut stdout, &clients)?;
+                    last_draw = Instant::now();
+                    dirty = false;
+                }
+                continue
+            },
+            Err(RecvTimeoutError::Disconnected) => break
+        };
+
+        // discrete events are logged above the table
+        let log = match msg {
+            ServerEvent::Listening{ address, total_size, dictionary } => {
+                let dictionary = dictionary.map(|size| format!(", compressing with a {} dictionary", ByteSize(size as u64))).unwrap_or_default();
+                Some(format!("Listening on {address}, hosting {}{dictionary}", ByteSize(total_size)))
+            },
+            ServerEvent::AcceptFailed{ error, retry_in } => {
+                Some(format!("Could not accept a client: {error} (trying again in {retry_in:?})"))
+            },
+            ServerEvent::ClientConnected(client) => {
+                clients.insert(client, ClientRow{ rel_path: String::new(), file_size: 0, file_sent: 0, total_sent: 0, streams_raw: 0, streams_wire: 0, retries: 0 });
+                Some(format!("Client arrived: {client}"))
+            },
+            ServerEvent::ClientQueued{ client, waiting } => {
+                Some(format!("{client} is queued, {waiting} client(s) waiting"))
+            },
+            ServerEvent::HandShake{ client, version, compression, resume_list } => {
+                let mut line = format!("{client} sent handshake (version {version})");
+                if compression {
+                    line.push_str(", requesting compression");
+                }
+                if let Some(hashes) = resume_list {
+                    line.push_str(&format!(", resuming with {hashes} hashes"));
+                }
+                Some(line)
+            },
+            ServerEvent::FileStarted{ client, rel_path, size } => {
+                if let Some(row) = clients.get_mut(&client) {
+                    row.rel_path = rel_path;
+                    row.file_size = size;
+                    row.file_sent = 0;
+                }
+                None
+            },
+            ServerEvent::BytesSent{ client, bytes } => {
+                if let Some(row) = clients.get_mut(&client) {
+                    row.file_sent += bytes as u64;
+                    row.total_sent += bytes as u64;
+                }
+                None
+            },
+            ServerEvent::FileRetried(client) => {
+                clients.get_mut(&client).map(|row| {
+                    row.retries += 1;
+                    format!("{client}: Hashes of {} did NOT Match... Retrying...", row.rel_path)
+                })
+            },
+            ServerEvent::StreamSent{ client, raw, wire } => {
+                if let Some(row) = clients.get_mut(&client) {
+                    row.streams_raw += raw;
+                    row.streams_wire += wire;
+                }
+                None
+            },
+            ServerEvent::CompressionLevelChanged{ client, level } => {
+                Some(format!("{client}: compressing at level {level}"))
+            },
+            ServerEvent::ClientFinished{ client, raw, wire, time_taken } => {
+                clients.remove(&client);
+                let speed = raw as f64 / time_taken.as_secs_f64().max(f64::EPSILON);
+                Some(format!("{client} finished: {} in {time_taken:.1?} ({}/s) - {} on the wire - ratio: {}",
+                             ByteSize(raw), ByteSize(speed as u64), ByteSize(wire), format_ratio(raw, wire)))
+            },
+            ServerEvent::ClientFailed{ client, error } => {
+                clients.remove(&client);
+                Some(format!("{client} failed: {error}"))
+            },
+            ServerEvent::ClientSilent{ client, timeout } => {
+                clients.remove(&client);
+                Some(format!("{client} has not responded for {timeout:?}, disconnected."))
+            }
+        };
+        dirty = true;
+
+        if log.is_some() || last_draw.elapsed() > REDRAW_INTERVAL {
+            clear_table(&mut stdout, table_lines)?;
+            if let Some(line) = log {
+                writeln!(&mut stdout, "{line}")?;
+            }
+            table_lines = draw_table(&mut stdout, &clients)?;
+            last_draw = Instant::now();
+            dirty = false;
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Move the cursor up to the start of the table and clear everything below
+fn clear_table(stdout: &mut impl Write, lines: usize) -> std::io::Result<()> {
+    if lines > 0 {
+        write!(stdout, "\x1b[{lines}A\x1b[J")?;
+    }
+    Ok(())
+}
+
+/// Returns the amount of lines drawn
+fn draw_table(stdout: &mut impl Write, clients: &BTreeMap<PeerAddr, ClientRow>) -> std::io::Result<usize> {
+    if clients.is_empty() {
+        return Ok(0)
+    }
+
+    writeln!(stdout, "{:<22} {:<40} {:>23} {:>10} {:>6} {:>7}", "Client", "File", "Progress", "Sent", "Ratio", "Retries")?;
+    for (client, row) in clients {
+        // only show the end of long paths
+        let path_len = row.rel_path.chars().count();
+        let rel_path = if path_len > 40 {
+            format!("…{}", row.rel_path.chars().skip(path_len - 39).collect::<String>())
+        } else {
+            row.rel_path.clone()
+        };
+        let progress = format!("{}/{}", ByteSize(row.file_sent), ByteSize(row.file_size));
+        writeln!(stdout, "{:<22} {rel_path:<40} {progress:>23} {:>10} {:>6} {:>7}",
+                 client.to_string(), ByteSize(row.total_sent).to_string(), format_ratio(row.streams_raw, row.streams_wire), row.retries)?;
+    }
+    stdout.flush()?;
+
+    Ok(clients.len() + 1)
+}
+
+/// e.g. "2.41", or "-" before anything has been sent
+fn format_ratio(raw: u64, wire: u64) -> String {
+    compression_ratio(raw, wire).map_or_else(|| "-".into(), |ratio| format!("{ratio:.2}"))
+}
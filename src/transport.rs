@@ -0,0 +1,157 @@
+pub mod tcp;
+#[cfg(unix)]
+pub mod unix;
+pub mod memory;
+#[cfg(feature = "async")]
+pub mod tokio_net;
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+
+/// A duplex byte stream between Hoster and client.
+pub trait Transport: Read + Write + Send {
+    /// Another handle to the same stream (e.g. one for reading, one for writing)
+    fn try_clone_boxed(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// `None` blocks forever. Timed out reads fail with `TimedOut` or `WouldBlock`.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Closes both directions, also for all other handles
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Applies what `options` set and the stream supports
+    fn tune(&self, _options: &SocketOptions) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The socket, for moving file contents to and from it within the kernel
+    /// (see [`crate::buffered_io::zero_copy`]). None for streams without one.
+    #[cfg(unix)]
+    fn socket_fd(&self) -> Option<std::os::fd::BorrowedFd<'_>> {
+        None
+    }
+}
+
+/// Accepts incoming [`Transport`]s on the Hoster's side.
+pub trait Listener: Send {
+    /// Does not block, `None` if nobody is waiting.
+    fn accept(&self) -> io::Result<Option<(Box<dyn Transport>, PeerAddr)>>;
+
+    /// Applies `options` to the listening socket (passing most of them on to accepted clients),
+    /// to find out about unsupported ones before the first client does
+    fn tune(&self, _options: &SocketOptions) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Where clients can connect to, for displaying
+    fn local_addr(&self) -> String;
+}
+
+/// Opens [`Transport`]s on the client's side.
+pub trait Connector: Send + Sync {
+    fn connect(&self) -> io::Result<Box<dyn Transport>>;
+}
+
+
+/// Tuning of the sockets, for connections with lots of bandwidth or latency.
+/// Unset options keep the OS defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// In Bytes (SO_SNDBUF, the kernel might double or cap it)
+    pub send_buffer: Option<usize>,
+    /// In Bytes (SO_RCVBUF, the kernel might double or cap it)
+    pub recv_buffer: Option<usize>,
+    /// Send small messages right away, rather than waiting to fill a packet (TCP_NODELAY)
+    pub nodelay: bool,
+    /// The TCP congestion control algorithm, like "bbr" or "cubic" (Linux only)
+    pub congestion: Option<String>
+}
+
+/// Applies `options` to a socket, the TCP ones only if it is `tcp`
+#[cfg(unix)]
+pub(crate) fn tune_socket(socket: std::os::fd::BorrowedFd<'_>, tcp: bool, options: &SocketOptions) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+    // (naming the option, the errno alone is not very telling)
+    let failed = |option: String| move |e: nix::errno::Errno| io::Error::new(io::Error::from(e).kind(), format!("Could not set {option}: {e}"));
+
+    if let Some(size) = options.send_buffer {
+        setsockopt(&socket, sockopt::SndBuf, &size).map_err(failed(format!("the send buffer to {size} B")))?;
+    }
+    if let Some(size) = options.recv_buffer {
+        setsockopt(&socket, sockopt::RcvBuf, &size).map_err(failed(format!("the receive buffer to {size} B")))?;
+    }
+    if !tcp {
+        return Ok(())
+    }
+    if options.nodelay {
+        setsockopt(&socket, sockopt::TcpNoDelay, &true).map_err(failed("TCP_NODELAY".into()))?;
+    }
+    if let Some(algorithm) = &options.congestion {
+        #[cfg(target_os = "linux")]
+        setsockopt(&socket, sockopt::TcpCongestion, &algorithm.into())
+            .map_err(failed(format!("the congestion control algorithm to \"{algorithm}\"")))?;
+        #[cfg(not(target_os = "linux"))]
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("Choosing a congestion control algorithm (\"{algorithm}\") is only supported on Linux")));
+    }
+    Ok(())
+}
+
+/// Only TCP_NODELAY is supported elsewhere
+#[cfg(not(unix))]
+pub(crate) fn check_portable(options: &SocketOptions) -> io::Result<()> {
+    if options.send_buffer.is_some() || options.recv_buffer.is_some() || options.congestion.is_some() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "Only --nodelay is supported on this platform"))
+    }
+    Ok(())
+}
+
+
+/// Identifies a connected client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PeerAddr {
+    Tcp(std::net::SocketAddr),
+    /// Unix domain sockets are mostly unnamed, so they are numbered instead
+    Unix(u64),
+    Memory(u64)
+}
+
+impl std::fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp(address) => write!(f, "{address}"),
+            PeerAddr::Unix(number) => write!(f, "unix#{number}"),
+            PeerAddr::Memory(number) => write!(f, "memory#{number}"),
+        }
+    }
+}
+
+
+/// Prefix of addresses referring to Unix domain sockets
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Listen on `address`, a socket address or `unix:/path/to/socket`
+pub fn bind(address: &str) -> io::Result<Box<dyn Listener>> {
+    match address.strip_prefix(UNIX_PREFIX) {
+        #[cfg(unix)]
+        Some(path) => Ok(Box::new(unix::UnixListener::bind(path)?)),
+        #[cfg(not(unix))]
+        Some(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform")),
+        None => Ok(Box::new(tcp::TcpListener::bind(address)?))
+    }
+}
+
+/// Connects to a socket address or `unix:/path/to/socket`
+impl Connector for String {
+    fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        match self.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            Some(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform")),
+            None => Ok(Box::new(std::net::TcpStream::connect(self)?))
+        }
+    }
+}
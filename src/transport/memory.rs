@@ -0,0 +1,197 @@
+use super::{Connector, Listener, PeerAddr, Transport};
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::time::Duration;
+
+
+/// Bytes in flight per direction, before writes block
+const PIPE_CAPACITY: usize = 1 << 20;
+
+
+/// One direction of a [`MemoryStream`]
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    changed: Condvar
+}
+
+#[derive(Default)]
+struct PipeState {
+    buffer: VecDeque<u8>,
+    closed: bool
+}
+
+impl Pipe {
+    /// Waits (at most `timeout`) while `condition` holds
+    fn wait_while<F: FnMut(&mut PipeState) -> bool>(&self, timeout: Option<Duration>, condition: F) -> io::Result<MutexGuard<'_, PipeState>> {
+        let state = self.state.lock().unwrap();
+        match timeout {
+            None => Ok(self.changed.wait_while(state, condition).unwrap()),
+            Some(timeout) => {
+                let (state, result) = self.changed.wait_timeout_while(state, timeout, condition).unwrap();
+                if result.timed_out() {
+                    return Err(io::ErrorKind::TimedOut.into())
+                }
+                Ok(state)
+            }
+        }
+    }
+
+    fn read(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        let mut state = self.wait_while(timeout, |s| s.buffer.is_empty() && !s.closed)?;
+
+        // (nothing left in a closed pipe means EOF)
+        let amount = min(buf.len(), state.buffer.len());
+        for (dist, byte) in buf.iter_mut().zip(state.buffer.drain(..amount)) {
+            *dist = byte;
+        }
+        self.changed.notify_all();
+        Ok(amount)
+    }
+
+    fn write(&self, buf: &[u8], timeout: Option<Duration>) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        let mut state = self.wait_while(timeout, |s| s.buffer.len() >= PIPE_CAPACITY && !s.closed)?;
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        let amount = min(buf.len(), PIPE_CAPACITY - state.buffer.len());
+        state.buffer.extend(&buf[..amount]);
+        self.changed.notify_all();
+        Ok(amount)
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+
+/// One side of a [`pipe`], shared by all its handles
+struct End {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+
+/// In-process duplex stream, e.g. for testing.
+/// Closed, once all handles of one side are dropped.
+pub struct MemoryStream {
+    end: Arc<End>
+}
+
+/// Two connected [`MemoryStream`]s
+pub fn pipe() -> (MemoryStream, MemoryStream) {
+    let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+    let end = |incoming, outgoing| MemoryStream{ end: Arc::new(End{
+        incoming,
+        outgoing,
+        read_timeout: Mutex::new(None),
+        write_timeout: Mutex::new(None)
+    })};
+    (end(a.clone(), b.clone()), end(b, a))
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.end.read_timeout.lock().unwrap();
+        self.end.incoming.read(buf, timeout)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let timeout = *self.end.write_timeout.lock().unwrap();
+        self.end.outgoing.write(buf, timeout)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryStream {
+    fn try_clone_boxed(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(MemoryStream{ end: self.end.clone() }))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.end.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.end.write_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.end.incoming.close();
+        self.end.outgoing.close();
+        Ok(())
+    }
+}
+
+
+pub struct MemoryListener {
+    incoming: Receiver<MemoryStream>,
+    accepted: AtomicU64
+}
+
+/// Connects to the [`MemoryListener`] it was created with
+#[derive(Clone)]
+pub struct MemoryConnector {
+    listener: Sender<MemoryStream>
+}
+
+/// A listener and a connector for it, to run Hoster and client in the same process
+pub fn channel() -> (MemoryListener, MemoryConnector) {
+    let (send, recv) = std::sync::mpsc::channel();
+    (MemoryListener{ incoming: recv, accepted: AtomicU64::new(0) }, MemoryConnector{ listener: send })
+}
+
+impl Listener for MemoryListener {
+    fn accept(&self) -> io::Result<Option<(Box<dyn Transport>, PeerAddr)>> {
+        match self.incoming.try_recv() {
+            Ok(stream) => {
+                let number = self.accepted.fetch_add(1, Ordering::Relaxed);
+                Ok(Some((Box::new(stream), PeerAddr::Memory(number))))
+            },
+            // (connectors being gone does not stop the server)
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => Ok(None)
+        }
+    }
+
+    fn local_addr(&self) -> String {
+        "memory".to_owned()
+    }
+}
+
+impl Connector for MemoryConnector {
+    fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        let (ours, theirs) = pipe();
+        self.listener.send(theirs).map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(Box::new(ours))
+    }
+}
@@ -0,0 +1,87 @@
+use super::{Listener, PeerAddr, SocketOptions, Transport};
+
+use std::io;
+use std::net::{self, TcpStream};
+use std::time::Duration;
+
+
+impl Transport for TcpStream {
+    fn try_clone_boxed(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    #[cfg(unix)]
+    fn socket_fd(&self) -> Option<std::os::fd::BorrowedFd<'_>> {
+        Some(std::os::fd::AsFd::as_fd(self))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, net::Shutdown::Both)
+    }
+
+    #[cfg(unix)]
+    fn tune(&self, options: &SocketOptions) -> io::Result<()> {
+        super::tune_socket(std::os::fd::AsFd::as_fd(self), true, options)
+    }
+
+    #[cfg(not(unix))]
+    fn tune(&self, options: &SocketOptions) -> io::Result<()> {
+        super::check_portable(options)?;
+        if options.nodelay {
+            self.set_nodelay(true)?;
+        }
+        Ok(())
+    }
+}
+
+
+pub struct TcpListener {
+    listener: net::TcpListener,
+    address: String
+}
+
+impl TcpListener {
+    pub fn bind(address: &str) -> io::Result<Self> {
+        let listener = net::TcpListener::bind(address)?;
+        // (polled, to be able to stop)
+        listener.set_nonblocking(true)?;
+        // (the port actually bound, in case it was chosen by the OS)
+        let address = listener.local_addr()?.to_string();
+        Ok(Self{ listener, address })
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<Option<(Box<dyn Transport>, PeerAddr)>> {
+        match self.listener.accept() {
+            Ok((stream, address)) => {
+                stream.set_nonblocking(false)?;
+                Ok(Some((Box::new(stream), PeerAddr::Tcp(address))))
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn local_addr(&self) -> String {
+        self.address.clone()
+    }
+
+    #[cfg(unix)]
+    fn tune(&self, options: &SocketOptions) -> io::Result<()> {
+        super::tune_socket(std::os::fd::AsFd::as_fd(&self.listener), true, options)
+    }
+
+    #[cfg(not(unix))]
+    fn tune(&self, options: &SocketOptions) -> io::Result<()> {
+        super::check_portable(options)
+    }
+}
@@ -0,0 +1,129 @@
+use super::{PeerAddr, SocketOptions, UNIX_PREFIX};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use std::io;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+
+/// A duplex [`tokio`] byte stream between Hoster and client.
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncTransport for T {}
+
+
+/// Accepts incoming [`AsyncTransport`]s on the Hoster's side.
+/// A Unix socket file is removed when dropped.
+pub enum AsyncListener {
+    Tcp{ listener: tokio::net::TcpListener, address: String },
+    #[cfg(unix)]
+    Unix{ listener: tokio::net::UnixListener, path: PathBuf, accepted: u64 }
+}
+
+impl AsyncListener {
+    /// Listen on `address`, a socket address or `unix:/path/to/socket`
+    pub async fn bind(address: &str) -> io::Result<Self> {
+        match address.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => {
+                // a socket left behind by a killed Hoster (nobody is listening on it anymore)
+                if tokio::net::UnixStream::connect(path).await.is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused) {
+                    tokio::fs::remove_file(path).await?;
+                }
+                Ok(Self::Unix{ listener: tokio::net::UnixListener::bind(path)?, path: path.into(), accepted: 0 })
+            },
+            #[cfg(not(unix))]
+            Some(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform")),
+            None => {
+                let listener = tokio::net::TcpListener::bind(address).await?;
+                // (the port actually bound, in case it was chosen by the OS)
+                let address = listener.local_addr()?.to_string();
+                Ok(Self::Tcp{ listener, address })
+            }
+        }
+    }
+
+    /// Waits for the next client (cancel safe), applying `options` to it.
+    /// Fails if the listener does, the client fails on its own (if `options` can not be applied).
+    pub async fn accept(&mut self, options: &SocketOptions) -> io::Result<(io::Result<Box<dyn AsyncTransport>>, PeerAddr)> {
+        match self {
+            Self::Tcp{ listener, .. } => {
+                let (stream, address) = listener.accept().await?;
+                let client = tune_tcp(&stream, options).map(|()| Box::new(stream) as Box<dyn AsyncTransport>);
+                Ok((client, PeerAddr::Tcp(address)))
+            },
+            #[cfg(unix)]
+            Self::Unix{ listener, accepted, .. } => {
+                let (stream, _) = listener.accept().await?;
+                let client = super::tune_socket(std::os::fd::AsFd::as_fd(&stream), false, options)
+                    .map(|()| Box::new(stream) as Box<dyn AsyncTransport>);
+                *accepted += 1;
+                Ok((client, PeerAddr::Unix(*accepted - 1)))
+            }
+        }
+    }
+
+    /// Like [`Listener::tune`](super::Listener::tune)
+    pub fn tune(&self, options: &SocketOptions) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Self::Tcp{ listener, .. } => super::tune_socket(std::os::fd::AsFd::as_fd(listener), true, options),
+            #[cfg(not(unix))]
+            Self::Tcp{ .. } => super::check_portable(options),
+            #[cfg(unix)]
+            Self::Unix{ listener, .. } => super::tune_socket(std::os::fd::AsFd::as_fd(listener), false, options)
+        }
+    }
+
+    /// Where clients can connect to, for displaying
+    pub fn local_addr(&self) -> String {
+        match self {
+            Self::Tcp{ address, .. } => address.clone(),
+            #[cfg(unix)]
+            Self::Unix{ path, .. } => format!("{UNIX_PREFIX}{}", path.display())
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for AsyncListener {
+    fn drop(&mut self) {
+        if let Self::Unix{ path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+
+/// Connects to a socket address or `unix:/path/to/socket`, applying `options`
+pub async fn connect(address: &str, options: &SocketOptions) -> io::Result<Box<dyn AsyncTransport>> {
+    match address.strip_prefix(UNIX_PREFIX) {
+        #[cfg(unix)]
+        Some(path) => {
+            let stream = tokio::net::UnixStream::connect(path).await?;
+            super::tune_socket(std::os::fd::AsFd::as_fd(&stream), false, options)?;
+            Ok(Box::new(stream))
+        },
+        #[cfg(not(unix))]
+        Some(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform")),
+        None => {
+            let stream = tokio::net::TcpStream::connect(address).await?;
+            tune_tcp(&stream, options)?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+fn tune_tcp(stream: &tokio::net::TcpStream, options: &SocketOptions) -> io::Result<()> {
+    #[cfg(unix)]
+    return super::tune_socket(std::os::fd::AsFd::as_fd(stream), true, options);
+    #[cfg(not(unix))]
+    {
+        super::check_portable(options)?;
+        if options.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use super::{Listener, PeerAddr, SocketOptions, Transport, UNIX_PREFIX};
+
+use std::io;
+use std::os::unix::net::{self, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+
+impl Transport for UnixStream {
+    fn try_clone_boxed(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    #[cfg(unix)]
+    fn socket_fd(&self) -> Option<std::os::fd::BorrowedFd<'_>> {
+        Some(std::os::fd::AsFd::as_fd(self))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, std::net::Shutdown::Both)
+    }
+
+    fn tune(&self, options: &SocketOptions) -> io::Result<()> {
+        super::tune_socket(std::os::fd::AsFd::as_fd(self), false, options)
+    }
+}
+
+
+/// Removes its socket file when dropped
+pub struct UnixListener {
+    listener: net::UnixListener,
+    path: PathBuf,
+    accepted: AtomicU64
+}
+
+impl UnixListener {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        // a socket left behind by a killed Hoster (nobody is listening on it anymore)
+        if UnixStream::connect(&path).is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused) {
+            std::fs::remove_file(&path)?;
+        }
+        let listener = net::UnixListener::bind(&path)?;
+        // (polled, to be able to stop)
+        listener.set_nonblocking(true)?;
+        Ok(Self{ listener, path: path.as_ref().to_owned(), accepted: AtomicU64::new(0) })
+    }
+}
+
+impl Listener for UnixListener {
+    fn accept(&self) -> io::Result<Option<(Box<dyn Transport>, PeerAddr)>> {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                let number = self.accepted.fetch_add(1, Ordering::Relaxed);
+                Ok(Some((Box::new(stream), PeerAddr::Unix(number))))
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn local_addr(&self) -> String {
+        format!("{UNIX_PREFIX}{}", self.path.display())
+    }
+
+    fn tune(&self, options: &SocketOptions) -> io::Result<()> {
+        super::tune_socket(std::os::fd::AsFd::as_fd(&self.listener), false, options)
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -1,9 +1,61 @@
 use simple_mass_data_transfer::client_events::ClientEvent;
-use crate::cli::Args;
+use simple_mass_data_transfer::control::{ControlState, TransferControl};
+use simple_mass_data_transfer::SmdError;
+use simple_mass_data_transfer::client::Client;
+use simple_mass_data_transfer::buffered_io::{RateLimiter, counting_io::compression_ratio};
 
 use eframe::Frame;
 use egui::Context;
 
+mod host;
+
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Tab {
+	Download,
+	Host
+}
+
+
+pub struct SmdUi {
+	tab: Tab,
+	client: ClientUi,
+	host: host::HostUi
+}
+
+impl Default for SmdUi {
+	fn default() -> Self {
+		Self{
+			tab: Tab::Download,
+			client: ClientUi::default(),
+			host: host::HostUi::default()
+		}
+	}
+}
+
+impl eframe::App for SmdUi {
+	fn update(&mut self, ctx: &Context, frame: &mut Frame) {
+		egui::TopBottomPanel::top("Tabs").show(ctx, |ui| {
+			ui.horizontal(|ui| {
+				ui.selectable_value(&mut self.tab, Tab::Download, "Download");
+				ui.selectable_value(&mut self.tab, Tab::Host, "Host");
+			});
+		});
+
+		// keep handling the events of the hidden tab
+		match self.tab {
+			Tab::Download => {
+				self.host.handle_events();
+				eframe::App::update(&mut self.client, ctx, frame)
+			},
+			Tab::Host => {
+				self.client.handle_events();
+				eframe::App::update(&mut self.host, ctx, frame)
+			}
+		}
+	}
+}
+
 
 #[derive(Debug)]
 struct File {
@@ -15,16 +67,25 @@ struct File {
 
 pub struct ClientUi {
     address: String,
-    connected: Option<std::thread::JoinHandle<anyhow::Result<()>>>,
+    connected: Option<std::thread::JoinHandle<Result<(), SmdError>>>,
 	path: Option<String>,
 	encryption_key: String,
 	compressed: bool,
 	downloaded_bytes: u64,
 	total_bytes: u64,
 	bytes_per_sec: f32,
+	/// Of the streams received so far
+	streams_raw: u64,
+	streams_wire: u64,
 	event_stream: Option<std::sync::mpsc::Receiver<ClientEvent>>,
 	dl_speed_timer: std::time::Instant,
 	files: Vec<File>,
+	control: TransferControl,
+	/// Per second, e.g. "2 MB" (empty for unlimited)
+	limit_rate: String,
+	rate_limit: RateLimiter,
+	/// Shown while reconnecting or waiting for the Hoster
+	notice: Option<String>,
 	popup: Vec<(String, bool)>
 }
 
@@ -33,46 +94,8 @@ impl ClientUi {
 	fn popup(&mut self, text: String) {
 		self.popup.push((text, true))
 	}
-}
-
-
-impl Default for ClientUi {
-	fn default() -> Self {
-		Self{
-			address: "".to_string(),
-			connected: None,
-			path: None,
-			encryption_key: "".to_string(),
-			compressed: true,
-			downloaded_bytes: 0,
-			total_bytes: 0,
-			bytes_per_sec: 0.,
-			event_stream: None,
-			dl_speed_timer: std::time::Instant::now(),
-			files: Vec::new(),
-			popup: Default::default()
-		}
-	}
-}
-
 
-impl eframe::App for ClientUi {
-    fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
-		// display all popup messages
-		for (i, (text, open)) in self.popup.iter_mut().enumerate() {
-			egui::Window::new(format!("Alert - {i}"))
-				.collapsible(false)
-				.resizable(true)
-				.open(open)
-				.show(ctx, |ui| {
-					ui.vertical_centered(|ui| {
-						ui.label(text.as_str())
-					});
-				});
-		}
-		self.popup.retain(|popup| popup.1);
-
-		// handle events
+	fn handle_events(&mut self) {
 		while let Some(recv) = &self.event_stream {
 			if let Ok(event) = recv.try_recv() {
 				match event {
@@ -85,22 +108,49 @@ impl eframe::App for ClientUi {
 					ClientEvent::FileHeader{rel_path, size} => {
 						self.files.push(File{ rel_path, total_bytes: size, downloaded_bytes: 0 })
 					},
+					ClientEvent::StreamReceived{ raw, wire } => {
+						self.streams_raw += raw;
+						self.streams_wire += wire;
+					},
 					ClientEvent::FileFinished(hashes_match) => if !hashes_match {
 						let _ = self.files.pop();
 					} else {
 						self.bytes_per_sec = self.files.last().unwrap().total_bytes as f32 / self.dl_speed_timer.elapsed().as_secs_f32();
 						self.dl_speed_timer = std::time::Instant::now();
 					},
-					ClientEvent::Completed(time_taken) => {
+					ClientEvent::Completed{ time_taken, raw, wire } => {
+						let speed = raw as f64 / time_taken.as_secs_f64().max(f64::EPSILON);
+						let ratio = compression_ratio(raw, wire).map(|ratio| format!(", ratio {ratio:.2}")).unwrap_or_default();
 						self.popup(
-							format!("Download Completed in {time_taken:?} ({}/s)", bytesize::ByteSize(self.total_bytes / time_taken.as_secs()))
+							format!("Download Completed in {time_taken:?} ({}/s)\n{} on the wire{ratio}", bytesize::ByteSize(speed as u64), bytesize::ByteSize(wire))
 						)
 					},
 					ClientEvent::ResumeListFound(file_amount) => {
 						self.popup(format!("Resume List found, containing {file_amount} hashes."))
+					},
+					ClientEvent::Cancelled => {
+						self.popup("Download cancelled. Connect again to resume it.".into())
+					},
+					ClientEvent::HosterSilent(timeout) => {
+						self.notice = Some(format!("Hoster has not responded for {timeout:?}."));
+					},
+					ClientEvent::Reconnecting{ attempt, reason } => {
+						// the unfinished file will be sent again
+						if self.files.last().is_some_and(|f| f.downloaded_bytes < f.total_bytes) {
+							let file = self.files.pop().unwrap();
+							self.downloaded_bytes -= file.downloaded_bytes;
+						}
+						self.notice = Some(match attempt {
+							0 => format!("Connection lost while paused ({reason}). Reconnecting..."),
+							_ => format!("Connection lost ({reason}). Reconnecting (attempt {attempt})...")
+						});
 					}
+					ClientEvent::Queued(waiting) => {
+						self.notice = Some(format!("Hoster is full, waiting for a free slot ({waiting} in the queue)..."));
+					},
 					ClientEvent::HandShakeResponse{ compression, total_size } => {
-						if compression && !self.compressed {
+						self.notice = None;
+						if compression.is_some() && !self.compressed {
 							self.popup("Server is forcing compression.".into())
 						}
 						self.total_bytes = total_size;
@@ -111,6 +161,42 @@ impl eframe::App for ClientUi {
 				break
 			}
 		}
+	}
+}
+
+
+impl Default for ClientUi {
+	fn default() -> Self {
+		Self{
+			address: "".to_string(),
+			connected: None,
+			path: None,
+			encryption_key: "".to_string(),
+			compressed: true,
+			downloaded_bytes: 0,
+			total_bytes: 0,
+			bytes_per_sec: 0.,
+			streams_raw: 0,
+			streams_wire: 0,
+			event_stream: None,
+			dl_speed_timer: std::time::Instant::now(),
+			files: Vec::new(),
+			control: TransferControl::new(),
+			limit_rate: String::new(),
+			rate_limit: RateLimiter::default(),
+			notice: None,
+			popup: Default::default()
+		}
+	}
+}
+
+
+impl eframe::App for ClientUi {
+    fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
+		show_popups(ctx, &mut self.popup);
+
+		// handle events
+		self.handle_events();
 
 		if self.connected.is_none() {
 			egui::CentralPanel::default().show(ctx, |ui| {
@@ -144,6 +230,10 @@ impl eframe::App for ClientUi {
 					.password(true)
 					.hint_text("Encryption Key")
 				).on_hover_text("If the server is using encryption, please enter the key here.");
+				ui.horizontal(|ui| {
+					ui.label("Rate Limit");
+					rate_limit_edit(ui, &mut self.limit_rate, &self.rate_limit);
+				});
 				ui.separator();
 				
 				// Connect Button
@@ -157,19 +247,25 @@ impl eframe::App for ClientUi {
 				// only enabled when address and path is some
 				let inputs_valid = validate_socket_address(&self.address) && self.path.is_some();
 				if ui.add_enabled(inputs_valid, button).clicked() || ui.input(|i| i.key_pressed(egui::Key::Enter) && inputs_valid) {
-					// build args
-					let args = Args{
-						action: crate::cli::Action::Download {
-							address: self.address.clone(),
-							path: self.path.as_ref().unwrap().clone(),
-						},
-						encryption_key: if self.encryption_key.is_empty() { None } else { Some(self.encryption_key.clone()) },
-						compression: self.compressed,
-					};
+					// build client
+					let client = Client::new(self.address.clone(), self.path.as_ref().unwrap())
+						.with_compression(self.compressed)
+						.with_encryption_key(if self.encryption_key.is_empty() { None } else { Some(self.encryption_key.clone()) })
+						.with_rate_limit(parse_rate(&self.limit_rate).flatten());
 					// start download thread
 					let (send, recv) = std::sync::mpsc::channel();
-					let handle = std::thread::spawn(move || crate::client::connect(args, send));
+					let control = client.control();
+					self.rate_limit = client.rate_limit();
+					let handle = std::thread::spawn(move || client.download(send));
 
+					// reset progress of previous downloads
+					self.files.clear();
+					self.downloaded_bytes = 0;
+					self.streams_raw = 0;
+					self.streams_wire = 0;
+					self.dl_speed_timer = std::time::Instant::now();
+					self.notice = None;
+					self.control = control;
 					self.event_stream = Some(recv);
 					self.connected = Some(handle);
 				}
@@ -177,17 +273,17 @@ impl eframe::App for ClientUi {
 		}
 		else {
 			// if handle is finished...
-			if self.connected.as_ref().map_or(false, |h| h.is_finished()) {
+			if self.connected.as_ref().is_some_and(|h| h.is_finished()) {
 				let handle = self.connected.take().unwrap();
 				if let Err(e) = handle.join().expect("SMD-Thread panicked. This is a Bug. Please report your console output.") {
-					self.popup(format!("SMD-Transfer encountered Error: {e:?}"));
+					self.popup(format!("SMD-Transfer encountered Error: {e}"));
 					eprintln!("{e:?}")
 				}
 			}
 
 			// show general info at top
 			egui::TopBottomPanel::top("TopBar").show(ctx, |ui| {
-				ui.columns(4, |columns| {
+				ui.columns(5, |columns| {
 					columns[0].vertical_centered(|ui| {
 						ui.label("Connected to:");
 						ui.label(self.address.as_str());
@@ -204,6 +300,23 @@ impl eframe::App for ClientUi {
 
 					columns[3].vertical_centered(|ui| {
 						ui.add_enabled(false, egui::Checkbox::new(&mut !self.encryption_key.is_empty(), "Encrypted"));
+					});
+
+					columns[4].vertical_centered(|ui| {
+						match self.control.state() {
+							ControlState::Running => if ui.button("Pause").clicked() {
+								self.control.pause()
+							},
+							ControlState::Paused => if ui.button("Resume").clicked() {
+								self.control.resume()
+							},
+							ControlState::Cancelled => {
+								ui.label("Cancelling...");
+							}
+						}
+						if ui.add_enabled(!self.control.is_cancelled(), egui::Button::new("Cancel")).clicked() {
+							self.control.cancel()
+						}
 						ui.allocate_space(ui.available_size())
 					});
 				});
@@ -211,6 +324,13 @@ impl eframe::App for ClientUi {
 
 			// progress bar and speed info at bottom
 			egui::TopBottomPanel::bottom("ProgressBottom").show(ctx, |ui| {
+				if let Some(notice) = &self.notice {
+					ui.colored_label(ui.visuals().warn_fg_color, notice.as_str());
+				}
+				ui.horizontal(|ui| {
+					ui.label("Rate Limit");
+					rate_limit_edit(ui, &mut self.limit_rate, &self.rate_limit);
+				});
 				ui.horizontal(|ui| {
 					ui.label(bytesize::ByteSize(self.downloaded_bytes).to_string());
 					ui.label("out of");
@@ -222,9 +342,14 @@ impl eframe::App for ClientUi {
 					bps.push_str("/s");
 					ui.label(bps);
 					ui.separator();
+
+					if let Some(ratio) = compression_ratio(self.streams_raw, self.streams_wire) {
+						ui.label(format!("ratio {ratio:.2}"));
+						ui.separator();
+					}
 					
 					ui.add(
-						egui::ProgressBar::new((self.downloaded_bytes as f32) / (self.total_bytes as f32))
+						egui::ProgressBar::new((self.downloaded_bytes as f32) / (self.total_bytes.max(1) as f32))
 							.show_percentage()
 							.animate(true)
 					);
@@ -259,8 +384,57 @@ impl eframe::App for ClientUi {
 }
 
 
+/// Display all popup messages, and remove the closed ones
+fn show_popups(ctx: &Context, popups: &mut Vec<(String, bool)>) {
+	for (i, (text, open)) in popups.iter_mut().enumerate() {
+		egui::Window::new(format!("Alert - {i}"))
+			.collapsible(false)
+			.resizable(true)
+			.open(open)
+			.show(ctx, |ui| {
+				ui.vertical_centered(|ui| {
+					ui.label(text.as_str())
+				});
+			});
+	}
+	popups.retain(|popup| popup.1);
+}
+
+
+/// A rate in Bytes per second (e.g. "2 MB"), `Some(None)` for unlimited (empty),
+/// `None` if invalid.
+fn parse_rate(text: &str) -> Option<Option<u64>> {
+	let text = text.trim();
+	if text.is_empty() {
+		return Some(None)
+	}
+	text.parse::<bytesize::ByteSize>().ok()
+		.map(|rate| Some(rate.as_u64()).filter(|rate| *rate > 0))
+}
+
+/// Text field for a rate per second, applied to `limiter` as soon as it is valid.
+fn rate_limit_edit(ui: &mut egui::Ui, text: &mut String, limiter: &RateLimiter) {
+	let valid = parse_rate(text).is_some();
+	let response = ui.add(egui::TextEdit::singleline(text)
+		.desired_width(80.)
+		.text_color_opt((!valid).then(|| ui.visuals().error_fg_color))
+		.hint_text("unlimited")
+	).on_hover_text("Per second, e.g. \"2 MB\". Can be changed at any time.");
+	if response.changed() {
+		if let Some(rate) = parse_rate(text) {
+			limiter.set_rate(rate);
+		}
+	}
+}
+
+
 /// Roughly validate a socket address string, without checking if domain exists.
 fn validate_socket_address(address: &str) -> bool {
+    // unix domain socket
+    if let Some(path) = address.strip_prefix(simple_mass_data_transfer::transport::UNIX_PREFIX) {
+        return !path.is_empty()
+    }
+    
     let re = regex::Regex::new(
         r"(([a-z]+\.)?[a-z]+(\.[a-z]+)?:[0-9]+)|([0-9]{1,3}\.[0-9]{1,3}\.[0-9]{1,3}\.[0-9]{1,3}:[0-9]+)"
     ).unwrap();
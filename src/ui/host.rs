@@ -0,0 +1,334 @@
+use simple_mass_data_transfer::{CompressionAlgorithm, SmdError};
+use simple_mass_data_transfer::buffered_io::counting_io::compression_ratio;
+use simple_mass_data_transfer::server_events::ServerEvent;
+use simple_mass_data_transfer::server::{CompressionLevel, Dictionary, RateLimits, Server};
+use simple_mass_data_transfer::control::TransferControl;
+
+use eframe::Frame;
+use egui::Context;
+
+use std::collections::BTreeMap;
+use simple_mass_data_transfer::transport::PeerAddr;
+
+
+#[derive(Debug, Default)]
+struct Client {
+	rel_path: String,
+	file_sent: u64,
+	file_size: u64,
+	total_sent: u64,
+	/// Of the streams sent so far
+	streams_raw: u64,
+	streams_wire: u64,
+	retries: usize
+}
+
+
+pub struct HostUi {
+	paths: Vec<String>,
+	bind_address: String,
+	encryption_key: String,
+	compressed: bool,
+	comp_level: u8,
+	/// Adjust the level to each client instead
+	auto_level: bool,
+	codec: CompressionAlgorithm,
+	train_dictionary: bool,
+	serving: Option<std::thread::JoinHandle<Result<(), SmdError>>>,
+	control: TransferControl,
+	/// Per second, e.g. "2 MB" (empty for unlimited)
+	max_rate: String,
+	total_rate: String,
+	rate_limits: RateLimits,
+	event_stream: Option<std::sync::mpsc::Receiver<ServerEvent>>,
+	total_size: u64,
+	clients: BTreeMap<PeerAddr, Client>,
+	log: Vec<String>,
+	popup: Vec<(String, bool)>
+}
+
+
+impl HostUi {
+	fn popup(&mut self, text: String) {
+		self.popup.push((text, true))
+	}
+
+	fn rate_limit_edits(&mut self, ui: &mut egui::Ui) {
+		ui.horizontal(|ui| {
+			ui.label("Max Rate per Client");
+			super::rate_limit_edit(ui, &mut self.max_rate, &self.rate_limits.per_client);
+			ui.separator();
+			ui.label("Total Rate");
+			super::rate_limit_edit(ui, &mut self.total_rate, &self.rate_limits.total);
+		});
+	}
+
+	pub fn handle_events(&mut self) {
+		while let Some(recv) = &self.event_stream {
+			if let Ok(event) = recv.try_recv() {
+				match event {
+					ServerEvent::Listening{ address, total_size, dictionary } => {
+						self.total_size = total_size;
+						self.log.push(format!("Listening on {address}"));
+						if let Some(size) = dictionary {
+							self.log.push(format!("Compressing with a {} dictionary", bytesize::ByteSize(size as u64)));
+						}
+					},
+					ServerEvent::AcceptFailed{ error, retry_in } => {
+						self.log.push(format!("Could not accept a client: {error} (trying again in {retry_in:?})"));
+					},
+					ServerEvent::ClientConnected(client) => {
+						self.clients.insert(client, Client::default());
+						self.log.push(format!("{client} connected"));
+					},
+					ServerEvent::ClientQueued{ client, waiting } => {
+						self.log.push(format!("{client} queued, {waiting} waiting"));
+					},
+					ServerEvent::HandShake{ .. } => (),
+					ServerEvent::FileStarted{ client, rel_path, size } => if let Some(c) = self.clients.get_mut(&client) {
+						c.rel_path = rel_path;
+						c.file_size = size;
+						c.file_sent = 0;
+					},
+					ServerEvent::BytesSent{ client, bytes } => if let Some(c) = self.clients.get_mut(&client) {
+						c.file_sent += bytes as u64;
+						c.total_sent += bytes as u64;
+					},
+					ServerEvent::FileRetried(client) => if let Some(c) = self.clients.get_mut(&client) {
+						c.retries += 1;
+					},
+					ServerEvent::StreamSent{ client, raw, wire } => if let Some(c) = self.clients.get_mut(&client) {
+						c.streams_raw += raw;
+						c.streams_wire += wire;
+					},
+					ServerEvent::CompressionLevelChanged{ client, level } => {
+						self.log.push(format!("{client} compressing at level {level}"));
+					},
+					ServerEvent::ClientFinished{ client, raw, wire, time_taken } => {
+						self.clients.remove(&client);
+						let ratio = compression_ratio(raw, wire).map(|ratio| format!(", ratio {ratio:.2}")).unwrap_or_default();
+						self.log.push(format!("{client} finished in {time_taken:.1?} ({} on the wire{ratio})", bytesize::ByteSize(wire)));
+					},
+					ServerEvent::ClientFailed{ client, error } => {
+						self.clients.remove(&client);
+						self.log.push(format!("{client} failed: {error}"));
+					},
+					ServerEvent::ClientSilent{ client, timeout } => {
+						self.clients.remove(&client);
+						self.log.push(format!("{client} has not responded for {timeout:?}, disconnected"));
+					}
+				}
+			}
+			else {
+				break
+			}
+		}
+	}
+}
+
+
+impl Default for HostUi {
+	fn default() -> Self {
+		Self{
+			paths: Vec::new(),
+			bind_address: "0.0.0.0:4444".to_string(),
+			encryption_key: "".to_string(),
+			compressed: false,
+			comp_level: 15,
+			codec: CompressionAlgorithm::Zstd,
+			auto_level: false,
+			train_dictionary: false,
+			serving: None,
+			control: Default::default(),
+			max_rate: String::new(),
+			total_rate: String::new(),
+			rate_limits: Default::default(),
+			event_stream: None,
+			total_size: 0,
+			clients: BTreeMap::new(),
+			log: Vec::new(),
+			popup: Default::default()
+		}
+	}
+}
+
+
+impl eframe::App for HostUi {
+	fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
+		super::show_popups(ctx, &mut self.popup);
+
+		// handle events
+		self.handle_events();
+
+		// if the server stopped...
+		if self.serving.as_ref().is_some_and(|h| h.is_finished()) {
+			let handle = self.serving.take().unwrap();
+			if let Err(e) = handle.join().expect("SMD-Thread panicked. This is a Bug. Please report your console output.") {
+				self.popup(format!("SMD-Transfer encountered Error: {e}"));
+				eprintln!("{e:?}")
+			}
+			self.clients.clear();
+		}
+
+		if self.serving.is_none() {
+			egui::CentralPanel::default().show(ctx, |ui| {
+				ui.heading("Host Files");
+				ui.separator();
+
+				// selected files and directories
+				let mut remove = None;
+				egui::ScrollArea::vertical().max_height(200.).show(ui, |ui| {
+					for (i, path) in self.paths.iter().enumerate() {
+						ui.horizontal(|ui| {
+							if ui.small_button("✖").clicked() {
+								remove = Some(i);
+							}
+							ui.label(path.as_str());
+						});
+					}
+				});
+				if let Some(i) = remove {
+					self.paths.remove(i);
+				}
+				ui.horizontal(|ui| {
+					if ui.button("Add Files").clicked() {
+						if let Some(files) = tinyfiledialogs::open_file_dialog_multi("Select Files to Host", "", None) {
+							self.paths.extend(files);
+						}
+					}
+					if ui.button("Add Directory").clicked() {
+						if let Some(dir) = tinyfiledialogs::select_folder_dialog("Select Directory to Host", "") {
+							self.paths.push(dir);
+						}
+					}
+				});
+				ui.separator();
+
+				// Bind address dialogue
+				ui.add(egui::TextEdit::singleline(&mut self.bind_address)
+					.clip_text(false)
+					.hint_text("Bind Address"));
+
+				// compression and encryption
+				ui.checkbox(&mut self.compressed, "Force Compression")
+					.on_hover_text("Clients can still request compression themselves.");
+				ui.horizontal(|ui| {
+					ui.add_enabled(!self.auto_level, egui::Slider::new(&mut self.comp_level, 1..=21).text("Compression Level"))
+						.on_hover_text("Pick a level whose speed roughly matches twice your upload speed.");
+					ui.checkbox(&mut self.auto_level, "Auto")
+						.on_hover_text("Adjust the level to each client's connection.");
+				});
+				egui::ComboBox::from_label("Algorithm")
+					.selected_text(self.codec.to_string())
+					.show_ui(ui, |ui| {
+						for codec in [CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4, CompressionAlgorithm::Brotli] {
+							ui.selectable_value(&mut self.codec, codec, codec.to_string());
+						}
+					}).response
+					.on_hover_text("lz4 is the fastest, brotli the strongest. Older clients always get zstd.");
+				ui.checkbox(&mut self.train_dictionary, "Train Dictionary")
+					.on_hover_text("Compresses many similar small files far better.");
+				ui.add(egui::TextEdit::singleline(&mut self.encryption_key)
+					.password(true)
+					.hint_text("Encryption Key")
+				).on_hover_text("If empty, no encryption will be used.");
+				self.rate_limit_edits(ui);
+				ui.separator();
+
+				// Start Button
+				let button = egui::Button::new(match (super::validate_socket_address(&self.bind_address), !self.paths.is_empty()) {
+					(true, true) => "Start Hosting",
+					(false, true) => "Enter a valid Bind Address!",
+					(true, false) => "Add Files or Directories to Host!",
+					(false, false) => "Enter a valid Bind Address and add Files or Directories!"
+				});
+
+				// only enabled when address and paths are valid
+				let inputs_valid = super::validate_socket_address(&self.bind_address) && !self.paths.is_empty();
+				if ui.add_enabled(inputs_valid, button).clicked() {
+					// build server
+					let server = Server::new(self.bind_address.clone())
+						// the paths are not meant as patterns
+						.with_paths(self.paths.iter().map(|p| glob::Pattern::escape(p)))
+						.with_compression(self.compressed, if self.auto_level { CompressionLevel::Auto } else { self.comp_level.into() })
+						.with_codec(self.codec)
+						.with_dictionary(if self.train_dictionary { Dictionary::Trained } else { Dictionary::None })
+						.with_encryption_key(if self.encryption_key.is_empty() { None } else { Some(self.encryption_key.clone()) })
+						.with_rate_limits(super::parse_rate(&self.max_rate).flatten(), super::parse_rate(&self.total_rate).flatten());
+					// start server thread
+					let (send, recv) = std::sync::mpsc::channel();
+					let control = server.control();
+					self.rate_limits = server.rate_limits();
+					let handle = std::thread::spawn(move || server.serve(send));
+
+					self.log.clear();
+					self.control = control;
+					self.event_stream = Some(recv);
+					self.serving = Some(handle);
+				}
+			});
+		}
+		else {
+			// show general info and stop button at top
+			egui::TopBottomPanel::top("HostTopBar").show(ctx, |ui| {
+				ui.horizontal(|ui| {
+					ui.label(format!("Hosting {} on {}", bytesize::ByteSize(self.total_size), self.bind_address));
+					ui.separator();
+					ui.add_enabled(false, egui::Checkbox::new(&mut self.compressed, "Compressed"));
+					ui.add_enabled(false, egui::Checkbox::new(&mut !self.encryption_key.is_empty(), "Encrypted"));
+					ui.separator();
+					if ui.add_enabled(!self.control.is_cancelled(), egui::Button::new("Stop Hosting")).clicked() {
+						self.control.cancel();
+					}
+				});
+				self.rate_limit_edits(ui);
+			});
+
+			// log at bottom
+			egui::TopBottomPanel::bottom("HostLog").resizable(true).show(ctx, |ui| {
+				egui::ScrollArea::vertical()
+					.stick_to_bottom(true)
+					.show(ui, |ui| {
+						for line in &self.log {
+							ui.label(line.as_str());
+						}
+					});
+			});
+
+			// connected clients
+			egui::CentralPanel::default().show(ctx, |ui| {
+				if self.clients.is_empty() {
+					ui.label("Waiting for Clients...");
+				}
+				egui::ScrollArea::vertical().show(ui, |ui| {
+					for (address, client) in &self.clients {
+						ui.horizontal(|ui| {
+							ui.strong(address.to_string());
+							ui.label(&client.rel_path);
+							ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
+								ui.label(format!("{} sent", bytesize::ByteSize(client.total_sent)));
+								if let Some(ratio) = compression_ratio(client.streams_raw, client.streams_wire) {
+									ui.separator();
+									ui.label(format!("ratio {ratio:.2}"));
+								}
+								if client.retries > 0 {
+									ui.separator();
+									ui.label(format!("{} retries", client.retries));
+								}
+							});
+						});
+						ui.add(
+							egui::ProgressBar::new((client.file_sent as f32) / (client.file_size.max(1) as f32))
+								.text(format!("{}/{}", bytesize::ByteSize(client.file_sent), bytesize::ByteSize(client.file_size)))
+								.fill(egui::Color32::from_hex("#008574").unwrap())
+						);
+						ui.add_space(ui.text_style_height(&egui::TextStyle::Body) / 2.)
+					}
+				});
+			});
+
+			// keep progress moving without user input
+			ctx.request_repaint_after(std::time::Duration::from_millis(100));
+		}
+	}
+}
@@ -0,0 +1,58 @@
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+
+use simple_mass_data_transfer::SmdError;
+use simple_mass_data_transfer::buffered_io::{PerhapsEncrReader, PerhapsEncrWriter};
+use simple_mass_data_transfer::buffered_io::encrypt_io::{prepare_key, MAX_BLOCK_SIZE};
+
+use std::io::{self, Read, Write};
+
+
+/// The [`SmdError`] an adapter failed with
+fn smd_error(error: &io::Error) -> Option<&SmdError> {
+    error.get_ref().and_then(|e| e.downcast_ref())
+}
+
+fn encrypt(data: &[u8], encryptor: &mut Option<ChaCha20Poly1305>) -> Vec<u8> {
+    let mut writer = PerhapsEncrWriter::with_encryptor(Vec::new(), encryptor);
+    writer.write_all(data).unwrap();
+    writer.into_inner()
+}
+
+fn decrypt(blocks: &[u8], decryptor: &mut Option<ChaCha20Poly1305>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    PerhapsEncrReader::with_decryptor(blocks, decryptor).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+
+#[test]
+fn large_writes_are_split_into_blocks() {
+    let mut key = Some(ChaCha20Poly1305::new(&prepare_key("key")));
+    let data: Vec<u8> = (0..3 * MAX_BLOCK_SIZE + 5).map(|i| i as u8).collect();
+
+    let blocks = encrypt(&data, &mut key);
+    let length = u32::from_be_bytes(blocks[12..16].try_into().unwrap()) as usize;
+    assert_eq!(length, MAX_BLOCK_SIZE + 16);
+    assert_eq!(decrypt(&blocks, &mut key).unwrap(), data);
+}
+
+#[test]
+fn corrupted_block_is_a_protocol_violation() {
+    let mut key = Some(ChaCha20Poly1305::new(&prepare_key("key")));
+    let mut blocks = encrypt(b"some data worth protecting", &mut key);
+    blocks[20] ^= 1;
+
+    let error = decrypt(&blocks, &mut key).unwrap_err();
+    assert!(matches!(smd_error(&error), Some(SmdError::ProtocolViolation(_))), "{error}");
+}
+
+#[test]
+fn oversized_block_is_refused() {
+    let mut key = Some(ChaCha20Poly1305::new(&prepare_key("key")));
+    // (a nonce and the largest length there is, without the block)
+    let mut blocks = vec![0; 12];
+    blocks.extend(u32::MAX.to_be_bytes());
+
+    let error = decrypt(&blocks, &mut key).unwrap_err();
+    assert!(matches!(smd_error(&error), Some(SmdError::ProtocolViolation(_))), "{error}");
+}
@@ -0,0 +1,855 @@
+use simple_mass_data_transfer::{Capabilities, CompressionAlgorithm, CompressionDescriptor, EntryHeader, FileHash, FileHashResponse, Handshake, HandshakeResponse, Rejection, RejectionCode, SmdError, PROTOCOL_VERSION};
+use simple_mass_data_transfer::buffered_io::{FramedReader, PerhapsCompressedReader, ZstdParams};
+use simple_mass_data_transfer::client::{Client, DownloadLimits, RetryPolicy};
+use simple_mass_data_transfer::client_events::ClientEvent;
+use simple_mass_data_transfer::control::TransferControl;
+use simple_mass_data_transfer::server::{BacklogPolicy, Server};
+use simple_mass_data_transfer::server_events::ServerEvent;
+use simple_mass_data_transfer::transport::{memory, Connector, Listener, SocketOptions, Transport};
+
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+
+/// Filled into a file of the tree, with a run this long never appearing elsewhere in a stream
+const MARKER: u8 = 0xEE;
+const MARKER_RUN: usize = 8;
+
+
+/// An empty directory of its own for each test
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("smd_test_{name}_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Many small files (batched into solid streams), a marked one among them, and a few large ones
+fn create_tree(root: &Path) -> PathBuf {
+    let tree = root.join("tree");
+    std::fs::create_dir_all(tree.join("small")).unwrap();
+    std::fs::create_dir_all(tree.join("nested/deeper")).unwrap();
+    std::fs::create_dir_all(tree.join("empty")).unwrap();
+    for i in 0..40 {
+        std::fs::write(tree.join(format!("small/{i}.txt")), format!("small file number {i}\n").repeat(i * 7 + 1)).unwrap();
+    }
+    std::fs::write(tree.join("small/marked.bin"), [MARKER; 1000]).unwrap();
+
+    // (xorshift, incompressible without containing a run of the marker)
+    let mut state = 0x2545_F491_4F6C_DD1D_u64;
+    let mut noise = |size: usize| -> Vec<u8> {
+        (0..size).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8 & 0x7F
+        }).collect()
+    };
+    std::fs::write(tree.join("nested/deeper/noise.bin"), noise(300_000)).unwrap();
+    std::fs::write(tree.join("big.bin"), noise(3 << 20)).unwrap();
+    std::fs::write(tree.join("nested/text.txt"), "compressible line\n".repeat(100_000)).unwrap();
+    tree
+}
+
+/// Fails unless both trees hold the same directories and files
+fn assert_same_tree(expected: &Path, actual: &Path) {
+    let entries = |root: &Path| -> Vec<(PathBuf, Option<Vec<u8>>)> {
+        let mut entries: Vec<_> = walkdir::WalkDir::new(root).into_iter()
+            .map(|entry| entry.unwrap())
+            .map(|entry| (
+                entry.path().strip_prefix(root).unwrap().to_owned(),
+                entry.file_type().is_file().then(|| std::fs::read(entry.path()).unwrap())
+            ))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    };
+    let (expected, actual) = (entries(expected), entries(actual));
+    assert_eq!(expected.len(), actual.len(), "different number of entries");
+    for (expected, actual) in expected.iter().zip(&actual) {
+        assert_eq!(expected.0, actual.0);
+        assert!(expected.1 == actual.1, "{:?} differs", expected.0);
+    }
+}
+
+/// Serves `server` on a memory transport while `client` downloads from it, returning the events of both
+fn transfer<C: Connector + 'static>(server: Server, client: impl FnOnce(C) -> Client, connector: impl FnOnce(memory::MemoryConnector) -> C) -> (Vec<ServerEvent>, Vec<ClientEvent>) {
+    let (listener, memory_connector) = memory::channel();
+    let (server_events, server_received) = std::sync::mpsc::channel();
+    let (client_events, client_received) = std::sync::mpsc::channel();
+    let control = server.control();
+    let serving = std::thread::spawn(move || server.serve_on(Box::new(listener), server_events));
+
+    let downloaded = client(connector(memory_connector)).download(client_events);
+    control.cancel();
+    serving.join().unwrap().unwrap();
+    downloaded.unwrap();
+
+    (server_received.try_iter().collect(), client_received.try_iter().collect())
+}
+
+fn hash_mismatches(events: &[ClientEvent]) -> usize {
+    events.iter().filter(|event| matches!(event, ClientEvent::FileFinished(false))).count()
+}
+
+
+#[test]
+fn round_trip_plain() {
+    let dir = test_dir("plain");
+    let tree = create_tree(&dir);
+    let destination = dir.join("download");
+
+    let server = Server::new("memory").with_paths([tree.to_str().unwrap()]);
+    let (_, events) = transfer(server, |connector| Client::with_connector(connector, &destination), |c| c);
+
+    assert_same_tree(&tree, &destination.join("tree"));
+    assert_eq!(hash_mismatches(&events), 0);
+    assert!(events.iter().any(|event| matches!(event, ClientEvent::Completed{ .. })));
+    // the resume list is gone after completion
+    assert!(!destination.join(".smdres").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn round_trip_compressed_and_encrypted() {
+    for codec in [CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4, CompressionAlgorithm::Brotli] {
+        let dir = test_dir(&format!("compressed_{codec}"));
+        let tree = create_tree(&dir);
+        let destination = dir.join("download");
+
+        let server = Server::new("memory")
+            .with_paths([tree.to_str().unwrap()])
+            .with_compression(true, 3)
+            .with_codec(codec)
+            .with_encryption_key(Some("key".into()));
+        let (_, events) = transfer(server, |connector| {
+            Client::with_connector(connector, &destination)
+                .with_compression(true)
+                .with_encryption_key(Some("key".into()))
+        }, |c| c);
+
+        assert_same_tree(&tree, &destination.join("tree"));
+        assert!(events.iter().any(|event| matches!(event, ClientEvent::HandShakeResponse{ compression: Some(c), .. } if *c == codec)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+
+/// Flips a Byte in the first run of [`MARKER`] Bytes it reads (once for all connections)
+struct Corrupting {
+    inner: Box<dyn Transport>,
+    corrupted: Arc<AtomicBool>
+}
+
+impl Read for Corrupting {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if !self.corrupted.load(Ordering::Relaxed) {
+            if let Some(position) = buf[..read].windows(MARKER_RUN).position(|run| run.iter().all(|&b| b == MARKER)) {
+                buf[position] ^= 0xFF;
+                self.corrupted.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Write for Corrupting {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for Corrupting {
+    fn try_clone_boxed(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Corrupting{ inner: self.inner.try_clone_boxed()?, corrupted: self.corrupted.clone() }))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+#[derive(Clone)]
+struct CorruptingConnector {
+    inner: memory::MemoryConnector,
+    corrupted: Arc<AtomicBool>
+}
+
+impl Connector for CorruptingConnector {
+    fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Corrupting{ inner: self.inner.connect()?, corrupted: self.corrupted.clone() }))
+    }
+}
+
+#[test]
+fn hash_mismatch_in_solid_stream_is_resent() {
+    let dir = test_dir("mismatch");
+    let tree = create_tree(&dir);
+    let destination = dir.join("download");
+    let corrupted = Arc::new(AtomicBool::new(false));
+
+    let server = Server::new("memory").with_paths([tree.to_str().unwrap()]);
+    let (server_events, events) = transfer(server,
+        |connector| Client::with_connector(connector, &destination).with_retry_policy(RetryPolicy{ max_attempts: 0, ..Default::default() }),
+        |inner| CorruptingConnector{ inner, corrupted: corrupted.clone() });
+
+    assert!(corrupted.load(Ordering::Relaxed));
+    assert_eq!(hash_mismatches(&events), 1);
+    assert_eq!(server_events.iter().filter(|event| matches!(event, ServerEvent::FileRetried(_))).count(), 1);
+    assert_same_tree(&tree, &destination.join("tree"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Pauses the client on its first connection, for longer than the server's idle timeout
+struct PausingConnector {
+    inner: memory::MemoryConnector,
+    control: Arc<OnceLock<TransferControl>>,
+    pause: Duration
+}
+
+impl Connector for PausingConnector {
+    fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        if let Some(control) = self.control.get().filter(|control| control.pauses() == 0) {
+            control.pause();
+            let (control, pause) = (control.clone(), self.pause);
+            std::thread::spawn(move || {
+                std::thread::sleep(pause);
+                control.resume();
+            });
+        }
+        self.inner.connect()
+    }
+}
+
+#[test]
+fn long_pause_reconnects_without_using_up_retries() {
+    let dir = test_dir("pause");
+    let tree = create_tree(&dir);
+    let destination = dir.join("download");
+    let control = Arc::new(OnceLock::new());
+
+    let server = Server::new("memory").with_paths([tree.to_str().unwrap()]).with_idle_timeout(Duration::from_millis(200));
+    let (_, events) = transfer(server,
+        |connector| {
+            let client = Client::with_connector(connector, &destination).with_retry_policy(RetryPolicy{ max_attempts: 0, ..Default::default() });
+            control.set(client.control()).unwrap();
+            client
+        },
+        |inner| PausingConnector{ inner, control: control.clone(), pause: Duration::from_secs(1) });
+
+    assert!(events.iter().any(|event| matches!(event, ClientEvent::Reconnecting{ attempt: 0, .. })));
+    assert_same_tree(&tree, &destination.join("tree"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+/// Sends the handshake of a client speaking at most `protocol_version`, and only knowing zstd and none of the transfer features,
+/// returning the server's response
+fn old_handshake(stream: &mut Box<dyn Transport>, reader: &mut impl BufRead, protocol_version: u32, compression: bool) -> HandshakeResponse {
+    let handshake = Handshake{
+        protocol_version,
+        min_protocol_version: 1,
+        version: "1.0.0".into(),
+        capabilities: Capabilities{ compression: vec![CompressionAlgorithm::Zstd], transfer: Vec::new(), ..Capabilities::supported() },
+        resume_list: None,
+        compression,
+        key_check: None,
+        timeout_ms: 0,
+        max_window_log: None
+    };
+    rmp_serde::encode::write(stream, &handshake).unwrap();
+    rmp_serde::decode::from_read(reader).unwrap()
+}
+
+/// Serves on a memory transport, returning a connection to it and how to stop serving
+fn serve_for_old_client(server: Server) -> (Box<dyn Transport>, impl FnOnce()) {
+    let (listener, connector) = memory::channel();
+    let (server_events, received) = std::sync::mpsc::channel();
+    let control = server.control();
+    let serving = std::thread::spawn(move || server.serve_on(Box::new(listener), server_events));
+    let stop = move || {
+        control.cancel();
+        serving.join().unwrap().unwrap();
+        drop(received);
+    };
+    (connector.connect().unwrap(), stop)
+}
+
+#[test]
+fn old_client_gets_files_one_by_one() {
+    let dir = test_dir("old_client");
+    let tree = create_tree(&dir);
+    let destination = dir.join("download");
+
+    let server = Server::new("memory").with_paths([tree.to_str().unwrap()]);
+    let (mut stream, stop) = serve_for_old_client(server);
+    let mut reader = io::BufReader::new(stream.try_clone_boxed().unwrap());
+
+    // (only whether zstd is used, as the client knows no other algorithm)
+    match old_handshake(&mut stream, &mut reader, 1, false) {
+        HandshakeResponse::Accepted{ protocol_version, compression, dictionary, window_log, .. } => {
+            assert_eq!(protocol_version, 1);
+            assert_eq!(compression, CompressionDescriptor::Zstd(false));
+            assert!(dictionary.is_none() && window_log.is_none());
+        },
+        response => panic!("Unexpected response {response:?}")
+    }
+
+    // receive like a client of protocol version 1, without solid streams or per-file compression
+    while !reader.fill_buf().unwrap().is_empty() {
+        match rmp_serde::decode::from_read(&mut reader).unwrap() {
+            EntryHeader::DirHeader{ path } => std::fs::create_dir_all(destination.join(String::from_utf8(path).unwrap())).unwrap(),
+            EntryHeader::FileHeader{ path, size, compressed } => {
+                assert_eq!(compressed, None);
+                let mut framed = FramedReader::new(&mut reader);
+                let mut content = Vec::new();
+                (&mut framed).take(size).read_to_end(&mut content).unwrap();
+                framed.finish().unwrap();
+                std::fs::write(destination.join(String::from_utf8(path).unwrap()), content).unwrap();
+
+                let _: FileHash = rmp_serde::decode::from_read(&mut reader).unwrap();
+                rmp_serde::encode::write(&mut stream, &FileHashResponse{ matches: true }).unwrap();
+            },
+            EntryHeader::SolidHeader{ .. } => panic!("Solid stream sent to a client of protocol version 1")
+        }
+    }
+
+    stop();
+    assert_same_tree(&tree, &destination.join("tree"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn old_client_falls_back_to_zstd() {
+    let dir = test_dir("old_client_zstd");
+    let tree = create_tree(&dir);
+
+    let server = Server::new("memory")
+        .with_paths([tree.to_str().unwrap()])
+        .with_compression(false, 3)
+        .with_codec(CompressionAlgorithm::Lz4);
+    let (mut stream, stop) = serve_for_old_client(server);
+    let mut reader = io::BufReader::new(stream.try_clone_boxed().unwrap());
+
+    match old_handshake(&mut stream, &mut reader, 2, true) {
+        HandshakeResponse::Accepted{ protocol_version, compression, .. } => {
+            assert_eq!(protocol_version, 2);
+            assert_eq!(compression.codec(true), Some(CompressionAlgorithm::Zstd));
+        },
+        response => panic!("Unexpected response {response:?}")
+    }
+
+    // the first file is readable with zstd
+    loop {
+        match rmp_serde::decode::from_read(&mut reader).unwrap() {
+            EntryHeader::DirHeader{ .. } => (),
+            EntryHeader::FileHeader{ path, size, compressed } => {
+                let path = dir.join(String::from_utf8(path).unwrap());
+                let mut decompressor = PerhapsCompressedReader::with_params(FramedReader::new(&mut reader), compressed.unwrap().then_some(CompressionAlgorithm::Zstd), None, None).unwrap();
+                let mut content = Vec::new();
+                (&mut decompressor).take(size).read_to_end(&mut content).unwrap();
+                assert_eq!(content, std::fs::read(path).unwrap());
+                break
+            },
+            EntryHeader::SolidHeader{ compressed, .. } => {
+                let mut decompressor = PerhapsCompressedReader::with_params(FramedReader::new(&mut reader), compressed.then_some(CompressionAlgorithm::Zstd), None, None).unwrap();
+                loop {
+                    match rmp_serde::decode::from_read(&mut decompressor).unwrap() {
+                        EntryHeader::FileHeader{ path, size, .. } => {
+                            let path = dir.join(String::from_utf8(path).unwrap());
+                            let mut content = Vec::new();
+                            (&mut decompressor).take(size).read_to_end(&mut content).unwrap();
+                            assert_eq!(content, std::fs::read(path).unwrap());
+                            break
+                        },
+                        EntryHeader::DirHeader{ .. } => (),
+                        EntryHeader::SolidHeader{ .. } => panic!("Nested solid stream")
+                    }
+                }
+                break
+            }
+        }
+    }
+
+    drop((stream, reader));
+    stop();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+#[test]
+fn limits_are_enforced() {
+    let dir = test_dir("limits");
+    let tree = create_tree(&dir);
+    let hoster = || Server::new("memory").with_paths([tree.to_str().unwrap()]);
+    let download = |limits: DownloadLimits| {
+        let (listener, connector) = memory::channel();
+        let server = hoster();
+        let control = server.control();
+        let serving = std::thread::spawn(move || server.serve_on(Box::new(listener), std::sync::mpsc::channel().0));
+        let result = Client::with_connector(connector, dir.join("download")).with_limits(limits).download(std::sync::mpsc::channel().0);
+        control.cancel();
+        serving.join().unwrap().unwrap();
+        result.unwrap_err().to_string()
+    };
+
+    // (the tree holds about 5 MB in 44 files)
+    let error = download(DownloadLimits{ max_size: Some(1 << 20), ..Default::default() });
+    assert!(error.contains("exceeding the set maximum"), "{error}");
+    let error = download(DownloadLimits{ max_files: Some(10), ..Default::default() });
+    assert!(error.contains("more than the set maximum of 10 files"), "{error}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn size_limit_holds_against_a_hoster_understating_its_size() {
+    let dir = test_dir("understated");
+
+    let (connector, hoster) = fake_hoster(|stream| {
+        rmp_serde::encode::write(stream, &HandshakeResponse::Accepted{
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            total_size: 10,
+            compression: CompressionDescriptor::Codec(None),
+            dictionary: None,
+            window_log: None
+        }).unwrap();
+        // (the client hangs up before the content)
+        let _ = rmp_serde::encode::write(stream, &EntryHeader::FileHeader{ path: b"huge.bin".to_vec(), size: 1 << 30, compressed: Some(false) });
+    });
+
+    let limits = DownloadLimits{ max_size: Some(1 << 20), ..Default::default() };
+    let result = Client::with_connector(connector, dir.join("download")).with_limits(limits).download(std::sync::mpsc::channel().0);
+    hoster.join().unwrap();
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("sent more than the set maximum"), "{error}");
+    assert!(!dir.join("download/huge.bin").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn preallocated_files_end_up_at_their_size() {
+    let dir = test_dir("preallocate");
+    let tree = create_tree(&dir);
+    let destination = dir.join("download");
+
+    let server = Server::new("memory").with_paths([tree.to_str().unwrap()]).with_compression(true, 3);
+    let limits = DownloadLimits{ preallocate: true, ..Default::default() };
+    let (_, events) = transfer(server, |connector| Client::with_connector(connector, &destination).with_limits(limits), |c| c);
+
+    assert_same_tree(&tree, &destination.join("tree"));
+    assert_eq!(hash_mismatches(&events), 0);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// A Hoster on a memory transport, `respond`ing to the handshake of a single client as it likes
+fn fake_hoster(respond: impl FnOnce(&mut Box<dyn Transport>) + Send + 'static) -> (memory::MemoryConnector, std::thread::JoinHandle<()>) {
+    let (listener, connector) = memory::channel();
+    let hoster = std::thread::spawn(move || {
+        let mut stream = loop {
+            match listener.accept().unwrap() {
+                Some((stream, _)) => break stream,
+                None => std::thread::sleep(Duration::from_millis(1))
+            }
+        };
+        let _: Handshake = rmp_serde::decode::from_read(&mut stream).unwrap();
+        respond(&mut stream);
+    });
+    (connector, hoster)
+}
+
+#[test]
+fn long_window_is_limited_to_the_clients_maximum() {
+    let dir = test_dir("window_limit");
+    let tree = create_tree(&dir);
+    let destination = dir.join("download");
+    let hoster = || Server::new("memory")
+        .with_paths([tree.to_str().unwrap()])
+        .with_compression(true, 3)
+        .with_zstd_params(ZstdParams{ long_window: Some(30), ..Default::default() });
+
+    // the window is announced as the client's maximum
+    let (mut stream, stop) = serve_for_old_client(hoster());
+    let mut reader = io::BufReader::new(stream.try_clone_boxed().unwrap());
+    let handshake = Handshake{
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: 1,
+        version: "1.0.0".into(),
+        capabilities: Capabilities::supported(),
+        resume_list: None,
+        compression: true,
+        key_check: None,
+        timeout_ms: 0,
+        max_window_log: Some(27)
+    };
+    rmp_serde::encode::write(&mut stream, &handshake).unwrap();
+    match rmp_serde::decode::from_read(&mut reader).unwrap() {
+        HandshakeResponse::Accepted{ window_log, .. } => assert_eq!(window_log, Some(27)),
+        response => panic!("Unexpected response {response:?}")
+    }
+    drop((stream, reader));
+    stop();
+
+    // and actually used
+    let limits = DownloadLimits{ max_window_log: 27, ..Default::default() };
+    transfer(hoster(), |connector| Client::with_connector(connector, &destination).with_limits(limits), |c| c);
+    assert_same_tree(&tree, &destination.join("tree"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn window_above_the_maximum_is_refused() {
+    let dir = test_dir("window_refused");
+    // a Hoster ignoring the client's maximum
+    let (connector, hoster) = fake_hoster(|stream| {
+        rmp_serde::encode::write(stream, &HandshakeResponse::Accepted{
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            total_size: 0,
+            compression: CompressionDescriptor::Codec(Some(CompressionAlgorithm::Zstd)),
+            dictionary: Some(None),
+            window_log: Some(31)
+        }).unwrap();
+    });
+
+    let (events, _received) = std::sync::mpsc::channel();
+    let result = Client::with_connector(connector, dir.join("download")).with_compression(true).download(events);
+    hoster.join().unwrap();
+    assert!(matches!(result, Err(SmdError::ProtocolViolation(_))), "{result:?}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn unsupported_socket_options_fail_at_bind() {
+    let (events, _received) = std::sync::mpsc::channel();
+    let server = Server::new("127.0.0.1:0")
+        .with_socket_options(SocketOptions{ congestion: Some("bogus".into()), ..Default::default() });
+    let result = server.serve(events);
+    assert!(result.as_ref().is_err_and(|e| e.to_string().contains("congestion")), "{result:?}");
+}
+
+/// Waits for the first of the `events` that `matches`
+fn wait_for(events: &std::sync::mpsc::Receiver<ServerEvent>, matches: impl Fn(&ServerEvent) -> bool) -> ServerEvent {
+    loop {
+        let event = events.recv_timeout(Duration::from_secs(30)).expect("the event never came");
+        if matches(&event) {
+            return event
+        }
+    }
+}
+
+/// Serves `server` (with a single worker, treating further clients according to `backlog`) on a memory transport,
+/// returning a connector, the server's events, how to stop serving, and a client taking up the worker until dropped
+fn serve_with_worker_taken(server: Server, backlog: BacklogPolicy) -> (memory::MemoryConnector, std::sync::mpsc::Receiver<ServerEvent>, impl FnOnce(), Box<dyn Transport>) {
+    let (listener, connector) = memory::channel();
+    let (server_events, received) = std::sync::mpsc::channel();
+    let server = server.with_max_clients(Some(1), backlog);
+    let control = server.control();
+    let serving = std::thread::spawn(move || server.serve_on(Box::new(listener), server_events));
+    let stop = move || {
+        control.cancel();
+        serving.join().unwrap().unwrap();
+    };
+
+    // (never sending its handshake)
+    let blocker = connector.connect().unwrap();
+    wait_for(&received, |event| matches!(event, ServerEvent::ClientConnected(_)));
+    (connector, received, stop, blocker)
+}
+
+#[test]
+fn queued_client_is_served_once_the_worker_is_free() {
+    let dir = test_dir("queued");
+    let tree = create_tree(&dir);
+    let destination = dir.join("download");
+
+    let (connector, events, stop, blocker) = serve_with_worker_taken(Server::new("memory").with_paths([tree.to_str().unwrap()]), BacklogPolicy::Queue);
+    // (told how many wait every quarter of its timeout)
+    let client = Client::with_connector(connector, &destination).with_timeout(Some(Duration::from_secs(1)));
+    let (client_events, client_received) = std::sync::mpsc::channel();
+    let downloading = std::thread::spawn(move || client.download(client_events));
+    wait_for(&events, |event| matches!(event, ServerEvent::ClientQueued{ waiting: 1, .. }));
+    match client_received.recv_timeout(Duration::from_secs(30)).unwrap() {
+        ClientEvent::Queued(waiting) => assert_eq!(waiting, 1),
+        event => panic!("Unexpected event {event:?}")
+    }
+
+    drop(blocker);
+    downloading.join().unwrap().unwrap();
+    stop();
+    assert_same_tree(&tree, &destination.join("tree"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn malformed_handshakes_leave_the_queue() {
+    let (connector, events, stop, _blocker) = serve_with_worker_taken(Server::new("memory"), BacklogPolicy::Queue);
+    let left = |events: &std::sync::mpsc::Receiver<ServerEvent>| match wait_for(events, |event| matches!(event, ServerEvent::ClientFailed{ .. })) {
+        ServerEvent::ClientFailed{ error, .. } => error,
+        _ => unreachable!()
+    };
+
+    // arrays in arrays in ...
+    let mut nested = connector.connect().unwrap();
+    nested.write_all(&[0x91; 64]).unwrap();
+    assert!(left(&events).contains("nested too deeply"));
+
+    // a string longer than any handshake
+    let mut oversized = connector.connect().unwrap();
+    let sending = std::thread::spawn(move || {
+        oversized.write_all(&[0xdb, 0xff, 0xff, 0xff, 0xff])?;
+        let chunk = vec![0; 1 << 20];
+        for _ in 0..40 {
+            oversized.write_all(&chunk)?;
+        }
+        io::Result::Ok(())
+    });
+    assert!(left(&events).contains("handshake of over"));
+    // (the server has hung up)
+    assert!(sending.join().unwrap().is_err());
+    stop();
+}
+
+#[test]
+fn rejections_become_their_errors() {
+    let codes = [
+        (RejectionCode::WrongVersion, 10),
+        (RejectionCode::WrongKey, 11),
+        (RejectionCode::ServerFull, 12),
+        (RejectionCode::Forbidden, 13),
+        (RejectionCode::QuotaExceeded, 14),
+        (RejectionCode::ServerError, 15),
+        (RejectionCode::Unknown, 19)
+    ];
+    let dir = test_dir("rejections");
+    for (code, exit_code) in codes {
+        let (connector, hoster) = fake_hoster(move |stream| {
+            rmp_serde::encode::write(stream, &HandshakeResponse::Rejected(Rejection::new(code, "Go away"))).unwrap();
+        });
+        let error = Client::with_connector(connector, dir.join("download")).download(std::sync::mpsc::channel().0).unwrap_err();
+        hoster.join().unwrap();
+
+        match (code, &error) {
+            (RejectionCode::WrongVersion, SmdError::VersionMismatch(message)) => assert_eq!(message, "Go away"),
+            (RejectionCode::WrongKey, SmdError::WrongKey) => (),
+            (_, SmdError::Rejected(rejection)) if rejection.code == code => (),
+            _ => panic!("{code:?} became {error:?}")
+        }
+        assert_eq!(error.exit_code(), Some(exit_code), "{code:?}");
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn server_refuses_other_versions_and_keys() {
+    let dir = test_dir("refused");
+    let tree = create_tree(&dir);
+    let hoster = || Server::new("memory")
+        .with_paths([tree.to_str().unwrap()])
+        .with_encryption_key(Some("key".into()));
+
+    // a client only speaking newer versions
+    let (mut stream, stop) = serve_for_old_client(hoster());
+    let mut reader = io::BufReader::new(stream.try_clone_boxed().unwrap());
+    let handshake = Handshake{
+        protocol_version: PROTOCOL_VERSION + 1,
+        min_protocol_version: PROTOCOL_VERSION + 1,
+        version: "99.0.0".into(),
+        capabilities: Capabilities::supported(),
+        resume_list: None,
+        compression: false,
+        key_check: None,
+        timeout_ms: 0,
+        max_window_log: None
+    };
+    rmp_serde::encode::write(&mut stream, &handshake).unwrap();
+    match rmp_serde::decode::from_read(&mut reader).unwrap() {
+        HandshakeResponse::Rejected(rejection) => {
+            assert_eq!(rejection.code, RejectionCode::WrongVersion);
+            assert!(matches!(SmdError::from(rejection), SmdError::VersionMismatch(_)));
+        },
+        response => panic!("Unexpected response {response:?}")
+    }
+    drop((stream, reader));
+    stop();
+
+    // clients with another key, or none at all
+    for key in [Some("other key"), None] {
+        let (listener, connector) = memory::channel();
+        let server = hoster();
+        let control = server.control();
+        let serving = std::thread::spawn(move || server.serve_on(Box::new(listener), std::sync::mpsc::channel().0));
+        let result = Client::with_connector(connector, dir.join("download"))
+            .with_encryption_key(key.map(Into::into))
+            .download(std::sync::mpsc::channel().0);
+        control.cancel();
+        serving.join().unwrap().unwrap();
+        assert!(matches!(result, Err(SmdError::WrongKey)), "{key:?}: {result:?}");
+        assert_eq!(result.unwrap_err().exit_code(), Some(11));
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn full_server_turns_clients_away() {
+    let dir = test_dir("full");
+    let (connector, _events, stop, _blocker) = serve_with_worker_taken(Server::new("memory"), BacklogPolicy::Reject);
+
+    let result = Client::with_connector(connector, dir.join("download")).download(std::sync::mpsc::channel().0);
+    match &result {
+        Err(SmdError::Rejected(rejection)) => assert_eq!(rejection.code, RejectionCode::ServerFull),
+        result => panic!("Unexpected result {result:?}")
+    }
+    assert_eq!(result.unwrap_err().exit_code(), Some(12));
+    stop();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// A directory holding a single file of `size` Bytes
+fn create_file_tree(root: &Path, size: usize) -> PathBuf {
+    let tree = root.join("tree");
+    std::fs::create_dir_all(&tree).unwrap();
+    std::fs::write(tree.join("file.bin"), vec![0x42; size]).unwrap();
+    tree
+}
+
+#[test]
+fn limited_transfer_takes_its_time() {
+    let dir = test_dir("rate_limited");
+    let tree = create_file_tree(&dir, 512 << 10);
+    let destination = dir.join("download");
+
+    let server = Server::new("memory")
+        .with_paths([tree.to_str().unwrap()])
+        .with_rate_limits(Some(512 << 10), None);
+    let started = Instant::now();
+    transfer(server, |connector| Client::with_connector(connector, &destination), |c| c);
+
+    // (a second at the rate, less what may be saved up in a quarter of one)
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(700), "took {elapsed:?}");
+    assert_same_tree(&tree, &destination.join("tree"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn changed_limit_takes_effect_mid_transfer() {
+    for on_client in [true, false] {
+        let dir = test_dir(&format!("rate_changed_{on_client}"));
+        let tree = create_file_tree(&dir, 1 << 20);
+        let destination = dir.join("download");
+
+        // (16 seconds at the initial rate, lifted after half of one)
+        let (listener, connector) = memory::channel();
+        let server = Server::new("memory").with_paths([tree.to_str().unwrap()]);
+        let client = Client::with_connector(connector, &destination);
+        let limiter = if on_client { client.rate_limit() } else { server.rate_limits().total };
+        limiter.set_rate(Some(64 << 10));
+        let lifting = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(500));
+            limiter.set_rate(None);
+        });
+
+        let started = Instant::now();
+        let control = server.control();
+        let serving = std::thread::spawn(move || server.serve_on(Box::new(listener), std::sync::mpsc::channel().0));
+        client.download(std::sync::mpsc::channel().0).unwrap();
+        let elapsed = started.elapsed();
+        control.cancel();
+        serving.join().unwrap().unwrap();
+        lifting.join().unwrap();
+
+        assert!(elapsed > Duration::from_millis(500) && elapsed < Duration::from_secs(8), "took {elapsed:?} (limited on the client: {on_client})");
+        assert_same_tree(&tree, &destination.join("tree"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+/// Serves `server` on a loopback TCP port (with its `tokio` version if `async_server`), returning its address and a function stopping it
+#[cfg(feature = "async")]
+fn serve_on_loopback(server: Server, async_server: bool) -> (String, impl FnOnce() -> Vec<ServerEvent>) {
+    let (server_events, server_received) = std::sync::mpsc::channel();
+    let control = server.control();
+    let serving = std::thread::spawn(move || match async_server {
+        true => tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+            .block_on(server.serve_async(server_events)),
+        false => server.serve(server_events)
+    });
+    let address = match server_received.recv().unwrap() {
+        ServerEvent::Listening{ address, .. } => address,
+        event => panic!("Unexpected event {event:?}")
+    };
+    (address, move || {
+        control.cancel();
+        serving.join().unwrap().unwrap();
+        server_received.try_iter().collect()
+    })
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_serves_blocking_client() {
+    let dir = test_dir("async_server");
+    let tree = create_tree(&dir);
+    let destination = dir.join("download");
+
+    let server = Server::new("127.0.0.1:0")
+        .with_paths([tree.to_str().unwrap()])
+        .with_compression(true, 19)
+        .with_encryption_key(Some("key".into()));
+    let (address, stop) = serve_on_loopback(server, true);
+    let (client_events, client_received) = std::sync::mpsc::channel();
+    Client::new(address, &destination)
+        .with_encryption_key(Some("key".into()))
+        .download(client_events)
+        .unwrap();
+    let events = stop();
+
+    assert_same_tree(&tree, &destination.join("tree"));
+    assert!(events.iter().any(|event| matches!(event, ServerEvent::ClientFinished{ .. })));
+    assert_eq!(hash_mismatches(&client_received.try_iter().collect::<Vec<_>>()), 0);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_client_downloads_from_blocking_server() {
+    for codec in [CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4] {
+        let dir = test_dir(&format!("async_client_{codec}"));
+        let tree = create_tree(&dir);
+        let destination = dir.join("download");
+
+        let server = Server::new("127.0.0.1:0")
+            .with_paths([tree.to_str().unwrap()])
+            .with_compression(true, 3)
+            .with_codec(codec);
+        let (address, stop) = serve_on_loopback(server, false);
+        let (client_events, client_received) = std::sync::mpsc::channel();
+        let client = Client::new(address, &destination).with_compression(true);
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+            .block_on(client.download_async(client_events))
+            .unwrap();
+        stop();
+
+        assert_same_tree(&tree, &destination.join("tree"));
+        assert!(client_received.try_iter().any(|event| matches!(event, ClientEvent::HandShakeResponse{ compression: Some(c), .. } if c == codec)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}